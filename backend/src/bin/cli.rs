@@ -0,0 +1,410 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::{Parser, Subcommand};
+use spotify_playlist_notification_backend::{
+    command::LambdaCommand,
+    discord::DiscordClient,
+    dynamodb::{DynamoDBClient, DynamoDBClientTrait},
+    error::{AppError, DomainError, require_env},
+    interaction,
+    oauth::{self, PkceChallenge},
+    playlist::{PlaylistState, Turn},
+    processor::execute_process,
+    spotify::SpotifyClient,
+    user::{AwayPeriod, User, UserMaster},
+};
+use tokio::net::TcpListener;
+
+// ローカルから運用するためのCLI。Lambdaと同じテーブルを操作する
+#[derive(Parser)]
+#[command(name = "spotify-playlist-notification")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// 通常のチェックを一度実行する
+    Check {
+        #[arg(long)]
+        playlist_id: Option<String>,
+    },
+    /// 通知もDynamoDBへの書き込みもせずに、送信されるはずだったメッセージを出力する
+    DryRun {
+        #[arg(long)]
+        playlist_id: Option<String>,
+    },
+    /// ローテーションのユーザーを操作する
+    #[command(subcommand)]
+    Users(UsersCommand),
+    /// プレイリストの状態を確認・変更する
+    #[command(subcommand)]
+    State(StateCommand),
+    /// Spotifyのリフレッシュトークンを設定する
+    #[command(subcommand)]
+    Token(TokenCommand),
+    /// Spotifyの認可を行い、リフレッシュトークンを保存する
+    #[command(subcommand)]
+    Auth(AuthCommand),
+    /// Discordのスラッシュコマンドを操作する
+    #[command(subcommand)]
+    Commands(CommandsCommand),
+}
+
+#[derive(Subcommand)]
+enum UsersCommand {
+    List,
+    /// ローテーションの最後に追加する
+    Add {
+        #[arg(long)]
+        name: String,
+        #[arg(long)]
+        spotify_user_id: String,
+        #[arg(long)]
+        discord_user_id: String,
+    },
+    Remove {
+        spotify_user_id: String,
+    },
+    /// 全員のspotify_user_idを新しい順番で指定する
+    Reorder {
+        #[arg(required = true)]
+        spotify_user_ids: Vec<String>,
+    },
+    /// 再開するまでローテーションで飛ばす
+    Pause {
+        spotify_user_id: String,
+    },
+    /// 休止と不在期間を解除する
+    Resume {
+        spotify_user_id: String,
+    },
+    /// 不在期間を設定する。期間中はローテーションで飛ばす
+    Away {
+        spotify_user_id: String,
+        /// YYYY-MM-DD(日本時間、この日を含む)
+        #[arg(long)]
+        from: String,
+        /// YYYY-MM-DD(日本時間、この日を含む)
+        #[arg(long)]
+        until: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum StateCommand {
+    Show {
+        playlist_id: String,
+    },
+    Set {
+        playlist_id: String,
+        /// 現在の番の人
+        #[arg(long)]
+        turn: Option<String>,
+        /// 空にすると次回の実行で必ず曲を比較する
+        #[arg(long)]
+        snapshot_id: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum TokenCommand {
+    Set { refresh_token: String },
+}
+
+#[derive(Subcommand)]
+enum AuthCommand {
+    /// ブラウザで認可し、ローカルのコールバックサーバーで認可コードを受け取る
+    Login {
+        /// Spotifyのアプリにhttp://127.0.0.1:<PORT>/callbackをリダイレクトURIとして登録しておく
+        #[arg(long, default_value_t = 8888)]
+        port: u16,
+    },
+}
+
+#[derive(Subcommand)]
+enum CommandsCommand {
+    /// /next・/history・/check・/skipを登録する
+    Register {
+        #[arg(long)]
+        application_id: String,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<(), AppError> {
+    dotenvy::dotenv().ok();
+    let cli = Cli::parse();
+    match cli.command {
+        Command::Check { playlist_id } => {
+            let owner = format!("cli-{}", std::process::id());
+            execute_process(&owner, &LambdaCommand::Check { playlist_id }).await?;
+        }
+        Command::DryRun { playlist_id } => {
+            let response = execute_process("cli", &LambdaCommand::DryRun { playlist_id }).await?;
+            println!("{}", serde_json::to_string_pretty(&response)?);
+        }
+        Command::Users(command) => execute_users(command).await?,
+        Command::State(command) => execute_state(command).await?,
+        Command::Token(TokenCommand::Set { refresh_token }) => {
+            let dynamodb_client = DynamoDBClient::new().await;
+            dynamodb_client
+                .put_spotify_refresh_token(&refresh_token)
+                .await?;
+            println!("spotify_refresh_token updated");
+        }
+        Command::Auth(AuthCommand::Login { port }) => login(port).await?,
+        Command::Commands(CommandsCommand::Register { application_id }) => {
+            let discord_client = DiscordClient::init()?;
+            discord_client
+                .register_commands(&application_id, &interaction::command_definitions())
+                .await?;
+            println!("commands registered");
+        }
+    }
+    Ok(())
+}
+
+async fn login(port: u16) -> Result<(), AppError> {
+    let client_id = require_env("SPOTIFY_CLIENT_ID")?;
+    let redirect_uri = format!("http://127.0.0.1:{port}/callback");
+    let pkce = PkceChallenge::new();
+    let state = oauth::random_state();
+    // ブラウザを開く前に待ち受けを始める
+    let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+    println!("Open this URL in your browser to authorize:");
+    println!(
+        "{}",
+        oauth::authorize_url(&client_id, &redirect_uri, &state, &pkce)?
+    );
+    let code = oauth::wait_for_callback(&listener, &state).await?;
+    let refresh_token =
+        SpotifyClient::exchange_authorization_code(&code, &redirect_uri, &pkce.verifier).await?;
+    let dynamodb_client = DynamoDBClient::new().await;
+    // 既存のトークンがある場合は、実行中のLambdaの更新と競合しないように条件付きで更新する
+    match dynamodb_client.extract_spotify_refresh_token().await? {
+        Some(previous_refresh_token) => {
+            dynamodb_client
+                .update_spotify_refresh_token(&previous_refresh_token, &refresh_token)
+                .await?
+        }
+        None => {
+            dynamodb_client
+                .put_spotify_refresh_token(&refresh_token)
+                .await?
+        }
+    }
+    println!("spotify_refresh_token updated");
+    Ok(())
+}
+
+async fn execute_users(command: UsersCommand) -> Result<(), AppError> {
+    let dynamodb_client = DynamoDBClient::new().await;
+    let user_master = dynamodb_client.extract_user_master().await?;
+    match command {
+        UsersCommand::List => {
+            for user in &user_master.users {
+                let availability = match (&user.away, user.paused) {
+                    (_, true) => "paused".to_string(),
+                    (Some(away), false) => format!("away {} - {}", away.from, away.until),
+                    (None, false) => "-".to_string(),
+                };
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    user.order, user.name, user.spotify_user_id, user.discord_user_id, availability
+                );
+            }
+        }
+        UsersCommand::Add {
+            name,
+            spotify_user_id,
+            discord_user_id,
+        } => {
+            if user_master
+                .get_user_by_spotify_id(&spotify_user_id)
+                .is_some()
+            {
+                return Err(AppError::Config(format!(
+                    "user already exists: {spotify_user_id}"
+                )));
+            }
+            let user = User {
+                name,
+                spotify_user_id,
+                discord_user_id,
+                order: user_master.next_order(),
+                paused: false,
+                away: None,
+            };
+            dynamodb_client
+                .replace_users(&[], std::slice::from_ref(&user))
+                .await?;
+            println!("added {} at {}", user.name, user.order);
+        }
+        UsersCommand::Remove { spotify_user_id } => {
+            let user = find_user(&user_master, &spotify_user_id)?;
+            dynamodb_client
+                .replace_users(std::slice::from_ref(user), &[])
+                .await?;
+            println!("removed {}", user.name);
+        }
+        UsersCommand::Reorder { spotify_user_ids } => {
+            let reordered = user_master.reorder(&spotify_user_ids)?;
+            // 順番が変わらないユーザーは書き換えない
+            let (removed, added): (Vec<User>, Vec<User>) = user_master
+                .users
+                .iter()
+                .filter_map(|user| {
+                    reordered
+                        .iter()
+                        .find(|u| {
+                            u.spotify_user_id == user.spotify_user_id && u.order != user.order
+                        })
+                        .map(|u| (user.clone(), u.clone()))
+                })
+                .unzip();
+            dynamodb_client.replace_users(&removed, &added).await?;
+            for user in &reordered {
+                println!("{}\t{}", user.order, user.name);
+            }
+        }
+        UsersCommand::Pause { spotify_user_id } => {
+            let user = find_user(&user_master, &spotify_user_id)?;
+            let updated = User {
+                paused: true,
+                ..user.clone()
+            };
+            dynamodb_client
+                .replace_users(&[], std::slice::from_ref(&updated))
+                .await?;
+            println!("paused {}", updated.name);
+        }
+        UsersCommand::Resume { spotify_user_id } => {
+            let user = find_user(&user_master, &spotify_user_id)?;
+            let updated = User {
+                paused: false,
+                away: None,
+                ..user.clone()
+            };
+            dynamodb_client
+                .replace_users(&[], std::slice::from_ref(&updated))
+                .await?;
+            println!("resumed {}", updated.name);
+        }
+        UsersCommand::Away {
+            spotify_user_id,
+            from,
+            until,
+        } => {
+            let user = find_user(&user_master, &spotify_user_id)?;
+            let updated = User {
+                away: Some(AwayPeriod::new(&from, &until)?),
+                ..user.clone()
+            };
+            dynamodb_client
+                .replace_users(&[], std::slice::from_ref(&updated))
+                .await?;
+            println!("{} is away from {} until {}", updated.name, from, until);
+        }
+    }
+    Ok(())
+}
+
+fn find_user<'a>(user_master: &'a UserMaster, spotify_user_id: &str) -> Result<&'a User, AppError> {
+    if let Some(user) = user_master.get_user_by_spotify_id(spotify_user_id) {
+        Ok(user)
+    } else {
+        Err(AppError::Config(format!("unknown user: {spotify_user_id}")))
+    }
+}
+
+async fn execute_state(command: StateCommand) -> Result<(), AppError> {
+    let dynamodb_client = DynamoDBClient::new().await;
+    match command {
+        StateCommand::Show { playlist_id } => {
+            let state = extract_playlist_state(&dynamodb_client, &playlist_id).await?;
+            println!("snapshot_id: {}", state.snapshot_id);
+            println!("version: {}", state.version);
+            match &state.turn {
+                Some(turn) => println!(
+                    "turn: #{} {} (started_at: {}, reminders: {})",
+                    turn.number, turn.spotify_user_id, turn.started_at, turn.reminder_count
+                ),
+                None => println!("turn: -"),
+            }
+            println!("tracks: {}", state.tracks.len());
+            for track in &state.tracks {
+                println!(
+                    "  {}\t{}\t{}",
+                    track.added_at, track.added_by, track.track_id
+                );
+            }
+            let skipped_turns = dynamodb_client.extract_skipped_turns(&playlist_id).await?;
+            println!("skipped turns: {}", skipped_turns.len());
+            for skipped_turn in &skipped_turns {
+                println!(
+                    "  #{}\t{}\t{}\t{}",
+                    skipped_turn.number,
+                    skipped_turn.spotify_user_id,
+                    skipped_turn.skipped_at,
+                    skipped_turn.reason.as_str()
+                );
+            }
+        }
+        StateCommand::Set {
+            playlist_id,
+            turn,
+            snapshot_id,
+        } => {
+            let state = extract_playlist_state(&dynamodb_client, &playlist_id).await?;
+            if let Some(turn) = &turn {
+                let user_master = dynamodb_client.extract_user_master().await?;
+                if user_master.get_user_by_spotify_id(turn).is_none() {
+                    return Err(AppError::Config(format!("unknown user: {turn}")));
+                }
+            }
+            // 番を変更した場合は、新しい番として今から数える
+            let new_turn = match turn {
+                Some(spotify_user_id) => Some(Turn {
+                    spotify_user_id,
+                    started_at: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                    number: state.turn.as_ref().map_or(0, |t| t.number) + 1,
+                    reminder_count: 0,
+                }),
+                None => state.turn.clone(),
+            };
+            let new_state = PlaylistState {
+                snapshot_id: snapshot_id.unwrap_or(state.snapshot_id),
+                turn: new_turn,
+                version: state.version + 1,
+                ..state
+            };
+            dynamodb_client
+                .update_playlist_state(&playlist_id, &new_state)
+                .await?;
+            println!(
+                "{}: state updated to version {}",
+                playlist_id, new_state.version
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn extract_playlist_state(
+    dynamodb_client: &DynamoDBClient,
+    playlist_id: &str,
+) -> Result<PlaylistState, AppError> {
+    if let Some(state) = dynamodb_client.extract_playlist_state(playlist_id).await? {
+        Ok(state)
+    } else {
+        Err(DomainError::NoPlaylistState {
+            playlist_id: playlist_id.to_string(),
+        }
+        .into())
+    }
+}
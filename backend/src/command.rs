@@ -0,0 +1,110 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{discord::DiscordCreateMessageRequest, error::AppError};
+
+// Lambdaのペイロードで指定する操作
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case", deny_unknown_fields)]
+pub enum LambdaCommand {
+    // playlist_idを省略した場合はすべてのプレイリストが対象
+    Check { playlist_id: Option<String> },
+    // 通知もDynamoDBへの書き込みもせずに、通知内容を出力する
+    DryRun { playlist_id: Option<String> },
+    // 最後の通知をもう一度送る
+    ResendLast { playlist_id: String },
+    // 現在の番の人をスキップして次の人に回す
+    SkipTurn { playlist_id: String },
+    // 通知せずに現在のプレイリストの曲で状態を作り直す
+    RebuildState { playlist_id: Option<String> },
+}
+
+impl LambdaCommand {
+    // スケジュール実行のペイロードは空になる
+    pub fn is_scheduled_payload(payload: &Value) -> bool {
+        match payload {
+            Value::Null => true,
+            Value::Object(object) => object.is_empty(),
+            _ => false,
+        }
+    }
+
+    // スケジュール実行など、actionを指定しない場合は通常のチェックを行う
+    pub fn from_payload(payload: Value) -> Result<Self, AppError> {
+        if Self::is_scheduled_payload(&payload) {
+            return Ok(Self::Check { playlist_id: None });
+        }
+        serde_json::from_value(payload)
+            .map_err(|e| AppError::Config(format!("invalid payload: {e}")))
+    }
+}
+
+// Lambdaのレスポンス。dry_runとロックを取得できなかった場合以外では空になる
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct LambdaResponse {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dry_run: Vec<DryRunPayload>,
+    // 別の実行がロックを持っていたため、何もしなかった
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub skipped: bool,
+}
+
+// 送信されるはずだったDiscordのメッセージ
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DryRunPayload {
+    pub playlist_id: String,
+    pub discord_messages: Vec<DiscordCreateMessageRequest>,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_is_scheduled_payload() {
+        assert!(LambdaCommand::is_scheduled_payload(&json!({})));
+        assert!(LambdaCommand::is_scheduled_payload(&Value::Null));
+        // 運用者が明示的に指定した操作
+        assert!(!LambdaCommand::is_scheduled_payload(
+            &json!({"action": "check"})
+        ));
+    }
+
+    #[test]
+    fn test_from_payload() {
+        assert_eq!(
+            LambdaCommand::from_payload(json!({})).unwrap(),
+            LambdaCommand::Check { playlist_id: None }
+        );
+        assert_eq!(
+            LambdaCommand::from_payload(json!({"action": "check", "playlist_id": "playlist_1"}))
+                .unwrap(),
+            LambdaCommand::Check {
+                playlist_id: Some("playlist_1".to_string())
+            }
+        );
+        assert_eq!(
+            LambdaCommand::from_payload(json!({"action": "dry_run"})).unwrap(),
+            LambdaCommand::DryRun { playlist_id: None }
+        );
+        assert_eq!(
+            LambdaCommand::from_payload(
+                json!({"action": "skip_turn", "playlist_id": "playlist_1"})
+            )
+            .unwrap(),
+            LambdaCommand::SkipTurn {
+                playlist_id: "playlist_1".to_string()
+            }
+        );
+        // 対象のプレイリストが必須の操作
+        assert!(LambdaCommand::from_payload(json!({"action": "resend_last"})).is_err());
+        // 打ち間違いで通常のチェックが実行されないようにする
+        assert!(LambdaCommand::from_payload(json!({"action": "chek"})).is_err());
+        assert!(
+            LambdaCommand::from_payload(json!({"action": "check", "playlistid": "playlist_1"}))
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use sha2::{Digest, Sha256};
+
+use crate::{playlist::TrackFingerprint, spotify::SpotifyPlaylistItem};
+
+#[derive(Debug, PartialEq)]
+pub struct MovedTrack {
+    pub fingerprint: TrackFingerprint,
+    pub from_position: usize,
+    pub to_position: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct PlaylistDiff<'a> {
+    pub added: Vec<&'a SpotifyPlaylistItem>,
+    pub removed: Vec<TrackFingerprint>,
+    pub moved: Vec<MovedTrack>,
+}
+
+impl<'a> PlaylistDiff<'a> {
+    pub fn compute(previous: &[TrackFingerprint], current: &'a [SpotifyPlaylistItem]) -> Self {
+        // 同じ曲が同じ人に同時刻で複数回追加されていても対応できるように、位置のキューで突き合わせる
+        let mut previous_positions: HashMap<&TrackFingerprint, VecDeque<usize>> = HashMap::new();
+        for (position, fingerprint) in previous.iter().enumerate() {
+            previous_positions
+                .entry(fingerprint)
+                .or_default()
+                .push_back(position);
+        }
+
+        let current_fingerprints = current
+            .iter()
+            .map(TrackFingerprint::from_item)
+            .collect::<Vec<TrackFingerprint>>();
+        let mut added_positions = Vec::new();
+        // (以前の位置, 現在の位置)
+        let mut matched = Vec::new();
+        let mut unmatched = Vec::new();
+        for (position, fingerprint) in current_fingerprints.iter().enumerate() {
+            match previous_positions
+                .get_mut(fingerprint)
+                .and_then(|positions| positions.pop_front())
+            {
+                Some(previous_position) => matched.push((previous_position, position)),
+                None => unmatched.push(position),
+            }
+        }
+        // 曲が利用できなくなった(または戻った)項目はIDが変わるため、追加日時と追加した人で突き合わせる
+        for position in unmatched {
+            let fingerprint = &current_fingerprints[position];
+            let previous_position = previous_positions
+                .iter_mut()
+                .find(|(previous, positions)| {
+                    !positions.is_empty()
+                        && previous.added_at == fingerprint.added_at
+                        && previous.added_by == fingerprint.added_by
+                        && (previous.is_unavailable() || fingerprint.is_unavailable())
+                })
+                .and_then(|(_, positions)| positions.pop_front());
+            match previous_position {
+                Some(previous_position) => matched.push((previous_position, position)),
+                // 中身のない項目は、追加として通知しない
+                None if fingerprint.is_unavailable() => {}
+                None => added_positions.push(position),
+            }
+        }
+        matched.sort_unstable_by_key(|(_, position)| *position);
+        let added = added_positions
+            .into_iter()
+            .map(|position| &current[position])
+            .collect();
+
+        let mut removed_positions = previous_positions
+            .into_values()
+            .flatten()
+            .collect::<Vec<usize>>();
+        removed_positions.sort_unstable();
+        let removed = removed_positions
+            .into_iter()
+            .map(|position| previous[position].clone())
+            .collect();
+
+        // 相対順序が保たれている最長の並びを基準にし、そこから外れた曲を移動したとみなす
+        let stable = longest_increasing_subsequence(
+            &matched
+                .iter()
+                .map(|(from, _)| *from)
+                .collect::<Vec<usize>>(),
+        )
+        .into_iter()
+        .collect::<HashSet<usize>>();
+        let moved = matched
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !stable.contains(i))
+            .map(|(_, (from, to))| MovedTrack {
+                fingerprint: previous[*from].clone(),
+                from_position: *from,
+                to_position: *to,
+            })
+            .collect();
+
+        Self {
+            added,
+            removed,
+            moved,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.moved.is_empty()
+    }
+
+    // 同じ状態からの同じ変更であれば同じキーになる。再実行時の二重通知の判定に使う
+    pub fn idempotency_key(&self, previous_snapshot_id: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(previous_snapshot_id);
+        for item in &self.added {
+            let fingerprint = TrackFingerprint::from_item(item);
+            hasher.update(format!(
+                "\n+{}\t{}\t{}",
+                fingerprint.track_id, fingerprint.added_at, fingerprint.added_by
+            ));
+        }
+        for fingerprint in &self.removed {
+            hasher.update(format!(
+                "\n-{}\t{}\t{}",
+                fingerprint.track_id, fingerprint.added_at, fingerprint.added_by
+            ));
+        }
+        for moved_track in &self.moved {
+            let fingerprint = &moved_track.fingerprint;
+            hasher.update(format!(
+                "\n~{}\t{}\t{}\t{}\t{}",
+                fingerprint.track_id,
+                fingerprint.added_at,
+                fingerprint.added_by,
+                moved_track.from_position,
+                moved_track.to_position
+            ));
+        }
+        hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+}
+
+// 最長増加部分列に含まれる要素のインデックスを返す
+fn longest_increasing_subsequence(values: &[usize]) -> Vec<usize> {
+    // tails[k]: 長さk+1の増加部分列の末尾要素のインデックス
+    let mut tails: Vec<usize> = Vec::new();
+    let mut predecessors: Vec<Option<usize>> = vec![None; values.len()];
+    for (i, value) in values.iter().enumerate() {
+        let k = tails.partition_point(|&t| values[t] < *value);
+        if k > 0 {
+            predecessors[i] = Some(tails[k - 1]);
+        }
+        if k == tails.len() {
+            tails.push(i);
+        } else {
+            tails[k] = i;
+        }
+    }
+    let mut indices = Vec::with_capacity(tails.len());
+    let mut cursor = tails.last().copied();
+    while let Some(i) = cursor {
+        indices.push(i);
+        cursor = predecessors[i];
+    }
+    indices.reverse();
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_item(track_id: &str, added_at: &str) -> SpotifyPlaylistItem {
+        SpotifyPlaylistItem::new_test_data(track_id, "spotify_user_1", added_at)
+    }
+
+    fn fingerprints(items: &[SpotifyPlaylistItem]) -> Vec<TrackFingerprint> {
+        items.iter().map(TrackFingerprint::from_item).collect()
+    }
+
+    fn added_ids(diff: &PlaylistDiff<'_>) -> Vec<String> {
+        diff.added.iter().map(|i| i.track_id()).collect()
+    }
+
+    #[test]
+    fn test_no_changes() {
+        let items = vec![new_item("a", "1"), new_item("b", "2")];
+        let diff = PlaylistDiff::compute(&fingerprints(&items), &items);
+        assert!(diff.is_empty());
+    }
+
+    #[test]
+    fn test_added_tracks() {
+        let previous = vec![new_item("a", "1")];
+        let current = vec![new_item("a", "1"), new_item("b", "2"), new_item("c", "3")];
+        let diff = PlaylistDiff::compute(&fingerprints(&previous), &current);
+        assert_eq!(added_ids(&diff), vec!["b", "c"]);
+        assert!(diff.removed.is_empty());
+        assert!(diff.moved.is_empty());
+    }
+
+    #[test]
+    fn test_unavailable_track() {
+        // Spotifyから曲が削除されるとtrackがnullになるが、プレイリストからは削除されていない
+        let unavailable = || SpotifyPlaylistItem {
+            track: None,
+            ..new_item("b", "2")
+        };
+        let previous = vec![new_item("a", "1"), new_item("b", "2")];
+        let current = vec![new_item("a", "1"), unavailable(), new_item("c", "3")];
+        let diff = PlaylistDiff::compute(&fingerprints(&previous), &current);
+        assert_eq!(added_ids(&diff), vec!["c"]);
+        assert!(diff.removed.is_empty());
+        assert!(diff.moved.is_empty());
+        assert_eq!(fingerprints(&current)[1].track_id, "unavailable:2");
+
+        // 利用できるように戻っても追加として通知しない
+        let previous = fingerprints(&[new_item("a", "1"), unavailable()]);
+        let current = vec![new_item("a", "1"), new_item("b", "2")];
+        let diff = PlaylistDiff::compute(&previous, &current);
+        assert!(diff.is_empty());
+
+        // 利用できなくなった曲が実際に削除された場合は削除として通知する
+        let previous = fingerprints(&[new_item("a", "1"), unavailable()]);
+        let current = vec![new_item("a", "1")];
+        let diff = PlaylistDiff::compute(&previous, &current);
+        assert_eq!(diff.removed.len(), 1);
+    }
+
+    #[test]
+    fn test_same_track_added_twice() {
+        let previous = vec![new_item("a", "1")];
+        let current = vec![new_item("a", "1"), new_item("a", "2")];
+        let diff = PlaylistDiff::compute(&fingerprints(&previous), &current);
+        assert_eq!(added_ids(&diff), vec!["a"]);
+        assert_eq!(diff.added[0].added_at, "2");
+        assert!(diff.removed.is_empty());
+    }
+
+    #[test]
+    fn test_last_notified_track_removed() {
+        let previous = vec![new_item("a", "1"), new_item("b", "2")];
+        let current = vec![new_item("a", "1"), new_item("c", "3")];
+        let diff = PlaylistDiff::compute(&fingerprints(&previous), &current);
+        assert_eq!(added_ids(&diff), vec!["c"]);
+        assert_eq!(
+            diff.removed,
+            vec![TrackFingerprint::from_item(&previous[1])]
+        );
+        assert!(diff.moved.is_empty());
+    }
+
+    #[test]
+    fn test_reordered_track() {
+        let previous = vec![
+            new_item("a", "1"),
+            new_item("b", "2"),
+            new_item("c", "3"),
+            new_item("d", "4"),
+        ];
+        let current = vec![
+            new_item("d", "4"),
+            new_item("a", "1"),
+            new_item("b", "2"),
+            new_item("c", "3"),
+        ];
+        let diff = PlaylistDiff::compute(&fingerprints(&previous), &current);
+        assert!(diff.added.is_empty());
+        assert!(diff.removed.is_empty());
+        assert_eq!(
+            diff.moved,
+            vec![MovedTrack {
+                fingerprint: TrackFingerprint::from_item(&previous[3]),
+                from_position: 3,
+                to_position: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_longest_increasing_subsequence() {
+        assert_eq!(longest_increasing_subsequence(&[]), Vec::<usize>::new());
+        assert_eq!(longest_increasing_subsequence(&[0, 1, 2]), vec![0, 1, 2]);
+        assert_eq!(longest_increasing_subsequence(&[3, 0, 1, 2]), vec![1, 2, 3]);
+        assert_eq!(longest_increasing_subsequence(&[1, 0, 3, 2]).len(), 2);
+    }
+
+    #[test]
+    fn test_idempotency_key() {
+        let previous = vec![new_item("a", "1")];
+        let current = vec![new_item("a", "1"), new_item("b", "2")];
+        let diff = PlaylistDiff::compute(&fingerprints(&previous), &current);
+        let key = diff.idempotency_key("snapshot_1");
+        assert_eq!(key.len(), 64);
+        assert_eq!(
+            key,
+            PlaylistDiff::compute(&fingerprints(&previous), &current).idempotency_key("snapshot_1")
+        );
+        assert_ne!(key, diff.idempotency_key("snapshot_2"));
+        let current = vec![new_item("a", "1"), new_item("b", "3")];
+        assert_ne!(
+            key,
+            PlaylistDiff::compute(&fingerprints(&previous), &current).idempotency_key("snapshot_1")
+        );
+    }
+}
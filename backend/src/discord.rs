@@ -1,13 +1,432 @@
-use std::env;
+use std::{error::Error, fmt, time::Duration};
 
-use reqwest::header::{AUTHORIZATION, CONTENT_TYPE, HeaderMap};
-use serde::Serialize;
+use mockall::automock;
+use reqwest::{StatusCode, header::AUTHORIZATION};
+use serde::{Deserialize, Serialize};
 
-use crate::OpaqueError;
+use crate::{
+    error::{AppError, require_env},
+    notifier::{NotifiedTrack, NotifiedUser, PlaylistNotification, TurnReminder},
+    template::{MessageTemplates, render},
+};
 
-#[derive(Serialize)]
-struct DiscordCreateMessageRequest {
-    content: String,
+// https://discord.com/developers/docs/resources/message#create-message
+const MAX_CONTENT_LENGTH: usize = 2000;
+const MAX_EMBEDS_PER_MESSAGE: usize = 10;
+const MAX_EMBEDS_TEXT_LENGTH: usize = 6000;
+// https://discord.com/developers/docs/resources/message#embed-object-embed-limits
+const MAX_EMBED_TITLE_LENGTH: usize = 256;
+const MAX_EMBED_DESCRIPTION_LENGTH: usize = 4096;
+const MAX_EMBED_FIELD_VALUE_LENGTH: usize = 1024;
+
+// レート制限時に待ってから再送する回数と、待つ時間の上限(秒)
+const MAX_RATE_LIMIT_RETRIES: usize = 2;
+const MAX_RETRY_AFTER_SECONDS: f64 = 10.0;
+// 429のボディからretry_afterを読めなかったときに待つ時間(秒)
+const DEFAULT_RETRY_AFTER_SECONDS: f64 = 1.0;
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DiscordCreateMessageRequest {
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub content: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub embeds: Vec<DiscordEmbed>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DiscordEmbedThumbnail {
+    pub url: String,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DiscordEmbedField {
+    pub name: String,
+    pub value: String,
+    pub inline: bool,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DiscordEmbed {
+    pub title: String,
+    // ローカルファイルにはURLがない
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub url: String,
+    pub description: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail: Option<DiscordEmbedThumbnail>,
+    pub fields: Vec<DiscordEmbedField>,
+    // ISO8601形式
+    pub timestamp: String,
+}
+
+impl DiscordEmbed {
+    // 1メッセージあたりの埋め込みの合計文字数の制限に含まれる文字数
+    fn text_length(&self) -> usize {
+        self.title.chars().count()
+            + self.description.chars().count()
+            + self
+                .fields
+                .iter()
+                .map(|f| f.name.chars().count() + f.value.chars().count())
+                .sum::<usize>()
+    }
+}
+
+impl DiscordCreateMessageRequest {
+    // 追加された曲と、削除・移動された曲はそれぞれ別のメッセージとして送る
+    // Discordの文字数・埋め込み数の制限を超える場合は複数のメッセージに分割する
+    pub fn from_notification(notification: &PlaylistNotification) -> Vec<Self> {
+        let mut requests = Vec::new();
+        if !notification.added_tracks.is_empty() {
+            requests.extend(Self::added_tracks(notification));
+        }
+        if !notification.removed_tracks.is_empty() || !notification.moved_tracks.is_empty() {
+            requests.extend(Self::removed_and_moved_tracks(notification));
+        }
+        if let Some(skipped_user) = &notification.skipped_user {
+            requests.push(Self::turn_skipped(notification, skipped_user));
+        }
+        if let Some(reminder) = &notification.reminder
+            && let Some(user) = &notification.next_user
+        {
+            requests.push(Self::turn_reminder(notification, reminder, user));
+        }
+        requests
+    }
+
+    fn turn_reminder(
+        notification: &PlaylistNotification,
+        reminder: &TurnReminder,
+        user: &NotifiedUser,
+    ) -> Self {
+        let templates = &notification.templates;
+        let playlist = format_playlist(notification);
+        Self {
+            content: render(
+                reminder.template(templates),
+                &[
+                    ("playlist", &playlist),
+                    ("user", &format_user(user)),
+                    ("days", &reminder.elapsed_days.to_string()),
+                ],
+            ),
+            embeds: vec![],
+        }
+    }
+
+    fn turn_skipped(notification: &PlaylistNotification, skipped_user: &NotifiedUser) -> Self {
+        let templates = &notification.templates;
+        let playlist = format_playlist(notification);
+        let mut message_lines = vec![
+            format!("## {}", templates.title),
+            "\n".to_string(),
+            render(
+                &templates.turn_skipped,
+                &[
+                    ("playlist", &playlist),
+                    ("user", skipped_user.display_name()),
+                ],
+            ),
+        ];
+        if let Some(next_user) = &notification.next_user {
+            message_lines.push(format!("### {}", templates.next_user_heading));
+            message_lines.push("\n".to_string());
+            message_lines.push(format_user(next_user));
+        }
+        Self {
+            content: message_lines.join("\n"),
+            embeds: vec![],
+        }
+    }
+
+    fn added_tracks(notification: &PlaylistNotification) -> Vec<Self> {
+        let templates = &notification.templates;
+        let playlist = format_playlist(notification);
+        let header_lines = vec![
+            format!("## {}", templates.title),
+            "\n".to_string(),
+            render(&templates.playlist_updated, &[("playlist", &playlist)]),
+        ];
+        let mut next_user_lines = Vec::new();
+        if let Some(next_user) = &notification.next_user {
+            next_user_lines.push(format!("### {}", templates.next_user_heading));
+            next_user_lines.push("\n".to_string());
+            next_user_lines.push(format_user(next_user));
+        }
+        let heading_line = format!("### {}", templates.added_tracks_heading);
+        let mut embed_pages = chunk_embeds(
+            notification
+                .added_tracks
+                .iter()
+                .map(|track| build_track_embed(templates, track))
+                .collect(),
+        );
+
+        // 1通に収まる場合は次の人を曲の一覧より先に表示する
+        if embed_pages.len() == 1 {
+            let message_lines = [header_lines, next_user_lines, vec![heading_line]].concat();
+            return vec![Self {
+                content: message_lines.join("\n"),
+                embeds: embed_pages.remove(0),
+            }];
+        }
+        // 複数に分かれる場合は次の人へのメンションを最後のメッセージに含める
+        let last_page_index = embed_pages.len() - 1;
+        embed_pages
+            .into_iter()
+            .enumerate()
+            .map(|(i, embeds)| {
+                let content = if i == 0 {
+                    [header_lines.clone(), vec![heading_line.clone()]]
+                        .concat()
+                        .join("\n")
+                } else if i == last_page_index {
+                    next_user_lines.join("\n")
+                } else {
+                    String::new()
+                };
+                Self { content, embeds }
+            })
+            .collect()
+    }
+
+    fn removed_and_moved_tracks(notification: &PlaylistNotification) -> Vec<Self> {
+        let templates = &notification.templates;
+        let playlist = format_playlist(notification);
+        let mut message_lines = vec![
+            format!("## {}", templates.title),
+            "\n".to_string(),
+            render(
+                &templates.playlist_tracks_changed,
+                &[("playlist", &playlist)],
+            ),
+        ];
+        if !notification.removed_tracks.is_empty() {
+            message_lines.push(format!("### {}", templates.removed_tracks_heading));
+            message_lines.push("\n".to_string());
+            for track in &notification.removed_tracks {
+                message_lines.push(render(
+                    &templates.removed_track,
+                    &[
+                        ("track", &track.url),
+                        ("added_by", &format_user(&track.added_by)),
+                    ],
+                ));
+            }
+        }
+        if !notification.moved_tracks.is_empty() {
+            message_lines.push(format!("### {}", templates.moved_tracks_heading));
+            message_lines.push("\n".to_string());
+            for moved_track in &notification.moved_tracks {
+                message_lines.push(render(
+                    &templates.moved_track,
+                    &[
+                        ("track", &moved_track.track.url),
+                        ("from", &moved_track.from_position.to_string()),
+                        ("to", &moved_track.to_position.to_string()),
+                        ("added_by", &format_user(&moved_track.track.added_by)),
+                    ],
+                ));
+            }
+        }
+        split_content(&message_lines)
+            .into_iter()
+            .map(|content| Self {
+                content,
+                embeds: vec![],
+            })
+            .collect()
+    }
+}
+
+// 行の途中で切らないように、制限文字数に収まるまで行をまとめる
+fn split_content(lines: &[String]) -> Vec<String> {
+    let mut contents = Vec::new();
+    let mut current = String::new();
+    let mut current_length = 0;
+    for line in lines {
+        // 1行で制限を超える場合は文字単位で分割する
+        let chars = line.chars().collect::<Vec<char>>();
+        for chunk in chars.chunks(MAX_CONTENT_LENGTH) {
+            let chunk_length = chunk.len();
+            let separator_length = if current.is_empty() { 0 } else { 1 };
+            if current_length + separator_length + chunk_length > MAX_CONTENT_LENGTH {
+                contents.push(std::mem::take(&mut current));
+                current_length = 0;
+            } else if separator_length > 0 {
+                current.push('\n');
+                current_length += 1;
+            }
+            current.extend(chunk);
+            current_length += chunk_length;
+        }
+    }
+    if !current.is_empty() {
+        contents.push(current);
+    }
+    contents
+}
+
+fn chunk_embeds(embeds: Vec<DiscordEmbed>) -> Vec<Vec<DiscordEmbed>> {
+    let mut pages: Vec<Vec<DiscordEmbed>> = Vec::new();
+    let mut current: Vec<DiscordEmbed> = Vec::new();
+    let mut current_length = 0;
+    for embed in embeds {
+        let length = embed.text_length();
+        if !current.is_empty()
+            && (current.len() == MAX_EMBEDS_PER_MESSAGE
+                || current_length + length > MAX_EMBEDS_TEXT_LENGTH)
+        {
+            pages.push(std::mem::take(&mut current));
+            current_length = 0;
+        }
+        current.push(embed);
+        current_length += length;
+    }
+    if !current.is_empty() {
+        pages.push(current);
+    }
+    pages
+}
+
+// https://discord.com/developers/docs/reference#error-messages
+#[derive(Deserialize, Debug)]
+struct DiscordErrorResponse {
+    message: Option<String>,
+    code: Option<u64>,
+}
+
+// https://discord.com/developers/docs/topics/rate-limits#exceeding-a-rate-limit
+#[derive(Deserialize, Debug)]
+struct DiscordRateLimitResponse {
+    retry_after: f64,
+    #[serde(default)]
+    global: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DiscordError {
+    // 401: トークンが無効
+    Unauthorized {
+        message: String,
+    },
+    // 403: チャンネルへの送信権限がない
+    Forbidden {
+        message: String,
+    },
+    // 404: チャンネルが存在しない
+    NotFound {
+        message: String,
+    },
+    // 429: retry_after秒後に再送できる
+    RateLimited {
+        retry_after: f64,
+        global: bool,
+    },
+    Api {
+        status: u16,
+        code: Option<u64>,
+        message: String,
+    },
+}
+
+impl DiscordError {
+    fn from_response(status: StatusCode, body: &str) -> Self {
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return match serde_json::from_str::<DiscordRateLimitResponse>(body) {
+                Ok(rate_limit) => Self::RateLimited {
+                    retry_after: rate_limit.retry_after,
+                    global: rate_limit.global,
+                },
+                // プロキシなどが返した429も再送できるようにする
+                Err(_) => Self::RateLimited {
+                    retry_after: DEFAULT_RETRY_AFTER_SECONDS,
+                    global: false,
+                },
+            };
+        }
+        let (code, message) = match serde_json::from_str::<DiscordErrorResponse>(body) {
+            Ok(response) => (response.code, response.message.unwrap_or_default()),
+            Err(_) => (None, body.to_string()),
+        };
+        match status {
+            StatusCode::UNAUTHORIZED => Self::Unauthorized { message },
+            StatusCode::FORBIDDEN => Self::Forbidden { message },
+            StatusCode::NOT_FOUND => Self::NotFound { message },
+            _ => Self::Api {
+                status: status.as_u16(),
+                code,
+                message,
+            },
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::RateLimited { .. } => true,
+            Self::Api { status, .. } => *status >= 500,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for DiscordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unauthorized { message } => write!(f, "discord unauthorized: {message}"),
+            Self::Forbidden { message } => write!(f, "discord forbidden: {message}"),
+            Self::NotFound { message } => write!(f, "discord not found: {message}"),
+            Self::RateLimited {
+                retry_after,
+                global,
+            } => write!(
+                f,
+                "discord rate limited: retry after {retry_after}s (global: {global})"
+            ),
+            Self::Api {
+                status,
+                code,
+                message,
+            } => write!(f, "discord api error: {status} {code:?} {message}"),
+        }
+    }
+}
+
+impl Error for DiscordError {}
+
+// 応答を保留したスラッシュコマンドの結果を送る。インタラクションのトークンで認証するためBotのトークンは不要
+// https://discord.com/developers/docs/interactions/receiving-and-responding#edit-original-interaction-response
+pub async fn edit_original_interaction_response(
+    application_id: &str,
+    interaction_token: &str,
+    content: &str,
+) -> Result<(), AppError> {
+    let response = reqwest::Client::new()
+        .patch(format!(
+            "https://discord.com/api/v10/webhooks/{application_id}/{interaction_token}/messages/@original"
+        ))
+        .json(&DiscordCreateMessageRequest {
+            content: content.to_string(),
+            embeds: vec![],
+        })
+        .send()
+        .await
+        // トークンを含むURLがエラーメッセージに出ないようにする
+        .map_err(reqwest::Error::without_url)?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    Err(DiscordError::from_response(status, &response.text().await?).into())
+}
+
+#[automock]
+pub trait DiscordClientTrait {
+    async fn create_message(
+        &self,
+        channel_id: &str,
+        request: &DiscordCreateMessageRequest,
+    ) -> Result<(), AppError>;
 }
 
 pub struct DiscordClient {
@@ -15,86 +434,430 @@ pub struct DiscordClient {
 }
 
 impl DiscordClient {
-    pub fn init() -> Result<Self, OpaqueError> {
-        let bot_token = env::var("DISCORD_BOT_TOKEN")?;
+    pub fn init() -> Result<Self, AppError> {
+        let bot_token = require_env("DISCORD_BOT_TOKEN")?;
         Ok(Self { bot_token })
     }
 }
+
 impl DiscordClient {
-    async fn create_discord_message(
+    // スラッシュコマンドをまとめて登録する(既存の定義は置き換えられる)
+    pub async fn register_commands(
         &self,
-        channel_id: &str,
-        request: &DiscordCreateMessageRequest,
-    ) -> Result<reqwest::Response, OpaqueError> {
-        let mut headers = HeaderMap::new();
-        headers.append(AUTHORIZATION, format!("Bot {}", self.bot_token).parse()?);
-        headers.append(CONTENT_TYPE, "application/json".parse()?);
-        let reqwest_client = reqwest::Client::new();
-        let response = reqwest_client
-            .post(format!(
-                "https://discord.com/api/v10/channels/{channel_id}/messages"
+        application_id: &str,
+        commands: &serde_json::Value,
+    ) -> Result<(), AppError> {
+        let response = reqwest::Client::new()
+            .put(format!(
+                "https://discord.com/api/v10/applications/{application_id}/commands"
             ))
-            .headers(headers)
-            .body(serde_json::to_string(&request)?)
+            .header(AUTHORIZATION, format!("Bot {}", self.bot_token))
+            .json(commands)
             .send()
             .await?;
-        Ok(response)
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        Err(DiscordError::from_response(status, &response.text().await?).into())
     }
+}
 
-    pub async fn send_latest_tracks_and_next_user_message(
+impl DiscordClientTrait for DiscordClient {
+    async fn create_message(
         &self,
         channel_id: &str,
-        playlist_name: &str,
-        playlist_url: &str,
-        latest_track_urls: &[&str],
-        next_user_id: &str,
-    ) -> Result<reqwest::Response, OpaqueError> {
-        let message_lines = vec![
-            "## プレイリスト更新のお知らせ".to_string(),
-            "\n".to_string(),
-            format!("[{playlist_name}]({playlist_url})が更新されました！",),
-            "### 追加された曲".to_string(),
-            "\n".to_string(),
-            latest_track_urls.join("\n"),
-            "### 次の人".to_string(),
-            "\n".to_string(),
-            format!("<@{}>", next_user_id),
-        ];
-        let request = DiscordCreateMessageRequest {
-            content: message_lines.join("\n"),
-        };
-        let response = self.create_discord_message(channel_id, &request).await?;
-        Ok(response)
+        request: &DiscordCreateMessageRequest,
+    ) -> Result<(), AppError> {
+        let reqwest_client = reqwest::Client::new();
+        let mut retries = 0;
+        loop {
+            let response = reqwest_client
+                .post(format!(
+                    "https://discord.com/api/v10/channels/{channel_id}/messages"
+                ))
+                .header(AUTHORIZATION, format!("Bot {}", self.bot_token))
+                .json(request)
+                .send()
+                .await?;
+            let status = response.status();
+            if status.is_success() {
+                return Ok(());
+            }
+            let error = DiscordError::from_response(status, &response.text().await?);
+            // 短時間のレート制限であれば待ってから再送する
+            if let DiscordError::RateLimited { retry_after, .. } = error
+                && retries < MAX_RATE_LIMIT_RETRIES
+                && retry_after <= MAX_RETRY_AFTER_SECONDS
+            {
+                retries += 1;
+                tokio::time::sleep(Duration::from_secs_f64(retry_after)).await;
+                continue;
+            }
+            return Err(error.into());
+        }
+    }
+}
+
+fn format_playlist(notification: &PlaylistNotification) -> String {
+    format!(
+        "[{}]({})",
+        notification.playlist_name, notification.playlist_url
+    )
+}
+
+fn format_user(user: &NotifiedUser) -> String {
+    match &user.discord_user_id {
+        Some(discord_user_id) => format!("<@{}>", discord_user_id),
+        None => format!("`{}`", user.display_name()),
+    }
+}
+
+fn format_duration(duration_ms: u64) -> String {
+    let seconds = duration_ms / 1000;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+// 制限文字数を超える場合は末尾を省略記号に置き換える
+fn truncate(text: String, max_length: usize) -> String {
+    if text.chars().count() <= max_length {
+        return text;
+    }
+    let mut truncated = text.chars().take(max_length - 1).collect::<String>();
+    truncated.push('…');
+    truncated
+}
+
+fn build_track_embed(templates: &MessageTemplates, track: &NotifiedTrack) -> DiscordEmbed {
+    let mut fields = vec![
+        DiscordEmbedField {
+            name: templates.added_by_label.clone(),
+            value: truncate(format_user(&track.added_by), MAX_EMBED_FIELD_VALUE_LENGTH),
+            inline: true,
+        },
+        DiscordEmbedField {
+            name: templates.duration_label.clone(),
+            value: format_duration(track.duration_ms),
+            inline: true,
+        },
+    ];
+    if let Some(preview_url) = &track.preview_url {
+        fields.push(DiscordEmbedField {
+            name: templates.preview_label.clone(),
+            value: truncate(format!("[▶]({preview_url})"), MAX_EMBED_FIELD_VALUE_LENGTH),
+            inline: true,
+        });
+    }
+    DiscordEmbed {
+        title: truncate(track.name.clone(), MAX_EMBED_TITLE_LENGTH),
+        url: track.url.clone(),
+        description: truncate(
+            format!("{} - {}", track.artists.join(", "), track.album_name),
+            MAX_EMBED_DESCRIPTION_LENGTH,
+        ),
+        thumbnail: track
+            .image_url
+            .as_ref()
+            .map(|url| DiscordEmbedThumbnail { url: url.clone() }),
+        fields,
+        timestamp: track.added_at.clone(),
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::env;
+
+    use crate::notifier::NotifiedTrackReference;
+
     use super::*;
 
     #[tokio::test]
     async fn test_create_message() {
         dotenvy::dotenv().ok();
         let channel_id = env::var("DISCORD_CHANNEL_ID").unwrap();
-        let playlist_name = "test";
-        let playlist_url = "https://open.spotify.com/playlist/...";
-        let latest_track_urls = vec![
-            "https://open.spotify.com/track/1",
-            "https://open.spotify.com/track/2",
-        ];
-        let next_user_id = "...";
         let client = DiscordClient::init().unwrap();
-        let res = client
-            .send_latest_tracks_and_next_user_message(
-                &channel_id,
-                playlist_name,
-                playlist_url,
-                &latest_track_urls,
-                next_user_id,
+        for request in
+            DiscordCreateMessageRequest::from_notification(&PlaylistNotification::new_test_data())
+        {
+            client.create_message(&channel_id, &request).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn test_from_notification() {
+        let requests =
+            DiscordCreateMessageRequest::from_notification(&PlaylistNotification::new_test_data());
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[0].content,
+            [
+                "## プレイリスト更新のお知らせ",
+                "\n",
+                "[Test Playlist](https://open.spotify.com/playlist/test)が更新されました！",
+                "### 次の人",
+                "\n",
+                "<@discord_user_2>",
+                "### 追加された曲",
+            ]
+            .join("\n")
+        );
+        let embed = &requests[0].embeds[0];
+        assert_eq!(embed.title, "Track 1");
+        assert_eq!(embed.url, "https://open.spotify.com/track/track_1");
+        assert_eq!(embed.description, "Artist - Album");
+        assert_eq!(
+            embed.thumbnail.as_ref().unwrap().url,
+            "https://i.scdn.co/image/300"
+        );
+        assert_eq!(embed.fields[0].value, "<@discord_user_1>");
+        assert_eq!(embed.fields[1].value, "3:35");
+        assert_eq!(embed.timestamp, "2023-01-01T00:00:00Z");
+
+        assert!(requests[1].embeds.is_empty());
+        assert!(
+            requests[1]
+                .content
+                .contains("https://open.spotify.com/track/track_0 (追加した人: <@discord_user_1>)")
+        );
+        assert!(requests[1].content.contains(
+            "https://open.spotify.com/track/track_2 (3番目 → 1番目、追加した人: `unknown_user`)"
+        ));
+    }
+
+    #[test]
+    fn test_from_notification_english() {
+        let notification = PlaylistNotification {
+            templates: MessageTemplates::english(),
+            ..PlaylistNotification::new_test_data()
+        };
+        let requests = DiscordCreateMessageRequest::from_notification(&notification);
+        assert!(requests[0].content.starts_with("## Playlist update"));
+        assert!(requests[0].content.contains("### Up next"));
+        assert_eq!(requests[0].embeds[0].fields[0].name, "Added by");
+        assert!(
+            requests[1].content.contains(
+                "https://open.spotify.com/track/track_2 (#3 → #1, added by `unknown_user`)"
             )
-            .await
-            .unwrap();
-        println!("{:?}", res);
-        println!("{:?}", res.text().await.unwrap());
+        );
+    }
+
+    #[test]
+    fn test_from_notification_turn_skipped() {
+        let template = PlaylistNotification::new_test_data();
+        let notification = PlaylistNotification {
+            added_tracks: vec![],
+            removed_tracks: vec![],
+            moved_tracks: vec![],
+            skipped_user: template.added_tracks[0].added_by.clone().into(),
+            ..template
+        };
+        assert_eq!(
+            DiscordCreateMessageRequest::from_notification(&notification),
+            vec![DiscordCreateMessageRequest {
+                content: [
+                    "## プレイリスト更新のお知らせ",
+                    "\n",
+                    "[Test Playlist](https://open.spotify.com/playlist/test)のUser 1さんの番をスキップしました",
+                    "### 次の人",
+                    "\n",
+                    "<@discord_user_2>",
+                ]
+                .join("\n"),
+                embeds: vec![],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_from_notification_turn_reminder() {
+        let template = PlaylistNotification::new_test_data();
+        let notification = |number, is_last| PlaylistNotification {
+            added_tracks: vec![],
+            removed_tracks: vec![],
+            moved_tracks: vec![],
+            reminder: Some(TurnReminder {
+                number,
+                elapsed_days: 5,
+                is_last,
+            }),
+            ..template.clone()
+        };
+        assert_eq!(
+            DiscordCreateMessageRequest::from_notification(&notification(1, false)),
+            vec![DiscordCreateMessageRequest {
+                content: "<@discord_user_2>さん、[Test Playlist](https://open.spotify.com/playlist/test)に曲を追加する番です".to_string(),
+                embeds: vec![],
+            }]
+        );
+        assert!(
+            DiscordCreateMessageRequest::from_notification(&notification(2, false))[0]
+                .content
+                .ends_with("5日経ちました。曲を追加してください")
+        );
+        assert!(
+            DiscordCreateMessageRequest::from_notification(&notification(3, true))[0]
+                .content
+                .ends_with("これが最後のリマインドです")
+        );
+    }
+
+    #[test]
+    fn test_from_notification_split_embeds() {
+        let template = PlaylistNotification::new_test_data();
+        let added_tracks = (0..25)
+            .map(|i| NotifiedTrack {
+                track_id: format!("track_{i}"),
+                ..template.added_tracks[0].clone()
+            })
+            .collect();
+        let notification = PlaylistNotification {
+            added_tracks,
+            removed_tracks: vec![],
+            moved_tracks: vec![],
+            ..template
+        };
+        let requests = DiscordCreateMessageRequest::from_notification(&notification);
+        assert_eq!(
+            requests
+                .iter()
+                .map(|r| r.embeds.len())
+                .collect::<Vec<usize>>(),
+            vec![10, 10, 5]
+        );
+        assert!(
+            requests[0]
+                .content
+                .starts_with("## プレイリスト更新のお知らせ")
+        );
+        assert!(!requests[0].content.contains("<@discord_user_2>"));
+        assert!(requests[1].content.is_empty());
+        assert!(requests[2].content.ends_with("<@discord_user_2>"));
+    }
+
+    #[test]
+    fn test_from_notification_truncate_embed() {
+        let template = PlaylistNotification::new_test_data();
+        let track = NotifiedTrack {
+            name: "t".repeat(300),
+            album_name: "a".repeat(5000),
+            preview_url: Some(format!("https://p.scdn.co/{}", "p".repeat(1100))),
+            ..template.added_tracks[0].clone()
+        };
+        // 切り詰めた埋め込みを2つ合わせると合計文字数の制限を超えるためページに分かれる
+        let notification = PlaylistNotification {
+            added_tracks: vec![track.clone(), track],
+            removed_tracks: vec![],
+            moved_tracks: vec![],
+            ..template
+        };
+        let requests = DiscordCreateMessageRequest::from_notification(&notification);
+        assert_eq!(requests.len(), 2);
+        for request in &requests {
+            let embed = &request.embeds[0];
+            assert_eq!(embed.title.chars().count(), MAX_EMBED_TITLE_LENGTH);
+            assert!(embed.title.ends_with('…'));
+            assert_eq!(
+                embed.description.chars().count(),
+                MAX_EMBED_DESCRIPTION_LENGTH
+            );
+            assert!(
+                embed
+                    .fields
+                    .iter()
+                    .all(|f| f.value.chars().count() <= MAX_EMBED_FIELD_VALUE_LENGTH)
+            );
+            assert!(embed.text_length() <= MAX_EMBEDS_TEXT_LENGTH);
+        }
+    }
+
+    #[test]
+    fn test_from_notification_split_content() {
+        let template = PlaylistNotification::new_test_data();
+        let removed_tracks = (0..100)
+            .map(|i| NotifiedTrackReference {
+                url: format!("https://open.spotify.com/track/track_{i}"),
+                ..template.removed_tracks[0].clone()
+            })
+            .collect::<Vec<NotifiedTrackReference>>();
+        let notification = PlaylistNotification {
+            added_tracks: vec![],
+            removed_tracks: removed_tracks.clone(),
+            ..template
+        };
+        let requests = DiscordCreateMessageRequest::from_notification(&notification);
+        assert!(requests.len() > 1);
+        assert!(
+            requests
+                .iter()
+                .all(|r| r.content.chars().count() <= MAX_CONTENT_LENGTH)
+        );
+        let joined = requests
+            .iter()
+            .map(|r| r.content.as_str())
+            .collect::<Vec<&str>>()
+            .join("\n");
+        for track in &removed_tracks {
+            assert!(joined.contains(&format!("{} (", track.url)));
+        }
+    }
+
+    #[test]
+    fn test_discord_error_from_response() {
+        assert_eq!(
+            DiscordError::from_response(
+                StatusCode::TOO_MANY_REQUESTS,
+                r#"{"message": "You are being rate limited.", "retry_after": 1.5, "global": false}"#
+            ),
+            DiscordError::RateLimited {
+                retry_after: 1.5,
+                global: false
+            }
+        );
+        // retry_afterのないボディでも再送できる
+        let error = DiscordError::from_response(StatusCode::TOO_MANY_REQUESTS, "rate limited");
+        assert_eq!(
+            error,
+            DiscordError::RateLimited {
+                retry_after: DEFAULT_RETRY_AFTER_SECONDS,
+                global: false
+            }
+        );
+        assert!(error.is_retryable());
+        assert_eq!(
+            DiscordError::from_response(
+                StatusCode::NOT_FOUND,
+                r#"{"message": "Unknown Channel", "code": 10003}"#
+            ),
+            DiscordError::NotFound {
+                message: "Unknown Channel".to_string()
+            }
+        );
+        assert_eq!(
+            DiscordError::from_response(StatusCode::UNAUTHORIZED, "401: Unauthorized"),
+            DiscordError::Unauthorized {
+                message: "401: Unauthorized".to_string()
+            }
+        );
+        assert_eq!(
+            DiscordError::from_response(
+                StatusCode::BAD_REQUEST,
+                r#"{"message": "Invalid Form Body", "code": 50035}"#
+            ),
+            DiscordError::Api {
+                status: 400,
+                code: Some(50035),
+                message: "Invalid Form Body".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_split_content_long_line() {
+        let lines = vec!["a".repeat(MAX_CONTENT_LENGTH + 10), "b".to_string()];
+        let contents = split_content(&lines);
+        assert_eq!(contents.len(), 2);
+        assert_eq!(contents[0].chars().count(), MAX_CONTENT_LENGTH);
+        assert_eq!(contents[1], format!("{}\nb", "a".repeat(10)));
     }
 }
@@ -1,42 +1,182 @@
-use aws_sdk_dynamodb::types::AttributeValue;
+use std::{
+    collections::HashMap,
+    env,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use aws_sdk_dynamodb::{
+    error::{ProvideErrorMetadata, SdkError},
+    operation::update_item::builders::UpdateItemFluentBuilder,
+    types::{AttributeValue, Delete, Put, TransactWriteItem},
+};
 use mockall::automock;
 
 use crate::{
-    OpaqueError,
-    user::{User, UserMaster},
+    error::AppError,
+    notifier::NotificationTarget,
+    playlist::{
+        NotificationOutbox, PlaylistConfig, PlaylistState, ReminderConfig, SkipReason, SkippedTurn,
+        TrackFingerprint, Turn,
+    },
+    user::{AwayPeriod, User, UserMaster},
 };
 
 const USER_TABLE_NAME: &str = "spotify-playlist-notification_user";
-const LAST_NOTIFIED_TRACK_TABLE_NAME: &str = "spotify-playlist-notification_last_notified_track";
+const PLAYLIST_TABLE_NAME: &str = "spotify-playlist-notification_playlist";
+const PLAYLIST_STATE_TABLE_NAME: &str = "spotify-playlist-notification_playlist_state";
 const SPOTIFY_REFRESH_TOKEN_TABLE_NAME: &str =
     "spotify-playlist-notification_spotify_refresh_token";
+const NOTIFICATION_OUTBOX_TABLE_NAME: &str = "spotify-playlist-notification_notification_outbox";
+const RUN_LOCK_TABLE_NAME: &str = "spotify-playlist-notification_run_lock";
+const SKIPPED_TURN_TABLE_NAME: &str = "spotify-playlist-notification_skipped_turn";
+// outboxはTTLで自動的に削除する
+const NOTIFICATION_OUTBOX_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
 
 #[automock]
 pub trait DynamoDBClientTrait {
-    async fn extract_user_master(&self) -> Result<UserMaster, OpaqueError>;
-    async fn extract_last_notified_track_id(&self) -> Result<Option<String>, OpaqueError>;
-    async fn update_last_notified_track_id(&self, new_track_id: &str) -> Result<(), OpaqueError>;
-    async fn extract_spotify_refresh_token(&self) -> Result<Option<String>, OpaqueError>;
+    async fn extract_user_master(&self) -> Result<UserMaster, AppError>;
+    // orderがキーに含まれるため、並び替えは削除と追加をまとめて行う
+    async fn replace_users(&self, removed: &[User], added: &[User]) -> Result<(), AppError>;
+    async fn extract_playlist_configs(&self) -> Result<Vec<PlaylistConfig>, AppError>;
+    async fn extract_playlist_state(
+        &self,
+        playlist_id: &str,
+    ) -> Result<Option<PlaylistState>, AppError>;
+    async fn update_playlist_state(
+        &self,
+        playlist_id: &str,
+        new_state: &PlaylistState,
+    ) -> Result<(), AppError>;
+    async fn extract_notification_outbox(
+        &self,
+        playlist_id: &str,
+        idempotency_key: &str,
+    ) -> Result<Option<NotificationOutbox>, AppError>;
+    // 再送のために最後に作成したoutboxを取得する
+    async fn extract_latest_notification_outbox(
+        &self,
+        playlist_id: &str,
+    ) -> Result<Option<NotificationOutbox>, AppError>;
+    async fn create_notification_outbox(
+        &self,
+        playlist_id: &str,
+        outbox: &NotificationOutbox,
+    ) -> Result<(), AppError>;
+    async fn add_notification_outbox_delivered_target(
+        &self,
+        playlist_id: &str,
+        idempotency_key: &str,
+        target_key: &str,
+    ) -> Result<(), AppError>;
+    async fn extract_spotify_refresh_token(&self) -> Result<Option<String>, AppError>;
+    // 手動で設定する場合など、現在の値にかかわらず上書きする
+    async fn put_spotify_refresh_token(&self, refresh_token: &str) -> Result<(), AppError>;
     async fn update_spotify_refresh_token(
         &self,
+        previous_refresh_token: &str,
         new_refresh_token: &str,
-    ) -> Result<(), OpaqueError>;
+    ) -> Result<(), AppError>;
+    // リフレッシュトークンが無効になったことを記録する。同じトークンで記録済みの場合はfalseを返す
+    async fn mark_spotify_refresh_token_invalid(
+        &self,
+        refresh_token: &str,
+        reason: &str,
+    ) -> Result<bool, AppError>;
+    // 同じ番のスキップを記録済みの場合は何もしない
+    async fn record_skipped_turn(
+        &self,
+        playlist_id: &str,
+        skipped_turn: &SkippedTurn,
+    ) -> Result<(), AppError>;
+    // 番の通し番号順
+    async fn extract_skipped_turns(&self, playlist_id: &str) -> Result<Vec<SkippedTurn>, AppError>;
+    // 他の実行がロックを保持している場合はfalseを返す
+    async fn acquire_run_lock(&self, owner: &str, lease: Duration) -> Result<bool, AppError>;
+    async fn release_run_lock(&self, owner: &str) -> Result<(), AppError>;
 }
 
+#[derive(Clone)]
 pub struct DynamoDBClient {
     client: aws_sdk_dynamodb::Client,
+    // 同じテーブルを複数の環境(本番、ローカル検証など)で共有するためのキー
+    environment: String,
 }
 
 impl DynamoDBClient {
     pub async fn new() -> Self {
         let config = aws_config::load_from_env().await;
         let client = aws_sdk_dynamodb::Client::new(&config);
-        DynamoDBClient { client }
+        let environment = env::var("APP_ENVIRONMENT").unwrap_or_else(|_| "production".to_string());
+        DynamoDBClient {
+            client,
+            environment,
+        }
+    }
+
+    // 新しいトークンは有効なため、無効になった記録は消す
+    fn set_spotify_refresh_token_request(&self, refresh_token: &str) -> UpdateItemFluentBuilder {
+        self.client
+            .update_item()
+            .table_name(SPOTIFY_REFRESH_TOKEN_TABLE_NAME)
+            .key(
+                "singleton_key",
+                AttributeValue::S("spotify_refresh_token".to_string()),
+            )
+            .update_expression(
+                "SET refresh_token = :new_refresh_token REMOVE invalid_refresh_token, invalid_reason, invalidated_at",
+            )
+            .expression_attribute_values(
+                ":new_refresh_token",
+                AttributeValue::S(refresh_token.to_string()),
+            )
+    }
+
+    fn playlist_state_key(&self, playlist_id: &str) -> HashMap<String, AttributeValue> {
+        HashMap::from([
+            (
+                "playlist_id".to_string(),
+                AttributeValue::S(playlist_id.to_string()),
+            ),
+            (
+                "environment".to_string(),
+                AttributeValue::S(self.environment.clone()),
+            ),
+        ])
+    }
+
+    fn run_lock_key(&self) -> HashMap<String, AttributeValue> {
+        HashMap::from([
+            (
+                "lock_name".to_string(),
+                AttributeValue::S("processor".to_string()),
+            ),
+            (
+                "environment".to_string(),
+                AttributeValue::S(self.environment.clone()),
+            ),
+        ])
+    }
+
+    fn notification_outbox_key(
+        &self,
+        playlist_id: &str,
+        idempotency_key: &str,
+    ) -> HashMap<String, AttributeValue> {
+        HashMap::from([
+            (
+                "playlist_id".to_string(),
+                AttributeValue::S(playlist_id.to_string()),
+            ),
+            (
+                "outbox_id".to_string(),
+                AttributeValue::S(format!("{}#{}", self.environment, idempotency_key)),
+            ),
+        ])
     }
 }
 
 impl DynamoDBClientTrait for DynamoDBClient {
-    async fn extract_user_master(&self) -> Result<UserMaster, OpaqueError> {
+    async fn extract_user_master(&self) -> Result<UserMaster, AppError> {
         let mut users = Vec::new();
         let request = self.client.scan().table_name(USER_TABLE_NAME);
         let response = request.send().await?;
@@ -62,11 +202,27 @@ impl DynamoDBClientTrait for DynamoDBClient {
                     .and_then(|v| v.as_n().ok())
                     .and_then(|s| s.parse::<usize>().ok())
                     .unwrap_or(0);
+                let paused = item
+                    .get("paused")
+                    .and_then(|v| v.as_bool().ok())
+                    .copied()
+                    .unwrap_or(false);
+                let get_s = |key: &str| {
+                    item.get(key)
+                        .and_then(|v| v.as_s().ok())
+                        .map(|s| s.to_string())
+                };
+                let away = match (get_s("away_from"), get_s("away_until")) {
+                    (Some(from), Some(until)) => Some(AwayPeriod { from, until }),
+                    _ => None,
+                };
                 users.push(User {
                     name,
                     spotify_user_id,
                     discord_user_id,
                     order,
+                    paused,
+                    away,
                 });
             }
         }
@@ -74,44 +230,340 @@ impl DynamoDBClientTrait for DynamoDBClient {
         Ok(UserMaster { users })
     }
 
-    async fn extract_last_notified_track_id(&self) -> Result<Option<String>, OpaqueError> {
+    async fn replace_users(&self, removed: &[User], added: &[User]) -> Result<(), AppError> {
+        let mut transact_items = Vec::new();
+        for user in removed {
+            let delete = Delete::builder()
+                .table_name(USER_TABLE_NAME)
+                .set_key(Some(user_key(user)))
+                .build()
+                .map_err(|e| AppError::DynamoDB(Box::new(e)))?;
+            transact_items.push(TransactWriteItem::builder().delete(delete).build());
+        }
+        for user in added {
+            let mut item = user_key(user);
+            item.insert(
+                "spotify_user_id".to_string(),
+                AttributeValue::S(user.spotify_user_id.clone()),
+            );
+            item.insert(
+                "discord_user_id".to_string(),
+                AttributeValue::S(user.discord_user_id.clone()),
+            );
+            if user.paused {
+                item.insert("paused".to_string(), AttributeValue::Bool(true));
+            }
+            if let Some(away) = &user.away {
+                item.insert(
+                    "away_from".to_string(),
+                    AttributeValue::S(away.from.clone()),
+                );
+                item.insert(
+                    "away_until".to_string(),
+                    AttributeValue::S(away.until.clone()),
+                );
+            }
+            let put = Put::builder()
+                .table_name(USER_TABLE_NAME)
+                .set_item(Some(item))
+                .build()
+                .map_err(|e| AppError::DynamoDB(Box::new(e)))?;
+            transact_items.push(TransactWriteItem::builder().put(put).build());
+        }
+        if transact_items.is_empty() {
+            return Ok(());
+        }
+        let request = self
+            .client
+            .transact_write_items()
+            .set_transact_items(Some(transact_items));
+        request.send().await?;
+        Ok(())
+    }
+
+    async fn extract_playlist_configs(&self) -> Result<Vec<PlaylistConfig>, AppError> {
+        let request = self.client.scan().table_name(PLAYLIST_TABLE_NAME);
+        let response = request.send().await?;
+        let mut playlist_configs = response
+            .items
+            .unwrap_or_default()
+            .iter()
+            .map(parse_playlist_config)
+            .collect::<Result<Vec<PlaylistConfig>, AppError>>()?;
+        playlist_configs.sort_by(|a, b| a.playlist_id.cmp(&b.playlist_id));
+        Ok(playlist_configs)
+    }
+
+    async fn extract_playlist_state(
+        &self,
+        playlist_id: &str,
+    ) -> Result<Option<PlaylistState>, AppError> {
         let request = self
             .client
             .get_item()
-            .table_name(LAST_NOTIFIED_TRACK_TABLE_NAME)
-            .key(
-                "singleton_key",
-                AttributeValue::S("last_notified_track_id".to_string()),
-            );
+            .table_name(PLAYLIST_STATE_TABLE_NAME)
+            .set_key(Some(self.playlist_state_key(playlist_id)));
         let response = request.send().await?;
         if let Some(item) = response.item {
-            if let Some(track_id) = item
-                .get("id")
+            let snapshot_id = item
+                .get("snapshot_id")
                 .and_then(|v| v.as_s().ok())
                 .map(|s| s.to_string())
+                .unwrap_or_default();
+            let tracks = item
+                .get("tracks")
+                .and_then(|v| v.as_l().ok())
+                .map(|l| {
+                    l.iter()
+                        .filter_map(|v| v.as_m().ok())
+                        .map(|m| {
+                            let get_s = |key: &str| {
+                                m.get(key)
+                                    .and_then(|v| v.as_s().ok())
+                                    .map(|s| s.to_string())
+                                    .unwrap_or_default()
+                            };
+                            TrackFingerprint {
+                                track_id: get_s("track_id"),
+                                added_at: get_s("added_at"),
+                                added_by: get_s("added_by"),
+                            }
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            // versionの導入前に保存された状態は0とする
+            let version = item
+                .get("version")
+                .and_then(|v| v.as_n().ok())
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(0);
+            let turn = item.get("turn").and_then(|v| v.as_m().ok()).map(|m| {
+                let get_n = |key: &str| {
+                    m.get(key)
+                        .and_then(|v| v.as_n().ok())
+                        .and_then(|s| s.parse::<u64>().ok())
+                        .unwrap_or(0)
+                };
+                Turn {
+                    spotify_user_id: m
+                        .get("spotify_user_id")
+                        .and_then(|v| v.as_s().ok())
+                        .map(|s| s.to_string())
+                        .unwrap_or_default(),
+                    started_at: get_n("started_at"),
+                    number: get_n("number"),
+                    reminder_count: get_n("reminder_count"),
+                }
+            });
+            return Ok(Some(PlaylistState {
+                snapshot_id,
+                tracks,
+                turn,
+                version,
+            }));
+        }
+        Ok(None)
+    }
+
+    async fn update_playlist_state(
+        &self,
+        playlist_id: &str,
+        new_state: &PlaylistState,
+    ) -> Result<(), AppError> {
+        let mut item = self.playlist_state_key(playlist_id);
+        item.insert(
+            "snapshot_id".to_string(),
+            AttributeValue::S(new_state.snapshot_id.clone()),
+        );
+        item.insert(
+            "tracks".to_string(),
+            AttributeValue::L(
+                new_state
+                    .tracks
+                    .iter()
+                    .map(|t| {
+                        AttributeValue::M(HashMap::from([
+                            (
+                                "track_id".to_string(),
+                                AttributeValue::S(t.track_id.clone()),
+                            ),
+                            (
+                                "added_at".to_string(),
+                                AttributeValue::S(t.added_at.clone()),
+                            ),
+                            (
+                                "added_by".to_string(),
+                                AttributeValue::S(t.added_by.clone()),
+                            ),
+                        ]))
+                    })
+                    .collect(),
+            ),
+        );
+        if let Some(turn) = &new_state.turn {
+            item.insert(
+                "turn".to_string(),
+                AttributeValue::M(HashMap::from([
+                    (
+                        "spotify_user_id".to_string(),
+                        AttributeValue::S(turn.spotify_user_id.clone()),
+                    ),
+                    (
+                        "started_at".to_string(),
+                        AttributeValue::N(turn.started_at.to_string()),
+                    ),
+                    (
+                        "number".to_string(),
+                        AttributeValue::N(turn.number.to_string()),
+                    ),
+                    (
+                        "reminder_count".to_string(),
+                        AttributeValue::N(turn.reminder_count.to_string()),
+                    ),
+                ])),
+            );
+        }
+        item.insert(
+            "version".to_string(),
+            AttributeValue::N(new_state.version.to_string()),
+        );
+        // 読み込んだ時点から他の処理に更新されていない場合のみ保存する
+        let request = self
+            .client
+            .put_item()
+            .table_name(PLAYLIST_STATE_TABLE_NAME)
+            .set_item(Some(item));
+        let request = if new_state.version <= 1 {
+            request.condition_expression("attribute_not_exists(version)")
+        } else {
+            request
+                .condition_expression("version = :previous_version")
+                .expression_attribute_values(
+                    ":previous_version",
+                    AttributeValue::N((new_state.version - 1).to_string()),
+                )
+        };
+        request.send().await.map_err(|e| {
+            conditional_check_failed_to_conflict(e, &format!("playlist_state {playlist_id}"))
+        })?;
+        Ok(())
+    }
+
+    async fn extract_notification_outbox(
+        &self,
+        playlist_id: &str,
+        idempotency_key: &str,
+    ) -> Result<Option<NotificationOutbox>, AppError> {
+        let request = self
+            .client
+            .get_item()
+            .table_name(NOTIFICATION_OUTBOX_TABLE_NAME)
+            .set_key(Some(
+                self.notification_outbox_key(playlist_id, idempotency_key),
+            ))
+            .consistent_read(true);
+        let response = request.send().await?;
+        Ok(response
+            .item
+            .map(|item| parse_notification_outbox(idempotency_key, &item)))
+    }
+
+    async fn extract_latest_notification_outbox(
+        &self,
+        playlist_id: &str,
+    ) -> Result<Option<NotificationOutbox>, AppError> {
+        let prefix = format!("{}#", self.environment);
+        let request = self
+            .client
+            .query()
+            .table_name(NOTIFICATION_OUTBOX_TABLE_NAME)
+            .key_condition_expression(
+                "playlist_id = :playlist_id AND begins_with(outbox_id, :prefix)",
+            )
+            .expression_attribute_values(":playlist_id", AttributeValue::S(playlist_id.to_string()))
+            .expression_attribute_values(":prefix", AttributeValue::S(prefix.clone()));
+        let items = request
+            .into_paginator()
+            .items()
+            .send()
+            .collect::<Result<Vec<_>, _>>()
+            .await?;
+        Ok(items
+            .iter()
+            .filter_map(|item| {
+                let outbox_id = item.get("outbox_id").and_then(|v| v.as_s().ok())?;
+                let idempotency_key = outbox_id.strip_prefix(&prefix)?;
+                Some(parse_notification_outbox(idempotency_key, item))
+            })
+            .max_by_key(|outbox| outbox.created_at))
+    }
+
+    async fn create_notification_outbox(
+        &self,
+        playlist_id: &str,
+        outbox: &NotificationOutbox,
+    ) -> Result<(), AppError> {
+        let expires_at = (SystemTime::now() + NOTIFICATION_OUTBOX_TTL)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut item = self.notification_outbox_key(playlist_id, &outbox.idempotency_key);
+        item.insert(
+            "payload".to_string(),
+            AttributeValue::S(outbox.payload.clone()),
+        );
+        item.insert(
+            "created_at".to_string(),
+            AttributeValue::N(outbox.created_at.to_string()),
+        );
+        item.insert(
+            "expires_at".to_string(),
+            AttributeValue::N(expires_at.to_string()),
+        );
+        // 空のString Setは保存できないため、通知済みの通知先は通知のたびに追加する
+        let request = self
+            .client
+            .put_item()
+            .table_name(NOTIFICATION_OUTBOX_TABLE_NAME)
+            .set_item(Some(item))
+            .condition_expression("attribute_not_exists(outbox_id)");
+        match request.send().await {
+            Ok(_) => Ok(()),
+            // 同時に実行された別の処理がすでに作成している
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
             {
-                return Ok(Some(track_id));
+                Ok(())
             }
+            Err(e) => Err(e.into()),
         }
-        Ok(None)
     }
 
-    async fn update_last_notified_track_id(&self, new_track_id: &str) -> Result<(), OpaqueError> {
+    async fn add_notification_outbox_delivered_target(
+        &self,
+        playlist_id: &str,
+        idempotency_key: &str,
+        target_key: &str,
+    ) -> Result<(), AppError> {
         let request = self
             .client
             .update_item()
-            .table_name(LAST_NOTIFIED_TRACK_TABLE_NAME)
-            .key(
-                "singleton_key",
-                AttributeValue::S("last_notified_track_id".to_string()),
-            )
-            .update_expression("SET id = :new_id")
-            .expression_attribute_values(":new_id", AttributeValue::S(new_track_id.to_string()));
+            .table_name(NOTIFICATION_OUTBOX_TABLE_NAME)
+            .set_key(Some(
+                self.notification_outbox_key(playlist_id, idempotency_key),
+            ))
+            .update_expression("ADD delivered_targets :target_key")
+            .expression_attribute_values(
+                ":target_key",
+                AttributeValue::Ss(vec![target_key.to_string()]),
+            );
         request.send().await?;
         Ok(())
     }
 
-    async fn extract_spotify_refresh_token(&self) -> Result<Option<String>, OpaqueError> {
+    async fn extract_spotify_refresh_token(&self) -> Result<Option<String>, AppError> {
         let request = self
             .client
             .get_item()
@@ -121,22 +573,136 @@ impl DynamoDBClientTrait for DynamoDBClient {
                 AttributeValue::S("spotify_refresh_token".to_string()),
             );
         let response = request.send().await?;
-        if let Some(item) = response.item {
-            if let Some(refresh_token) = item
+        if let Some(item) = response.item
+            && let Some(refresh_token) = item
                 .get("refresh_token")
                 .and_then(|v| v.as_s().ok())
                 .map(|s| s.to_string())
-            {
-                return Ok(Some(refresh_token));
-            }
+        {
+            return Ok(Some(refresh_token));
         }
         Ok(None)
     }
 
+    async fn put_spotify_refresh_token(&self, refresh_token: &str) -> Result<(), AppError> {
+        self.set_spotify_refresh_token_request(refresh_token)
+            .send()
+            .await?;
+        Ok(())
+    }
+
     async fn update_spotify_refresh_token(
         &self,
+        previous_refresh_token: &str,
         new_refresh_token: &str,
-    ) -> Result<(), OpaqueError> {
+    ) -> Result<(), AppError> {
+        let request = self
+            .set_spotify_refresh_token_request(new_refresh_token)
+            // 読み込んだ後に他の処理がトークンを更新していた場合は上書きしない
+            .condition_expression("refresh_token = :previous_refresh_token")
+            .expression_attribute_values(
+                ":previous_refresh_token",
+                AttributeValue::S(previous_refresh_token.to_string()),
+            );
+        request
+            .send()
+            .await
+            .map_err(|e| conditional_check_failed_to_conflict(e, "spotify_refresh_token"))?;
+        Ok(())
+    }
+
+    async fn record_skipped_turn(
+        &self,
+        playlist_id: &str,
+        skipped_turn: &SkippedTurn,
+    ) -> Result<(), AppError> {
+        let mut item = HashMap::from([
+            (
+                "playlist_id".to_string(),
+                AttributeValue::S(playlist_id.to_string()),
+            ),
+            (
+                "skipped_turn_id".to_string(),
+                AttributeValue::S(format!("{}#{}", self.environment, skipped_turn.number)),
+            ),
+            (
+                "spotify_user_id".to_string(),
+                AttributeValue::S(skipped_turn.spotify_user_id.clone()),
+            ),
+            (
+                "number".to_string(),
+                AttributeValue::N(skipped_turn.number.to_string()),
+            ),
+            (
+                "skipped_at".to_string(),
+                AttributeValue::N(skipped_turn.skipped_at.to_string()),
+            ),
+            (
+                "reason".to_string(),
+                AttributeValue::S(skipped_turn.reason.as_str().to_string()),
+            ),
+        ]);
+        if let Some(started_at) = skipped_turn.started_at {
+            item.insert(
+                "started_at".to_string(),
+                AttributeValue::N(started_at.to_string()),
+            );
+        }
+        let request = self
+            .client
+            .put_item()
+            .table_name(SKIPPED_TURN_TABLE_NAME)
+            .set_item(Some(item))
+            .condition_expression("attribute_not_exists(skipped_turn_id)");
+        match request.send().await {
+            Ok(_) => Ok(()),
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+            {
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn extract_skipped_turns(&self, playlist_id: &str) -> Result<Vec<SkippedTurn>, AppError> {
+        let request = self
+            .client
+            .query()
+            .table_name(SKIPPED_TURN_TABLE_NAME)
+            .key_condition_expression(
+                "playlist_id = :playlist_id AND begins_with(skipped_turn_id, :prefix)",
+            )
+            .expression_attribute_values(":playlist_id", AttributeValue::S(playlist_id.to_string()))
+            .expression_attribute_values(
+                ":prefix",
+                AttributeValue::S(format!("{}#", self.environment)),
+            );
+        let items = request
+            .into_paginator()
+            .items()
+            .send()
+            .collect::<Result<Vec<_>, _>>()
+            .await?;
+        let mut skipped_turns = items
+            .iter()
+            .map(parse_skipped_turn)
+            .collect::<Vec<SkippedTurn>>();
+        // ソートキーは文字列のため、通し番号で並べ直す
+        skipped_turns.sort_by_key(|s| s.number);
+        Ok(skipped_turns)
+    }
+
+    async fn mark_spotify_refresh_token_invalid(
+        &self,
+        refresh_token: &str,
+        reason: &str,
+    ) -> Result<bool, AppError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
         let request = self
             .client
             .update_item()
@@ -145,13 +711,290 @@ impl DynamoDBClientTrait for DynamoDBClient {
                 "singleton_key",
                 AttributeValue::S("spotify_refresh_token".to_string()),
             )
-            .update_expression("SET refresh_token = :new_refresh_token")
+            .update_expression(
+                "SET invalid_refresh_token = :refresh_token, invalid_reason = :reason, invalidated_at = :now",
+            )
+            // 毎回の実行で通知しないように、同じトークンについては一度だけ記録する
+            .condition_expression(
+                "attribute_not_exists(invalid_refresh_token) OR invalid_refresh_token <> :refresh_token",
+            )
             .expression_attribute_values(
-                ":new_refresh_token",
-                aws_sdk_dynamodb::types::AttributeValue::S(new_refresh_token.to_string()),
+                ":refresh_token",
+                AttributeValue::S(refresh_token.to_string()),
+            )
+            .expression_attribute_values(":reason", AttributeValue::S(reason.to_string()))
+            .expression_attribute_values(":now", AttributeValue::N(now.to_string()));
+        match request.send().await {
+            Ok(_) => Ok(true),
+            Err(e) => match conditional_check_failed_to_conflict(e, "spotify_refresh_token") {
+                AppError::Conflict(_) => Ok(false),
+                e => Err(e),
+            },
+        }
+    }
+
+    async fn acquire_run_lock(&self, owner: &str, lease: Duration) -> Result<bool, AppError> {
+        let now = SystemTime::now();
+        let expires_at = (now + lease)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut item = self.run_lock_key();
+        item.insert("owner".to_string(), AttributeValue::S(owner.to_string()));
+        item.insert(
+            "expires_at".to_string(),
+            AttributeValue::N(expires_at.to_string()),
+        );
+        // 異常終了した実行のロックは期限が切れたら奪う
+        let request = self
+            .client
+            .put_item()
+            .table_name(RUN_LOCK_TABLE_NAME)
+            .set_item(Some(item))
+            .condition_expression("attribute_not_exists(lock_name) OR expires_at < :now")
+            .expression_attribute_values(
+                ":now",
+                AttributeValue::N(
+                    now.duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs()
+                        .to_string(),
+                ),
             );
-        request.send().await?;
-        Ok(())
+        match request.send().await {
+            Ok(_) => Ok(true),
+            Err(e) => match conditional_check_failed_to_conflict(e, "run_lock") {
+                AppError::Conflict(_) => Ok(false),
+                e => Err(e),
+            },
+        }
+    }
+
+    async fn release_run_lock(&self, owner: &str) -> Result<(), AppError> {
+        // 期限切れで他の実行に奪われたロックは削除しない
+        let request = self
+            .client
+            .delete_item()
+            .table_name(RUN_LOCK_TABLE_NAME)
+            .set_key(Some(self.run_lock_key()))
+            .condition_expression("#owner = :owner")
+            .expression_attribute_names("#owner", "owner")
+            .expression_attribute_values(":owner", AttributeValue::S(owner.to_string()));
+        match request.send().await {
+            Ok(_) => Ok(()),
+            Err(e) => match conditional_check_failed_to_conflict(e, "run_lock") {
+                AppError::Conflict(_) => Ok(()),
+                e => Err(e),
+            },
+        }
+    }
+}
+
+fn user_key(user: &User) -> HashMap<String, AttributeValue> {
+    HashMap::from([
+        ("name".to_string(), AttributeValue::S(user.name.clone())),
+        (
+            "order".to_string(),
+            AttributeValue::N(user.order.to_string()),
+        ),
+    ])
+}
+
+fn parse_notification_outbox(
+    idempotency_key: &str,
+    item: &HashMap<String, AttributeValue>,
+) -> NotificationOutbox {
+    let payload = item
+        .get("payload")
+        .and_then(|v| v.as_s().ok())
+        .map(|s| s.to_string())
+        .unwrap_or_default();
+    let delivered_targets = item
+        .get("delivered_targets")
+        .and_then(|v| v.as_ss().ok())
+        .cloned()
+        .unwrap_or_default();
+    let created_at = item
+        .get("created_at")
+        .and_then(|v| v.as_n().ok())
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or(0);
+    NotificationOutbox {
+        idempotency_key: idempotency_key.to_string(),
+        payload,
+        delivered_targets,
+        created_at,
+    }
+}
+
+fn parse_skipped_turn(item: &HashMap<String, AttributeValue>) -> SkippedTurn {
+    let get_n = |key: &str| {
+        item.get(key)
+            .and_then(|v| v.as_n().ok())
+            .and_then(|s| s.parse::<u64>().ok())
+    };
+    SkippedTurn {
+        spotify_user_id: item
+            .get("spotify_user_id")
+            .and_then(|v| v.as_s().ok())
+            .map(|s| s.to_string())
+            .unwrap_or_default(),
+        number: get_n("number").unwrap_or(0),
+        started_at: get_n("started_at"),
+        skipped_at: get_n("skipped_at").unwrap_or(0),
+        reason: item
+            .get("reason")
+            .and_then(|v| v.as_s().ok())
+            .and_then(|s| SkipReason::parse(s))
+            // 理由を記録する前の自動スキップは、期限切れと不在を区別していないため期限切れとみなす
+            .unwrap_or_else(|| {
+                if item.get("automatic").and_then(|v| v.as_bool().ok()) == Some(&true) {
+                    SkipReason::Overdue
+                } else {
+                    SkipReason::Manual
+                }
+            }),
+    }
+}
+
+fn conditional_check_failed_to_conflict<E, R>(e: SdkError<E, R>, resource: &str) -> AppError
+where
+    E: ProvideErrorMetadata + std::error::Error + Send + Sync + 'static,
+    R: std::fmt::Debug + Send + Sync + 'static,
+{
+    if e.code() == Some("ConditionalCheckFailedException") {
+        AppError::Conflict(resource.to_string())
+    } else {
+        e.into()
+    }
+}
+
+fn parse_playlist_config(
+    item: &HashMap<String, AttributeValue>,
+) -> Result<PlaylistConfig, AppError> {
+    let playlist_id = if let Some(playlist_id) = item.get("playlist_id").and_then(|v| v.as_s().ok())
+    {
+        playlist_id.to_string()
+    } else {
+        return Err(AppError::Config(
+            "playlist config without playlist_id".to_string(),
+        ));
+    };
+    // 設定の誤りで通知先が黙って消えると、変更を通知しないまま状態だけが進むためエラーにする
+    let notification_targets = match item.get("notification_targets") {
+        Some(value) => {
+            let list = value.as_l().map_err(|_| {
+                AppError::Config(format!(
+                    "{playlist_id}: notification_targets must be a list"
+                ))
+            })?;
+            list.iter()
+                .enumerate()
+                .map(|(i, v)| parse_notification_target(&playlist_id, i, v))
+                .collect::<Result<Vec<NotificationTarget>, AppError>>()?
+        }
+        // notification_targets導入前の設定との互換性のため
+        None => item
+            .get("discord_channel_id")
+            .and_then(|v| v.as_s().ok())
+            .map(|channel_id| {
+                vec![NotificationTarget::Discord {
+                    channel_id: channel_id.to_string(),
+                }]
+            })
+            .unwrap_or_default(),
+    };
+    if notification_targets.is_empty() {
+        return Err(AppError::Config(format!(
+            "{playlist_id}: no notification targets"
+        )));
+    }
+    let member_spotify_user_ids = item
+        .get("member_spotify_user_ids")
+        .and_then(|v| v.as_l().ok())
+        .map(|l| {
+            l.iter()
+                .filter_map(|v| v.as_s().ok())
+                .map(|s| s.to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    let locale = item
+        .get("locale")
+        .and_then(|v| v.as_s().ok())
+        .map(|s| s.to_string())
+        .unwrap_or_else(|| "ja".to_string());
+    let template_overrides = item
+        .get("template_overrides")
+        .and_then(|v| v.as_m().ok())
+        .map(|m| {
+            m.iter()
+                .filter_map(|(k, v)| v.as_s().ok().map(|s| (k.clone(), s.clone())))
+                .collect()
+        })
+        .unwrap_or_default();
+    let reminder = item.get("reminder").and_then(|v| v.as_m().ok()).map(|m| {
+        let get_n = |key: &str, default: u64| {
+            m.get(key)
+                .and_then(|v| v.as_n().ok())
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(default)
+        };
+        ReminderConfig {
+            grace_period_days: get_n("grace_period_days", 3),
+            interval_days: get_n("interval_days", 1),
+            max_reminders: get_n("max_reminders", 3),
+        }
+    });
+    let turn_deadline_days = item
+        .get("turn_deadline_days")
+        .and_then(|v| v.as_n().ok())
+        .and_then(|s| s.parse::<u64>().ok());
+    Ok(PlaylistConfig {
+        playlist_id,
+        notification_targets,
+        member_spotify_user_ids,
+        locale,
+        template_overrides,
+        reminder,
+        turn_deadline_days,
+    })
+}
+
+fn parse_notification_target(
+    playlist_id: &str,
+    index: usize,
+    value: &AttributeValue,
+) -> Result<NotificationTarget, AppError> {
+    // URLなどの秘密情報を含むため、値そのものはエラーに含めない
+    let invalid = || {
+        AppError::Config(format!(
+            "{playlist_id}: invalid notification target at index {index}"
+        ))
+    };
+    let item = value.as_m().map_err(|_| invalid())?;
+    let get_s = |key: &str| {
+        item.get(key)
+            .and_then(|v| v.as_s().ok())
+            .map(|s| s.to_string())
+            .ok_or_else(invalid)
+    };
+    match get_s("type")?.as_str() {
+        "discord" => Ok(NotificationTarget::Discord {
+            channel_id: get_s("channel_id")?,
+        }),
+        "slack" => Ok(NotificationTarget::Slack {
+            webhook_url: get_s("webhook_url")?,
+        }),
+        "webhook" => Ok(NotificationTarget::Webhook { url: get_s("url")? }),
+        "email" => Ok(NotificationTarget::Email {
+            to: item
+                .get("to")
+                .and_then(|v| v.as_ss().ok())
+                .cloned()
+                .ok_or_else(invalid)?,
+        }),
+        _ => Err(invalid()),
     }
 }
 
@@ -160,6 +1003,142 @@ mod tests {
     use super::*;
     use dotenvy::dotenv;
 
+    #[test]
+    fn test_parse_playlist_config() {
+        let target = |kind: &str, key: &str, value: &str| {
+            AttributeValue::M(HashMap::from([
+                ("type".to_string(), AttributeValue::S(kind.to_string())),
+                (key.to_string(), AttributeValue::S(value.to_string())),
+            ]))
+        };
+        let item = |targets: Option<Vec<AttributeValue>>| {
+            let mut item = HashMap::from([
+                (
+                    "playlist_id".to_string(),
+                    AttributeValue::S("test_playlist".to_string()),
+                ),
+                (
+                    "discord_channel_id".to_string(),
+                    AttributeValue::S("legacy_channel".to_string()),
+                ),
+            ]);
+            if let Some(targets) = targets {
+                item.insert(
+                    "notification_targets".to_string(),
+                    AttributeValue::L(targets),
+                );
+            }
+            item
+        };
+
+        // notification_targetsがある場合は旧形式のdiscord_channel_idを使わない
+        let config = parse_playlist_config(&item(Some(vec![target(
+            "discord",
+            "channel_id",
+            "new_channel",
+        )])))
+        .unwrap();
+        assert_eq!(
+            config.notification_targets,
+            vec![NotificationTarget::Discord {
+                channel_id: "new_channel".to_string()
+            }]
+        );
+        let config = parse_playlist_config(&item(None)).unwrap();
+        assert_eq!(
+            config.notification_targets,
+            vec![NotificationTarget::Discord {
+                channel_id: "legacy_channel".to_string()
+            }]
+        );
+
+        // 不正な通知先は黙って捨てずにエラーにする
+        assert!(matches!(
+            parse_playlist_config(&item(Some(vec![target("line", "token", "x")]))),
+            Err(AppError::Config(_))
+        ));
+        assert!(matches!(
+            parse_playlist_config(&item(Some(vec![target("slack", "url", "x")]))),
+            Err(AppError::Config(_))
+        ));
+        assert!(matches!(
+            parse_playlist_config(&item(Some(vec![]))),
+            Err(AppError::Config(_))
+        ));
+        let mut without_id = item(None);
+        without_id.remove("playlist_id");
+        assert!(matches!(
+            parse_playlist_config(&without_id),
+            Err(AppError::Config(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_skipped_turn() {
+        let item = |key: &str, value: AttributeValue| {
+            HashMap::from([
+                (
+                    "spotify_user_id".to_string(),
+                    AttributeValue::S("spotify_user_1".to_string()),
+                ),
+                ("number".to_string(), AttributeValue::N("3".to_string())),
+                (
+                    "skipped_at".to_string(),
+                    AttributeValue::N("1700000000".to_string()),
+                ),
+                (key.to_string(), value),
+            ])
+        };
+        let skipped_turn =
+            parse_skipped_turn(&item("reason", AttributeValue::S("away".to_string())));
+        assert_eq!(skipped_turn.reason, SkipReason::Away);
+        assert_eq!(skipped_turn.number, 3);
+        assert_eq!(skipped_turn.started_at, None);
+        // 理由を記録する前の記録
+        assert_eq!(
+            parse_skipped_turn(&item("automatic", AttributeValue::Bool(true))).reason,
+            SkipReason::Overdue
+        );
+        assert_eq!(
+            parse_skipped_turn(&item("automatic", AttributeValue::Bool(false))).reason,
+            SkipReason::Manual
+        );
+    }
+
+    #[test]
+    fn test_set_spotify_refresh_token_request() {
+        let dynamodb_client = DynamoDBClient {
+            client: aws_sdk_dynamodb::Client::from_conf(
+                aws_sdk_dynamodb::Config::builder()
+                    .behavior_version(aws_sdk_dynamodb::config::BehaviorVersion::latest())
+                    .region(aws_sdk_dynamodb::config::Region::new("ap-northeast-1"))
+                    .build(),
+            ),
+            environment: "test".to_string(),
+        };
+        // 手動で設定した場合も、無効になった記録を消す
+        let request = dynamodb_client.set_spotify_refresh_token_request("new_refresh_token");
+        let update_expression = request.get_update_expression().as_deref().unwrap();
+        assert!(update_expression.starts_with("SET refresh_token = :new_refresh_token"));
+        for attribute in ["invalid_refresh_token", "invalid_reason", "invalidated_at"] {
+            assert!(
+                update_expression
+                    .split_once("REMOVE ")
+                    .is_some_and(|(_, removed)| removed.split(", ").any(|a| a == attribute)),
+                "{attribute} is not removed"
+            );
+        }
+        assert_eq!(
+            request
+                .get_expression_attribute_values()
+                .as_ref()
+                .and_then(|values| values.get(":new_refresh_token")),
+            Some(&AttributeValue::S("new_refresh_token".to_string()))
+        );
+        // 上書きする場合は現在の値にかかわらず書き込む
+        assert!(request.get_condition_expression().is_none());
+    }
+
     #[tokio::test]
     async fn test_extract_user_master() {
         dotenv().ok();
@@ -171,14 +1150,25 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_extract_last_notified_track_id() {
+    async fn test_extract_playlist_configs() {
+        dotenv().ok();
+        let dynamodb_client = DynamoDBClient::new().await;
+        let playlist_configs = dynamodb_client.extract_playlist_configs().await.unwrap();
+        for playlist_config in playlist_configs {
+            println!("{:?}", playlist_config);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_extract_playlist_state() {
         dotenv().ok();
+        let playlist_id = env::var("SPOTIFY_PLAYLIST_ID").unwrap();
         let dynamodb_client = DynamoDBClient::new().await;
-        let track_id = dynamodb_client
-            .extract_last_notified_track_id()
+        let playlist_state = dynamodb_client
+            .extract_playlist_state(&playlist_id)
             .await
             .unwrap();
-        println!("{:?}", track_id);
+        println!("{:?}", playlist_state);
     }
 
     #[tokio::test]
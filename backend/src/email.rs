@@ -0,0 +1,210 @@
+use lettre::{
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor, message::Mailbox,
+    transport::smtp::authentication::Credentials,
+};
+
+use crate::{
+    error::{AppError, require_env},
+    notifier::PlaylistNotification,
+    template::render,
+};
+
+const DIGEST_SEPARATOR: &str = "\n\n--------------------\n\n";
+
+pub struct EmailClient {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl EmailClient {
+    pub fn init() -> Result<Self, AppError> {
+        let host = require_env("SMTP_HOST")?;
+        let credentials =
+            Credentials::new(require_env("SMTP_USERNAME")?, require_env("SMTP_PASSWORD")?);
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::relay(&host)
+            .map_err(|e| AppError::Config(format!("invalid SMTP_HOST: {e}")))?
+            .credentials(credentials)
+            .build();
+        let from = require_env("SMTP_FROM")?
+            .parse()
+            .map_err(|e| AppError::Config(format!("invalid SMTP_FROM: {e}")))?;
+        Ok(Self { transport, from })
+    }
+
+    // 1回の実行で発生した通知を、まとめて1通のダイジェストとして送る
+    pub async fn send(
+        &self,
+        to: &[String],
+        notifications: &[PlaylistNotification],
+    ) -> Result<(), AppError> {
+        let mut builder = Message::builder()
+            .from(self.from.clone())
+            .subject(build_subject(notifications));
+        for address in to {
+            builder = builder.to(address
+                .parse()
+                .map_err(|e| AppError::Config(format!("invalid email address {address}: {e}")))?);
+        }
+        let message = builder
+            .body(build_digest_body(notifications))
+            .map_err(|e| AppError::Email(Box::new(e)))?;
+        self.transport
+            .send(message)
+            .await
+            .map_err(|e| AppError::Email(Box::new(e)))?;
+        Ok(())
+    }
+}
+
+fn build_subject(notifications: &[PlaylistNotification]) -> String {
+    let mut playlist_names: Vec<&str> = Vec::new();
+    for notification in notifications {
+        if !playlist_names.contains(&notification.playlist_name.as_str()) {
+            playlist_names.push(&notification.playlist_name);
+        }
+    }
+    let title = notifications
+        .first()
+        .map_or("", |notification| notification.templates.title.as_str());
+    format!("[{}] {}", playlist_names.join(", "), title)
+}
+
+fn build_digest_body(notifications: &[PlaylistNotification]) -> String {
+    notifications
+        .iter()
+        .map(build_body)
+        .collect::<Vec<String>>()
+        .join(DIGEST_SEPARATOR)
+}
+
+fn build_body(notification: &PlaylistNotification) -> String {
+    let templates = &notification.templates;
+    let mut body_lines = vec![
+        match (
+            &notification.reminder,
+            &notification.next_user,
+            &notification.skipped_user,
+        ) {
+            (Some(reminder), Some(user), _) => render(
+                reminder.template(templates),
+                &[
+                    ("playlist", &notification.playlist_name),
+                    ("user", user.display_name()),
+                    ("days", &reminder.elapsed_days.to_string()),
+                ],
+            ),
+            (_, _, Some(skipped_user)) => render(
+                &templates.turn_skipped,
+                &[
+                    ("playlist", &notification.playlist_name),
+                    ("user", skipped_user.display_name()),
+                ],
+            ),
+            _ => render(
+                &templates.playlist_updated,
+                &[("playlist", &notification.playlist_name)],
+            ),
+        },
+        notification.playlist_url.clone(),
+    ];
+    if !notification.added_tracks.is_empty() {
+        body_lines.push(String::new());
+        body_lines.push(format!("■ {}", templates.added_tracks_heading));
+        for track in &notification.added_tracks {
+            body_lines.push(format!(
+                "- {}",
+                render(
+                    &templates.added_track,
+                    &[
+                        ("track", &track.name),
+                        ("artists", &track.artists.join(", ")),
+                        ("added_by", track.added_by.display_name()),
+                    ],
+                )
+            ));
+            body_lines.push(format!("  {}", track.url));
+        }
+    }
+    if !notification.removed_tracks.is_empty() {
+        body_lines.push(String::new());
+        body_lines.push(format!("■ {}", templates.removed_tracks_heading));
+        for track in &notification.removed_tracks {
+            body_lines.push(format!(
+                "- {}",
+                render(
+                    &templates.removed_track,
+                    &[
+                        ("track", &track.url),
+                        ("added_by", track.added_by.display_name()),
+                    ],
+                )
+            ));
+        }
+    }
+    if !notification.moved_tracks.is_empty() {
+        body_lines.push(String::new());
+        body_lines.push(format!("■ {}", templates.moved_tracks_heading));
+        for moved_track in &notification.moved_tracks {
+            body_lines.push(format!(
+                "- {}",
+                render(
+                    &templates.moved_track,
+                    &[
+                        ("track", &moved_track.track.url),
+                        ("from", &moved_track.from_position.to_string()),
+                        ("to", &moved_track.to_position.to_string()),
+                        ("added_by", moved_track.track.added_by.display_name()),
+                    ],
+                )
+            ));
+        }
+    }
+    if (!notification.added_tracks.is_empty() || notification.skipped_user.is_some())
+        && let Some(next_user) = &notification.next_user
+    {
+        body_lines.push(String::new());
+        body_lines.push(format!(
+            "{}: {}",
+            templates.next_user_heading,
+            next_user.display_name()
+        ));
+    }
+    body_lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_body() {
+        let notification = PlaylistNotification::new_test_data();
+        assert_eq!(
+            build_subject(std::slice::from_ref(&notification)),
+            "[Test Playlist] プレイリスト更新のお知らせ"
+        );
+        let body = build_body(&notification);
+        assert!(body.contains("- Track 1 - Artist (追加した人: User 1)"));
+        assert!(body.ends_with("次の人: User 2"));
+    }
+
+    #[test]
+    fn test_build_digest() {
+        let updated = PlaylistNotification::new_test_data();
+        let other_playlist = PlaylistNotification {
+            playlist_name: "Other Playlist".to_string(),
+            ..PlaylistNotification::new_test_data()
+        };
+        let notifications = [updated.clone(), updated.clone(), other_playlist];
+        // 同じプレイリストの名前は件名に一度だけ入れる
+        assert_eq!(
+            build_subject(&notifications),
+            "[Test Playlist, Other Playlist] プレイリスト更新のお知らせ"
+        );
+        let body = build_digest_body(&notifications);
+        let sections = body.split(DIGEST_SEPARATOR).collect::<Vec<&str>>();
+        assert_eq!(sections.len(), 3);
+        assert_eq!(sections[0], build_body(&updated));
+        assert!(sections[2].contains("Other Playlist"));
+    }
+}
@@ -0,0 +1,246 @@
+use std::{env, error::Error, fmt};
+
+use aws_sdk_dynamodb::error::SdkError;
+
+use crate::{discord::DiscordError, spotify::SpotifyError};
+
+#[derive(Debug)]
+pub enum AppError {
+    Spotify(SpotifyError),
+    Discord(DiscordError),
+    DynamoDB(Box<dyn Error + Send + Sync + 'static>),
+    // スラッシュコマンドを非同期に実行するためのLambdaの呼び出し
+    Lambda(Box<dyn Error + Send + Sync + 'static>),
+    // Slack・Webhookなど、それ以外のHTTPリクエストの失敗
+    Http(reqwest::Error),
+    Email(Box<dyn Error + Send + Sync + 'static>),
+    // CLIのコールバックサーバーなど
+    Io(std::io::Error),
+    Serialization(serde_json::Error),
+    // 環境変数やテーブルの設定の不備
+    Config(String),
+    Domain(DomainError),
+    // 条件付き書き込みで、同時に実行された別の処理に先に更新された
+    Conflict(String),
+    // 一部のプレイリスト・通知先の失敗をまとめたもの
+    FailedPlaylists(Vec<(String, AppError)>),
+    FailedNotificationTargets(Vec<(String, AppError)>),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DomainError {
+    MissingSpotifyRefreshToken,
+    // 曲を追加した人がローテーションに含まれていない
+    NoNextUser { spotify_user_id: String },
+    NoPlaylistState { playlist_id: String },
+    // 曲がなく、番の人も決まっていない
+    NoCurrentTurn { playlist_id: String },
+    NoNotificationHistory { playlist_id: String },
+}
+
+impl AppError {
+    // 時間をおいて再実行すれば成功する可能性があるか
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Spotify(e) => e.is_retryable(),
+            Self::Discord(e) => e.is_retryable(),
+            Self::DynamoDB(_) | Self::Lambda(_) | Self::Email(_) | Self::Io(_) => true,
+            Self::Http(e) => {
+                e.is_timeout()
+                    || e.is_connect()
+                    || e.status()
+                        .is_some_and(|s| s.is_server_error() || s.as_u16() == 429)
+            }
+            Self::Config(_) | Self::Domain(_) | Self::Serialization(_) | Self::Conflict(_) => false,
+            Self::FailedPlaylists(errors) | Self::FailedNotificationTargets(errors) => {
+                errors.iter().any(|(_, e)| e.is_retryable())
+            }
+        }
+    }
+
+    // ログに出力するイベント名
+    pub fn event_name(&self) -> &'static str {
+        match self {
+            Self::Spotify(_) => "spotify_error",
+            Self::Discord(_) => "discord_error",
+            Self::DynamoDB(_) => "dynamodb_error",
+            Self::Lambda(_) => "lambda_error",
+            Self::Http(_) => "http_error",
+            Self::Email(_) => "email_error",
+            Self::Io(_) => "io_error",
+            Self::Serialization(_) => "serialization_error",
+            Self::Config(_) => "config_error",
+            Self::Domain(_) => "domain_error",
+            Self::Conflict(_) => "conflict",
+            Self::FailedPlaylists(_) => "failed_playlists",
+            Self::FailedNotificationTargets(_) => "failed_notification_targets",
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Spotify(e) => write!(f, "{e}"),
+            Self::Discord(e) => write!(f, "{e}"),
+            Self::DynamoDB(e) => write!(f, "dynamodb error: {e}"),
+            Self::Lambda(e) => write!(f, "lambda error: {e}"),
+            Self::Http(e) => write!(f, "http error: {e}"),
+            Self::Email(e) => write!(f, "email error: {e}"),
+            Self::Io(e) => write!(f, "io error: {e}"),
+            Self::Serialization(e) => write!(f, "serialization error: {e}"),
+            Self::Config(message) => write!(f, "config error: {message}"),
+            Self::Domain(e) => write!(f, "{e}"),
+            Self::Conflict(resource) => write!(f, "{resource} was updated concurrently"),
+            Self::FailedPlaylists(errors) => {
+                write!(f, "failed playlists: {}", join_errors(errors))
+            }
+            Self::FailedNotificationTargets(errors) => {
+                write!(f, "failed notification targets: {}", join_errors(errors))
+            }
+        }
+    }
+}
+
+fn join_errors(errors: &[(String, AppError)]) -> String {
+    errors
+        .iter()
+        .map(|(key, e)| format!("{key} ({e})"))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+impl Error for AppError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Spotify(e) => Some(e),
+            Self::Discord(e) => Some(e),
+            Self::DynamoDB(e) | Self::Lambda(e) | Self::Email(e) => Some(e.as_ref()),
+            Self::Http(e) => Some(e),
+            Self::Io(e) => Some(e),
+            Self::Serialization(e) => Some(e),
+            Self::Domain(e) => Some(e),
+            Self::Config(_)
+            | Self::Conflict(_)
+            | Self::FailedPlaylists(_)
+            | Self::FailedNotificationTargets(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for DomainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingSpotifyRefreshToken => write!(f, "no spotify_refresh_token"),
+            Self::NoNextUser { spotify_user_id } => {
+                write!(f, "no next_user after {spotify_user_id}")
+            }
+            Self::NoPlaylistState { playlist_id } => {
+                write!(f, "no playlist_state for {playlist_id}")
+            }
+            Self::NoCurrentTurn { playlist_id } => {
+                write!(f, "no current turn for {playlist_id}")
+            }
+            Self::NoNotificationHistory { playlist_id } => {
+                write!(f, "no notification history for {playlist_id}")
+            }
+        }
+    }
+}
+
+impl Error for DomainError {}
+
+impl From<SpotifyError> for AppError {
+    fn from(e: SpotifyError) -> Self {
+        Self::Spotify(e)
+    }
+}
+
+impl From<DiscordError> for AppError {
+    fn from(e: DiscordError) -> Self {
+        Self::Discord(e)
+    }
+}
+
+impl From<DomainError> for AppError {
+    fn from(e: DomainError) -> Self {
+        Self::Domain(e)
+    }
+}
+
+impl From<reqwest::Error> for AppError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Http(e)
+    }
+}
+
+impl From<std::io::Error> for AppError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for AppError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Serialization(e)
+    }
+}
+
+impl<E, R> From<SdkError<E, R>> for AppError
+where
+    E: Error + Send + Sync + 'static,
+    R: fmt::Debug + Send + Sync + 'static,
+{
+    fn from(e: SdkError<E, R>) -> Self {
+        Self::DynamoDB(Box::new(e))
+    }
+}
+
+// 設定されていない場合はConfigエラーにする
+pub fn require_env(key: &str) -> Result<String, AppError> {
+    env::var(key).map_err(|_| AppError::Config(format!("{key} is not set")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_retryable() {
+        assert!(!AppError::Config("x".to_string()).is_retryable());
+        assert!(
+            !AppError::Domain(DomainError::NoNextUser {
+                spotify_user_id: "spotify_user_1".to_string()
+            })
+            .is_retryable()
+        );
+        assert!(
+            AppError::Discord(DiscordError::RateLimited {
+                retry_after: 1.0,
+                global: false
+            })
+            .is_retryable()
+        );
+        // 一つでも再実行で成功しうる失敗があれば再実行する
+        let failed_playlists = AppError::FailedPlaylists(vec![
+            (
+                "playlist_1".to_string(),
+                AppError::Spotify(SpotifyError::NotFound {
+                    message: "not found".to_string(),
+                }),
+            ),
+            (
+                "playlist_2".to_string(),
+                AppError::Spotify(SpotifyError::Server {
+                    status: 503,
+                    message: String::new(),
+                }),
+            ),
+        ]);
+        assert!(failed_playlists.is_retryable());
+        assert_eq!(
+            failed_playlists.to_string(),
+            "failed playlists: playlist_1 (spotify not found: not found), playlist_2 (spotify server error: 503 )"
+        );
+    }
+}
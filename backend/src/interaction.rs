@@ -0,0 +1,813 @@
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use aws_sdk_lambda::{primitives::Blob, types::InvocationType};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use crate::{
+    command::{LambdaCommand, LambdaResponse},
+    discord::edit_original_interaction_response,
+    dynamodb::{DynamoDBClient, DynamoDBClientTrait},
+    error::{AppError, DomainError, require_env},
+    notifier::NotificationTarget,
+    playlist::{PlaylistConfig, PlaylistState},
+    processor::{current_turn_user, execute_process},
+};
+
+// https://discord.com/developers/docs/interactions/receiving-and-responding
+const INTERACTION_TYPE_PING: u8 = 1;
+const INTERACTION_TYPE_APPLICATION_COMMAND: u8 = 2;
+const RESPONSE_TYPE_PONG: u8 = 1;
+const RESPONSE_TYPE_CHANNEL_MESSAGE: u8 = 4;
+// 3秒以内に応答できない処理は、応答を保留して後から結果を送る
+const RESPONSE_TYPE_DEFERRED_CHANNEL_MESSAGE: u8 = 5;
+// 実行した人にだけ表示する
+const MESSAGE_FLAG_EPHEMERAL: u64 = 1 << 6;
+// /historyで表示する曲数
+const HISTORY_LENGTH: usize = 5;
+// リプレイ攻撃を防ぐため、署名のタイムスタンプがこれより古いリクエストは拒否する(秒)
+const MAX_SIGNATURE_AGE_SECONDS: u64 = 5 * 60;
+// https://discord.com/developers/docs/topics/permissions#permissions-bitwise-permission-flags
+const PERMISSION_ADMINISTRATOR: u64 = 1 << 3;
+// /checkと/skipはサーバーの管理権限を持つ人だけが実行できる
+const PERMISSION_MANAGE_GUILD: u64 = 1 << 5;
+
+#[derive(Deserialize, Debug)]
+pub struct Interaction {
+    #[serde(rename = "type")]
+    pub interaction_type: u8,
+    pub channel_id: Option<String>,
+    pub data: Option<InteractionData>,
+    // サーバー内で実行された場合のみ含まれる
+    pub member: Option<InteractionMember>,
+    // 保留した応答を後から書き換えるために使う
+    #[serde(default)]
+    pub application_id: String,
+    #[serde(default)]
+    pub token: String,
+}
+
+impl Interaction {
+    fn has_permission(&self, permission: u64) -> bool {
+        self.member.as_ref().is_some_and(|member| {
+            member
+                .permissions
+                .parse::<u64>()
+                .is_ok_and(|permissions| permissions & (permission | PERMISSION_ADMINISTRATOR) != 0)
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InteractionMember {
+    // チャンネルの上書きを含めた権限のビットフラグ(文字列)
+    pub permissions: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InteractionData {
+    pub name: String,
+    #[serde(default)]
+    pub options: Vec<InteractionOption>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InteractionOption {
+    pub name: String,
+    pub value: Value,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct InteractionResponse {
+    #[serde(rename = "type")]
+    pub response_type: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<InteractionResponseData>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct InteractionResponseData {
+    pub content: String,
+    pub flags: u64,
+}
+
+impl InteractionResponse {
+    fn pong() -> Self {
+        Self {
+            response_type: RESPONSE_TYPE_PONG,
+            data: None,
+        }
+    }
+
+    fn deferred() -> Self {
+        Self {
+            response_type: RESPONSE_TYPE_DEFERRED_CHANNEL_MESSAGE,
+            data: None,
+        }
+    }
+
+    fn message(content: String, ephemeral: bool) -> Self {
+        Self {
+            response_type: RESPONSE_TYPE_CHANNEL_MESSAGE,
+            data: Some(InteractionResponseData {
+                content,
+                flags: if ephemeral { MESSAGE_FLAG_EPHEMERAL } else { 0 },
+            }),
+        }
+    }
+}
+
+// 応答を保留したスラッシュコマンド。Lambdaを非同期に呼び出し直して実行する
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeferredInteraction {
+    pub application_id: String,
+    pub token: String,
+    pub command: LambdaCommand,
+}
+
+impl DeferredInteraction {
+    // スケジュール実行などのペイロードと区別する
+    pub fn from_payload(payload: &Value) -> Option<Self> {
+        serde_json::from_value(payload.get("deferred_interaction")?.clone()).ok()
+    }
+
+    fn to_payload(&self) -> Result<Vec<u8>, AppError> {
+        Ok(serde_json::to_vec(
+            &json!({ "deferred_interaction": self }),
+        )?)
+    }
+}
+
+// Lambdaの関数URLから呼び出された場合のイベント
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FunctionUrlRequest {
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub body: Option<String>,
+    #[serde(default)]
+    pub is_base64_encoded: bool,
+}
+
+impl FunctionUrlRequest {
+    // スケジュール実行などのペイロードと区別する
+    pub fn from_payload(payload: &Value) -> Option<Self> {
+        payload.get("requestContext")?.get("http")?;
+        serde_json::from_value(payload.clone()).ok()
+    }
+
+    fn body(&self) -> Vec<u8> {
+        let body = self.body.as_deref().unwrap_or_default();
+        if self.is_base64_encoded {
+            STANDARD.decode(body).unwrap_or_default()
+        } else {
+            body.as_bytes().to_vec()
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FunctionUrlResponse {
+    pub status_code: u16,
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+impl FunctionUrlResponse {
+    fn new(status_code: u16, body: String) -> Self {
+        Self {
+            status_code,
+            headers: HashMap::from([("content-type".to_string(), "application/json".to_string())]),
+            body,
+        }
+    }
+}
+
+// Discordに登録するスラッシュコマンドの定義
+pub fn command_definitions() -> Value {
+    let playlist_option = json!({
+        "type": 3,
+        "name": "playlist",
+        "description": "プレイリストID(省略時はこのチャンネルに通知しているプレイリスト)",
+        "required": false,
+    });
+    json!([
+        {"name": "next", "description": "次に曲を追加する人を表示する", "options": [playlist_option]},
+        {"name": "history", "description": "最近追加された曲を表示する", "options": [playlist_option]},
+        {"name": "check", "description": "プレイリストの更新を今すぐ確認する", "options": [playlist_option], "default_member_permissions": PERMISSION_MANAGE_GUILD.to_string()},
+        {"name": "skip", "description": "現在の番の人をスキップする", "options": [playlist_option], "default_member_permissions": PERMISSION_MANAGE_GUILD.to_string()},
+    ])
+}
+
+// Discordからのリクエストであることを署名(Ed25519)で確認する
+pub fn verify_signature(
+    public_key: &str,
+    signature: &str,
+    timestamp: &str,
+    body: &[u8],
+    now: u64,
+) -> bool {
+    // 署名が正しくても、古いリクエストの再送は受け付けない
+    let is_recent = timestamp
+        .parse::<u64>()
+        .is_ok_and(|t| now.abs_diff(t) <= MAX_SIGNATURE_AGE_SECONDS);
+    if !is_recent {
+        return false;
+    }
+    let verifying_key = hex::decode(public_key)
+        .ok()
+        .and_then(|k| <[u8; 32]>::try_from(k.as_slice()).ok())
+        .and_then(|k| VerifyingKey::from_bytes(&k).ok());
+    let signature = hex::decode(signature)
+        .ok()
+        .and_then(|s| <[u8; 64]>::try_from(s.as_slice()).ok())
+        .map(|s| Signature::from_bytes(&s));
+    match (verifying_key, signature) {
+        (Some(verifying_key), Some(signature)) => verifying_key
+            .verify(&[timestamp.as_bytes(), body].concat(), &signature)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+pub async fn handle_function_url_request(
+    request: &FunctionUrlRequest,
+) -> Result<FunctionUrlResponse, AppError> {
+    let public_key = require_env("DISCORD_PUBLIC_KEY")?;
+    // Lambdaの実行環境で設定される、この関数自身の名前
+    let function_name = require_env("AWS_LAMBDA_FUNCTION_NAME")?;
+    let dynamodb_client = DynamoDBClient::new().await;
+    let lambda_client = aws_sdk_lambda::Client::new(&aws_config::load_from_env().await);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    respond(
+        &public_key,
+        request,
+        &dynamodb_client,
+        now,
+        async |deferred: &DeferredInteraction| {
+            lambda_client
+                .invoke()
+                .function_name(&function_name)
+                .invocation_type(InvocationType::Event)
+                .payload(Blob::new(deferred.to_payload()?))
+                .send()
+                .await
+                .map_err(|e| AppError::Lambda(Box::new(e)))?;
+            Ok(())
+        },
+    )
+    .await
+}
+
+// 保留したスラッシュコマンドを実行し、結果で応答を書き換える
+// 非同期呼び出しの再試行で操作が重複しないように、失敗してもエラーは返さない
+pub async fn handle_deferred_interaction(owner: &str, deferred: &DeferredInteraction) {
+    let result = execute_process(owner, &deferred.command).await;
+    if let Err(e) = &result {
+        println!("interaction: {}", e);
+    }
+    let content = deferred_reply(&deferred.command, result);
+    if let Err(e) =
+        edit_original_interaction_response(&deferred.application_id, &deferred.token, &content)
+            .await
+    {
+        println!("interaction: failed to send the result: {}", e);
+    }
+}
+
+fn deferred_reply(command: &LambdaCommand, result: Result<LambdaResponse, AppError>) -> String {
+    match (command, result) {
+        (_, Err(e)) => format!("エラーが発生しました: {e}"),
+        // 別の実行が処理中で、何もしなかった
+        (LambdaCommand::Check { .. }, Ok(response)) if response.skipped => {
+            "別の処理が実行中のため、確認しませんでした。しばらくしてからもう一度実行してください"
+                .to_string()
+        }
+        (LambdaCommand::SkipTurn { .. }, Ok(response)) if response.skipped => {
+            "別の処理が実行中のため、スキップしませんでした。しばらくしてからもう一度実行してください"
+                .to_string()
+        }
+        (LambdaCommand::SkipTurn { .. }, Ok(_)) => "現在の番の人をスキップしました".to_string(),
+        (_, Ok(_)) => "プレイリストの更新を確認しました".to_string(),
+    }
+}
+
+async fn respond<D: DynamoDBClientTrait>(
+    public_key: &str,
+    request: &FunctionUrlRequest,
+    dynamodb_client: &D,
+    now: u64,
+    dispatch: impl AsyncFn(&DeferredInteraction) -> Result<(), AppError>,
+) -> Result<FunctionUrlResponse, AppError> {
+    let header = |key: &str| {
+        request
+            .headers
+            .get(key)
+            .map(|s| s.as_str())
+            .unwrap_or_default()
+    };
+    let body = request.body();
+    // 署名が正しくない場合は401を返す必要がある
+    if !verify_signature(
+        public_key,
+        header("x-signature-ed25519"),
+        header("x-signature-timestamp"),
+        &body,
+        now,
+    ) {
+        return Ok(FunctionUrlResponse::new(
+            401,
+            r#"{"error":"invalid request signature"}"#.to_string(),
+        ));
+    }
+    // 署名は正しいが解釈できないリクエストは400を返す
+    let interaction = if let Ok(interaction) = serde_json::from_slice::<Interaction>(&body) {
+        interaction
+    } else {
+        return Ok(FunctionUrlResponse::new(
+            400,
+            r#"{"error":"invalid request body"}"#.to_string(),
+        ));
+    };
+    let response = handle_interaction(dynamodb_client, &interaction, dispatch).await;
+    Ok(FunctionUrlResponse::new(
+        200,
+        serde_json::to_string(&response)?,
+    ))
+}
+
+// 時間のかかる操作はdispatchで非同期に実行し、応答を保留する
+pub async fn handle_interaction<D: DynamoDBClientTrait>(
+    dynamodb_client: &D,
+    interaction: &Interaction,
+    dispatch: impl AsyncFn(&DeferredInteraction) -> Result<(), AppError>,
+) -> InteractionResponse {
+    match interaction.interaction_type {
+        INTERACTION_TYPE_PING => InteractionResponse::pong(),
+        INTERACTION_TYPE_APPLICATION_COMMAND => {
+            match run_command(dynamodb_client, interaction, dispatch).await {
+                Ok(response) => response,
+                Err(e) => {
+                    println!("interaction: {}", e);
+                    InteractionResponse::message(format!("エラーが発生しました: {e}"), true)
+                }
+            }
+        }
+        interaction_type => InteractionResponse::message(
+            format!("unsupported interaction type: {interaction_type}"),
+            true,
+        ),
+    }
+}
+
+async fn run_command<D: DynamoDBClientTrait>(
+    dynamodb_client: &D,
+    interaction: &Interaction,
+    dispatch: impl AsyncFn(&DeferredInteraction) -> Result<(), AppError>,
+) -> Result<InteractionResponse, AppError> {
+    let data = if let Some(data) = &interaction.data {
+        data
+    } else {
+        return Err(AppError::Config("no command data".to_string()));
+    };
+    let playlist_option = data
+        .options
+        .iter()
+        .find(|o| o.name == "playlist")
+        .and_then(|o| o.value.as_str());
+    let playlist_configs = dynamodb_client.extract_playlist_configs().await?;
+    let playlist_config = select_playlist_config(
+        &playlist_configs,
+        playlist_option,
+        interaction.channel_id.as_deref(),
+    )?;
+    let playlist_id = playlist_config.playlist_id.clone();
+    match data.name.as_str() {
+        "next" => {
+            let state = extract_playlist_state(dynamodb_client, &playlist_id).await?;
+            let user_master = dynamodb_client.extract_user_master().await?;
+            let rotation =
+                user_master.filter_by_spotify_user_ids(&playlist_config.member_spotify_user_ids);
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let user = current_turn_user(&playlist_id, &rotation, &state, now)?;
+            Ok(InteractionResponse::message(
+                format!("次は{}さんの番です", user.name),
+                false,
+            ))
+        }
+        "history" => {
+            let state = extract_playlist_state(dynamodb_client, &playlist_id).await?;
+            let user_master = dynamodb_client.extract_user_master().await?;
+            let mut tracks = state.tracks.iter().collect::<Vec<_>>();
+            tracks.sort_by(|a, b| b.added_at.cmp(&a.added_at));
+            let lines = tracks
+                .iter()
+                .take(HISTORY_LENGTH)
+                .map(|track| {
+                    let added_by = user_master
+                        .get_user_by_spotify_id(&track.added_by)
+                        .map_or(track.added_by.as_str(), |u| u.name.as_str());
+                    // added_atはISO 8601形式のため、先頭の日付部分だけ表示する
+                    let date = track.added_at.get(..10).unwrap_or(&track.added_at);
+                    format!(
+                        "- {} {} https://open.spotify.com/track/{}",
+                        date, added_by, track.track_id
+                    )
+                })
+                .collect::<Vec<String>>();
+            if lines.is_empty() {
+                return Ok(InteractionResponse::message(
+                    "まだ曲が追加されていません".to_string(),
+                    false,
+                ));
+            }
+            Ok(InteractionResponse::message(
+                format!("最近追加された曲\n{}", lines.join("\n")),
+                false,
+            ))
+        }
+        // 通知はプロセッサーから通常どおり送られ、結果は保留した応答に書き込む
+        "check" | "skip" => {
+            // default_member_permissionsはサーバーの設定で変更できるため、ここでも確認する
+            if !interaction.has_permission(PERMISSION_MANAGE_GUILD) {
+                return Ok(InteractionResponse::message(
+                    "このコマンドを実行する権限がありません".to_string(),
+                    true,
+                ));
+            }
+            let command = if data.name == "check" {
+                LambdaCommand::Check {
+                    playlist_id: Some(playlist_id),
+                }
+            } else {
+                LambdaCommand::SkipTurn { playlist_id }
+            };
+            dispatch(&DeferredInteraction {
+                application_id: interaction.application_id.clone(),
+                token: interaction.token.clone(),
+                command,
+            })
+            .await?;
+            Ok(InteractionResponse::deferred())
+        }
+        name => Err(AppError::Config(format!("unknown command: {name}"))),
+    }
+}
+
+// 指定がなければ、コマンドを実行したチャンネルに通知しているプレイリストを対象にする
+fn select_playlist_config<'a>(
+    playlist_configs: &'a [PlaylistConfig],
+    playlist_id: Option<&str>,
+    channel_id: Option<&str>,
+) -> Result<&'a PlaylistConfig, AppError> {
+    if let Some(playlist_id) = playlist_id {
+        return if let Some(playlist_config) = playlist_configs
+            .iter()
+            .find(|c| c.playlist_id == playlist_id)
+        {
+            Ok(playlist_config)
+        } else {
+            Err(AppError::Config(format!("unknown playlist: {playlist_id}")))
+        };
+    }
+    let in_channel = playlist_configs
+        .iter()
+        .filter(|c| {
+            c.notification_targets.iter().any(|t| {
+                matches!(t, NotificationTarget::Discord { channel_id: id } if Some(id.as_str()) == channel_id)
+            })
+        })
+        .collect::<Vec<&PlaylistConfig>>();
+    match (in_channel.as_slice(), playlist_configs) {
+        ([playlist_config], _) => Ok(playlist_config),
+        ([], [playlist_config]) => Ok(playlist_config),
+        _ => Err(AppError::Config("playlistを指定してください".to_string())),
+    }
+}
+
+async fn extract_playlist_state<D: DynamoDBClientTrait>(
+    dynamodb_client: &D,
+    playlist_id: &str,
+) -> Result<PlaylistState, AppError> {
+    if let Some(state) = dynamodb_client.extract_playlist_state(playlist_id).await? {
+        Ok(state)
+    } else {
+        Err(DomainError::NoPlaylistState {
+            playlist_id: playlist_id.to_string(),
+        }
+        .into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use ed25519_dalek::{Signer, SigningKey};
+
+    use crate::{dynamodb::MockDynamoDBClientTrait, user::UserMaster};
+
+    use super::*;
+
+    fn new_signed_request(signing_key: &SigningKey, body: &str) -> FunctionUrlRequest {
+        let timestamp = "1700000000";
+        let signature = signing_key.sign(format!("{timestamp}{body}").as_bytes());
+        FunctionUrlRequest {
+            headers: HashMap::from([
+                (
+                    "x-signature-ed25519".to_string(),
+                    hex::encode(signature.to_bytes()),
+                ),
+                ("x-signature-timestamp".to_string(), timestamp.to_string()),
+            ]),
+            body: Some(body.to_string()),
+            is_base64_encoded: false,
+        }
+    }
+
+    fn new_mock_dynamodb_client() -> MockDynamoDBClientTrait {
+        let mut mock_dynamodb_client = MockDynamoDBClientTrait::new();
+        mock_dynamodb_client
+            .expect_extract_playlist_configs()
+            .returning(|| Ok(vec![PlaylistConfig::new_test_data()]));
+        mock_dynamodb_client
+            .expect_extract_user_master()
+            .returning(|| Ok(UserMaster::new_test_data()));
+        mock_dynamodb_client
+            .expect_extract_playlist_state()
+            .returning(|_| {
+                Ok(Some(PlaylistState::new_test_data(
+                    "snapshot_2",
+                    &["track_1", "track_2"],
+                    2,
+                )))
+            });
+        mock_dynamodb_client
+    }
+
+    fn new_command(name: &str) -> Interaction {
+        Interaction {
+            interaction_type: INTERACTION_TYPE_APPLICATION_COMMAND,
+            channel_id: Some("test_channel".to_string()),
+            data: Some(InteractionData {
+                name: name.to_string(),
+                options: vec![],
+            }),
+            member: Some(InteractionMember {
+                permissions: PERMISSION_MANAGE_GUILD.to_string(),
+            }),
+            application_id: "test_application".to_string(),
+            token: "test_token".to_string(),
+        }
+    }
+
+    async fn never_dispatch(_: &DeferredInteraction) -> Result<(), AppError> {
+        panic!("must not dispatch")
+    }
+
+    #[tokio::test]
+    async fn test_respond_verifies_signature() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let public_key = hex::encode(signing_key.verifying_key().to_bytes());
+        let mock_dynamodb_client = MockDynamoDBClientTrait::new();
+
+        let request = new_signed_request(&signing_key, r#"{"type":1}"#);
+        let response = respond(
+            &public_key,
+            &request,
+            &mock_dynamodb_client,
+            1_700_000_000,
+            never_dispatch,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.body, r#"{"type":1}"#);
+
+        // 署名後に改ざんされたリクエスト
+        let mut request = new_signed_request(&signing_key, r#"{"type":1}"#);
+        request.body = Some(r#"{"type":2}"#.to_string());
+        let response = respond(
+            &public_key,
+            &request,
+            &mock_dynamodb_client,
+            1_700_000_000,
+            never_dispatch,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status_code, 401);
+
+        // 署名は正しいが古いリクエストの再送
+        let request = new_signed_request(&signing_key, r#"{"type":1}"#);
+        let response = respond(
+            &public_key,
+            &request,
+            &mock_dynamodb_client,
+            1_700_000_000 + MAX_SIGNATURE_AGE_SECONDS + 1,
+            never_dispatch,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status_code, 401);
+
+        // 署名は正しいが解釈できないボディ
+        let request = new_signed_request(&signing_key, r#"{"type":"ping"}"#);
+        let response = respond(
+            &public_key,
+            &request,
+            &mock_dynamodb_client,
+            1_700_000_000,
+            never_dispatch,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status_code, 400);
+    }
+
+    #[tokio::test]
+    async fn test_next_and_history() {
+        let mock_dynamodb_client = new_mock_dynamodb_client();
+        // 最後にtrack_2を追加したUser 2の次のUser 1の番
+        assert_eq!(
+            handle_interaction(&mock_dynamodb_client, &new_command("next"), never_dispatch).await,
+            InteractionResponse::message("次はUser 1さんの番です".to_string(), false)
+        );
+        assert_eq!(
+            handle_interaction(
+                &mock_dynamodb_client,
+                &new_command("history"),
+                never_dispatch
+            )
+            .await,
+            InteractionResponse::message(
+                [
+                    "最近追加された曲",
+                    "- 2023-01-02 User 2 https://open.spotify.com/track/track_2",
+                    "- 2023-01-01 User 1 https://open.spotify.com/track/track_1",
+                ]
+                .join("\n"),
+                false
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_and_skip_are_deferred() {
+        let mock_dynamodb_client = new_mock_dynamodb_client();
+        let dispatched = Mutex::new(Vec::new());
+        let dispatch = async |deferred: &DeferredInteraction| {
+            dispatched.lock().unwrap().push(deferred.clone());
+            Ok(())
+        };
+        assert_eq!(
+            handle_interaction(&mock_dynamodb_client, &new_command("check"), &dispatch).await,
+            InteractionResponse::deferred()
+        );
+        assert_eq!(
+            handle_interaction(&mock_dynamodb_client, &new_command("skip"), &dispatch).await,
+            InteractionResponse::deferred()
+        );
+        assert_eq!(
+            *dispatched.lock().unwrap(),
+            vec![
+                DeferredInteraction {
+                    application_id: "test_application".to_string(),
+                    token: "test_token".to_string(),
+                    command: LambdaCommand::Check {
+                        playlist_id: Some("test_playlist".to_string())
+                    },
+                },
+                DeferredInteraction {
+                    application_id: "test_application".to_string(),
+                    token: "test_token".to_string(),
+                    command: LambdaCommand::SkipTurn {
+                        playlist_id: "test_playlist".to_string()
+                    },
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_and_skip_require_permission() {
+        let mock_dynamodb_client = new_mock_dynamodb_client();
+        let denied = InteractionResponse::message(
+            "このコマンドを実行する権限がありません".to_string(),
+            true,
+        );
+        for name in ["check", "skip"] {
+            // 管理権限のないメンバー
+            let mut interaction = new_command(name);
+            interaction.member = Some(InteractionMember {
+                permissions: "2048".to_string(),
+            });
+            assert_eq!(
+                handle_interaction(&mock_dynamodb_client, &interaction, never_dispatch).await,
+                denied
+            );
+            // DMなどサーバー外での実行
+            interaction.member = None;
+            assert_eq!(
+                handle_interaction(&mock_dynamodb_client, &interaction, never_dispatch).await,
+                denied
+            );
+        }
+        // 管理者はすべての権限を持つ
+        let dispatched = Mutex::new(0);
+        let dispatch = async |_: &DeferredInteraction| {
+            *dispatched.lock().unwrap() += 1;
+            Ok(())
+        };
+        let mut interaction = new_command("skip");
+        interaction.member = Some(InteractionMember {
+            permissions: PERMISSION_ADMINISTRATOR.to_string(),
+        });
+        assert_eq!(
+            handle_interaction(&mock_dynamodb_client, &interaction, &dispatch).await,
+            InteractionResponse::deferred()
+        );
+        assert_eq!(*dispatched.lock().unwrap(), 1);
+        // 権限を求めるのは/checkと/skipだけ
+        assert_eq!(
+            command_definitions()
+                .as_array()
+                .unwrap()
+                .iter()
+                .filter_map(|c| c.get("default_member_permissions"))
+                .collect::<Vec<&Value>>(),
+            vec!["32", "32"]
+        );
+    }
+
+    #[test]
+    fn test_deferred_interaction_payload() {
+        let deferred = DeferredInteraction {
+            application_id: "test_application".to_string(),
+            token: "test_token".to_string(),
+            command: LambdaCommand::SkipTurn {
+                playlist_id: "test_playlist".to_string(),
+            },
+        };
+        let payload: Value = serde_json::from_slice(&deferred.to_payload().unwrap()).unwrap();
+        assert_eq!(DeferredInteraction::from_payload(&payload), Some(deferred));
+        // 運用者が指定した操作やスケジュール実行とは区別する
+        assert_eq!(
+            DeferredInteraction::from_payload(&json!({"action": "skip_turn"})),
+            None
+        );
+        assert_eq!(DeferredInteraction::from_payload(&json!({})), None);
+    }
+
+    #[test]
+    fn test_deferred_reply() {
+        let check = LambdaCommand::Check { playlist_id: None };
+        let skip = LambdaCommand::SkipTurn {
+            playlist_id: "test_playlist".to_string(),
+        };
+        assert_eq!(
+            deferred_reply(&check, Ok(LambdaResponse::default())),
+            "プレイリストの更新を確認しました"
+        );
+        assert_eq!(
+            deferred_reply(&skip, Ok(LambdaResponse::default())),
+            "現在の番の人をスキップしました"
+        );
+        // ロックを取得できずに何もしなかった場合は、実行されなかったことを伝える
+        let skipped = || LambdaResponse {
+            skipped: true,
+            ..LambdaResponse::default()
+        };
+        assert!(deferred_reply(&check, Ok(skipped())).contains("確認しませんでした"));
+        assert!(deferred_reply(&skip, Ok(skipped())).contains("スキップしませんでした"));
+        assert_eq!(
+            deferred_reply(&skip, Err(AppError::Config("x".to_string()))),
+            "エラーが発生しました: config error: x"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unknown_playlist_is_ephemeral() {
+        let mock_dynamodb_client = new_mock_dynamodb_client();
+        let mut interaction = new_command("next");
+        interaction.data.as_mut().unwrap().options = vec![InteractionOption {
+            name: "playlist".to_string(),
+            value: json!("unknown_playlist"),
+        }];
+        let response =
+            handle_interaction(&mock_dynamodb_client, &interaction, never_dispatch).await;
+        assert_eq!(response.data.unwrap().flags, MESSAGE_FLAG_EPHEMERAL);
+    }
+}
@@ -0,0 +1,19 @@
+// LambdaとCLIから使うためのクレートで、外部に公開するものではない
+#![allow(async_fn_in_trait)]
+
+pub mod command;
+pub mod diff;
+pub mod discord;
+pub mod dynamodb;
+pub mod email;
+pub mod error;
+pub mod interaction;
+pub mod notifier;
+pub mod oauth;
+pub mod playlist;
+pub mod processor;
+pub mod slack;
+pub mod spotify;
+pub mod template;
+pub mod user;
+pub mod webhook;
@@ -0,0 +1,684 @@
+use std::{env, fmt};
+
+use mockall::automock;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    diff::PlaylistDiff,
+    discord::{DiscordClient, DiscordClientTrait, DiscordCreateMessageRequest},
+    email::EmailClient,
+    error::AppError,
+    playlist::TrackFingerprint,
+    slack::SlackClient,
+    spotify::{SpotifyPlaylistItem, SpotifyPlaylistResponse},
+    template::MessageTemplates,
+    user::{User, UserMaster},
+    webhook::WebhookClient,
+};
+
+// プレイリストごとに設定する通知先
+// Slack・WebhookのURLは秘密情報なので、ログやエラーにはDisplayの伏せ字表記を使う
+#[derive(Clone, PartialEq)]
+pub enum NotificationTarget {
+    Discord { channel_id: String },
+    Slack { webhook_url: String },
+    Webhook { url: String },
+    Email { to: Vec<String> },
+}
+
+impl NotificationTarget {
+    // outboxに通知済みとして記録するためのキー
+    // URLはそのまま保存せずハッシュにする
+    pub fn key(&self) -> String {
+        match self {
+            Self::Discord { channel_id } => format!("discord:{channel_id}"),
+            Self::Slack { webhook_url } => format!("slack:{}", hash_secret(webhook_url)),
+            Self::Webhook { url } => format!("webhook:{}", hash_secret(url)),
+            Self::Email { to } => format!("email:{}", to.join(",")),
+        }
+    }
+
+    // メールは通知ごとに送らず、実行の最後にダイジェストとしてまとめて送る
+    pub fn is_digest(&self) -> bool {
+        matches!(self, Self::Email { .. })
+    }
+
+    // 通知先に送るメッセージの数。Discordは文字数の制限で複数のメッセージに分けて送る
+    pub fn page_count(&self, notification: &PlaylistNotification) -> usize {
+        match self {
+            Self::Discord { .. } => {
+                DiscordCreateMessageRequest::from_notification(notification).len()
+            }
+            _ => 1,
+        }
+    }
+}
+
+fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+impl fmt::Display for NotificationTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Discord { channel_id } => write!(f, "discord:{channel_id}"),
+            // 同じ種類の通知先を見分けられるようにハッシュの先頭だけ出す
+            Self::Slack { webhook_url } => write!(f, "slack:{}", &hash_secret(webhook_url)[..8]),
+            Self::Webhook { url } => write!(f, "webhook:{}", &hash_secret(url)[..8]),
+            Self::Email { to } => write!(f, "email:{}", to.join(",")),
+        }
+    }
+}
+
+// {:?}で出力されてもURLが漏れないようにDisplayと同じ表記にする
+impl fmt::Debug for NotificationTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NotifiedUser {
+    pub spotify_user_id: String,
+    // ユーザーテーブルに存在しない場合はNone
+    pub name: Option<String>,
+    pub discord_user_id: Option<String>,
+}
+
+impl NotifiedUser {
+    fn new(user_master: &UserMaster, spotify_user_id: &str) -> Self {
+        match user_master.get_user_by_spotify_id(spotify_user_id) {
+            Some(user) => Self::from_user(user),
+            None => Self {
+                spotify_user_id: spotify_user_id.to_string(),
+                name: None,
+                discord_user_id: None,
+            },
+        }
+    }
+
+    fn from_user(user: &User) -> Self {
+        Self {
+            spotify_user_id: user.spotify_user_id.clone(),
+            name: Some(user.name.clone()),
+            discord_user_id: Some(user.discord_user_id.clone()),
+        }
+    }
+
+    pub fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.spotify_user_id)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NotifiedTrack {
+    pub track_id: String,
+    pub name: String,
+    pub url: String,
+    pub artists: Vec<String>,
+    pub album_name: String,
+    pub image_url: Option<String>,
+    pub duration_ms: u64,
+    pub preview_url: Option<String>,
+    pub added_at: String,
+    pub added_by: NotifiedUser,
+}
+
+impl NotifiedTrack {
+    fn new(user_master: &UserMaster, item: &SpotifyPlaylistItem) -> Self {
+        let track = item.track.as_ref();
+        Self {
+            track_id: item.track_id(),
+            name: track.map(|t| t.name.clone()).unwrap_or_default(),
+            url: track.map(|t| t.url().to_string()).unwrap_or_default(),
+            artists: track
+                .map(|t| t.artists.iter().map(|a| a.name.clone()).collect())
+                .unwrap_or_default(),
+            album_name: track
+                .and_then(|t| t.album.as_ref())
+                .map(|a| a.name.clone())
+                .unwrap_or_default(),
+            image_url: track
+                .and_then(|t| t.thumbnail_url())
+                .map(|url| url.to_string()),
+            duration_ms: track.map_or(0, |t| t.duration_ms),
+            preview_url: track.and_then(|t| t.preview_url.clone()),
+            added_at: item.added_at.clone(),
+            added_by: NotifiedUser::new(user_master, &item.added_by.id),
+        }
+    }
+}
+
+// 削除・移動された曲は前回保存した情報しか持っていない
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NotifiedTrackReference {
+    pub track_id: String,
+    pub url: String,
+    pub added_at: String,
+    pub added_by: NotifiedUser,
+}
+
+impl NotifiedTrackReference {
+    fn new(user_master: &UserMaster, fingerprint: &TrackFingerprint) -> Self {
+        Self {
+            track_id: fingerprint.track_id.clone(),
+            url: fingerprint.track_url(),
+            added_at: fingerprint.added_at.clone(),
+            added_by: NotifiedUser::new(user_master, &fingerprint.added_by),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NotifiedMovedTrack {
+    pub track: NotifiedTrackReference,
+    // 1始まりの位置
+    pub from_position: usize,
+    pub to_position: usize,
+}
+
+// 番の人へのリマインド。リマインドされる人はnext_user
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TurnReminder {
+    // この番で何回目のリマインドか(1始まり)
+    pub number: u64,
+    // 番が回ってきてからの経過日数
+    pub elapsed_days: u64,
+    pub is_last: bool,
+}
+
+impl TurnReminder {
+    pub fn template<'a>(&self, templates: &'a MessageTemplates) -> &'a str {
+        if self.is_last {
+            &templates.reminder_last
+        } else if self.number > 1 {
+            &templates.reminder_repeated
+        } else {
+            &templates.reminder
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlaylistNotification {
+    pub playlist_id: String,
+    pub playlist_name: String,
+    pub playlist_url: String,
+    pub added_tracks: Vec<NotifiedTrack>,
+    pub removed_tracks: Vec<NotifiedTrackReference>,
+    pub moved_tracks: Vec<NotifiedMovedTrack>,
+    pub next_user: Option<NotifiedUser>,
+    // 番をスキップされた人
+    #[serde(default)]
+    pub skipped_user: Option<NotifiedUser>,
+    #[serde(default)]
+    pub reminder: Option<TurnReminder>,
+    // 再送時は送信時点の設定から作り直す
+    #[serde(skip)]
+    pub templates: MessageTemplates,
+}
+
+impl PlaylistNotification {
+    pub fn new(
+        playlist_id: &str,
+        spotify_playlist: &SpotifyPlaylistResponse,
+        diff: &PlaylistDiff<'_>,
+        next_user: Option<&User>,
+        user_master: &UserMaster,
+        templates: MessageTemplates,
+    ) -> Self {
+        Self {
+            playlist_id: playlist_id.to_string(),
+            playlist_name: spotify_playlist.name.clone(),
+            playlist_url: spotify_playlist.external_urls.spotify.clone(),
+            added_tracks: diff
+                .added
+                .iter()
+                .map(|item| NotifiedTrack::new(user_master, item))
+                .collect(),
+            removed_tracks: diff
+                .removed
+                .iter()
+                .map(|fingerprint| NotifiedTrackReference::new(user_master, fingerprint))
+                .collect(),
+            moved_tracks: diff
+                .moved
+                .iter()
+                .map(|moved_track| NotifiedMovedTrack {
+                    track: NotifiedTrackReference::new(user_master, &moved_track.fingerprint),
+                    from_position: moved_track.from_position + 1,
+                    to_position: moved_track.to_position + 1,
+                })
+                .collect(),
+            next_user: next_user.map(NotifiedUser::from_user),
+            skipped_user: None,
+            reminder: None,
+            templates,
+        }
+    }
+
+    pub fn turn_skipped(
+        playlist_id: &str,
+        spotify_playlist: &SpotifyPlaylistResponse,
+        skipped_user: &User,
+        next_user: &User,
+        templates: MessageTemplates,
+    ) -> Self {
+        Self {
+            playlist_id: playlist_id.to_string(),
+            playlist_name: spotify_playlist.name.clone(),
+            playlist_url: spotify_playlist.external_urls.spotify.clone(),
+            added_tracks: vec![],
+            removed_tracks: vec![],
+            moved_tracks: vec![],
+            next_user: Some(NotifiedUser::from_user(next_user)),
+            skipped_user: Some(NotifiedUser::from_user(skipped_user)),
+            reminder: None,
+            templates,
+        }
+    }
+
+    pub fn turn_reminder(
+        playlist_id: &str,
+        spotify_playlist: &SpotifyPlaylistResponse,
+        user: &User,
+        reminder: TurnReminder,
+        templates: MessageTemplates,
+    ) -> Self {
+        Self {
+            playlist_id: playlist_id.to_string(),
+            playlist_name: spotify_playlist.name.clone(),
+            playlist_url: spotify_playlist.external_urls.spotify.clone(),
+            added_tracks: vec![],
+            removed_tracks: vec![],
+            moved_tracks: vec![],
+            next_user: Some(NotifiedUser::from_user(user)),
+            skipped_user: None,
+            reminder: Some(reminder),
+            templates,
+        }
+    }
+}
+
+#[automock]
+pub trait NotifierTrait {
+    // page番目のメッセージを送る。ページ数はNotificationTarget::page_countで求める
+    async fn notify(
+        &self,
+        target: &NotificationTarget,
+        notification: &PlaylistNotification,
+        page: usize,
+    ) -> Result<(), AppError>;
+    // 1回の実行で発生した通知をまとめて送る。ダイジェストに対応しているのはメールだけ
+    async fn notify_digest(
+        &self,
+        target: &NotificationTarget,
+        notifications: &[PlaylistNotification],
+    ) -> Result<(), AppError>;
+    // 運用者向けのDiscordチャンネルに通知する
+    async fn alert_admin(&self, message: &str) -> Result<(), AppError>;
+}
+
+pub struct Notifier<C: DiscordClientTrait = DiscordClient> {
+    // 初期化に失敗した通知先はそのエラーを保持し、使われたときに通知の失敗として返す
+    discord_client: Result<C, AppError>,
+    admin_channel_id: Option<String>,
+    email_client: Result<EmailClient, AppError>,
+    slack_client: SlackClient,
+    webhook_client: WebhookClient,
+}
+
+impl Notifier {
+    pub fn init() -> Result<Self, AppError> {
+        Ok(Self::new(
+            DiscordClient::init(),
+            env::var("DISCORD_ADMIN_CHANNEL_ID").ok(),
+            EmailClient::init(),
+        ))
+    }
+}
+
+impl<C: DiscordClientTrait> Notifier<C> {
+    pub fn new(
+        discord_client: Result<C, AppError>,
+        admin_channel_id: Option<String>,
+        email_client: Result<EmailClient, AppError>,
+    ) -> Self {
+        Self {
+            discord_client,
+            admin_channel_id,
+            email_client,
+            slack_client: SlackClient::new(),
+            webhook_client: WebhookClient::new(),
+        }
+    }
+
+    fn discord_client(&self) -> Result<&C, AppError> {
+        self.discord_client
+            .as_ref()
+            .map_err(|e| init_error("discord", e))
+    }
+
+    fn email_client(&self) -> Result<&EmailClient, AppError> {
+        self.email_client
+            .as_ref()
+            .map_err(|e| init_error("email", e))
+    }
+}
+
+// 初期化のエラーは使われるたびに返すため、同じ内容の設定エラーを作る
+fn init_error(target: &str, error: &AppError) -> AppError {
+    match error {
+        AppError::Config(message) => AppError::Config(format!("{target}: {message}")),
+        error => AppError::Config(format!("{target}: {error}")),
+    }
+}
+
+impl<C: DiscordClientTrait> NotifierTrait for Notifier<C> {
+    async fn notify(
+        &self,
+        target: &NotificationTarget,
+        notification: &PlaylistNotification,
+        page: usize,
+    ) -> Result<(), AppError> {
+        match target {
+            NotificationTarget::Discord { channel_id } => {
+                let discord_client = self.discord_client()?;
+                if let Some(request) = DiscordCreateMessageRequest::from_notification(notification)
+                    .into_iter()
+                    .nth(page)
+                {
+                    discord_client.create_message(channel_id, &request).await?;
+                }
+            }
+            NotificationTarget::Slack { webhook_url } => {
+                self.slack_client.post(webhook_url, notification).await?;
+            }
+            NotificationTarget::Webhook { url } => {
+                self.webhook_client.post(url, notification).await?;
+            }
+            NotificationTarget::Email { to } => {
+                self.email_client()?
+                    .send(to, std::slice::from_ref(notification))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn notify_digest(
+        &self,
+        target: &NotificationTarget,
+        notifications: &[PlaylistNotification],
+    ) -> Result<(), AppError> {
+        if let NotificationTarget::Email { to } = target {
+            self.email_client()?.send(to, notifications).await
+        } else {
+            Err(AppError::Config(format!(
+                "{target} does not support digests"
+            )))
+        }
+    }
+
+    async fn alert_admin(&self, message: &str) -> Result<(), AppError> {
+        let discord_client = self.discord_client()?;
+        let channel_id = if let Some(channel_id) = &self.admin_channel_id {
+            channel_id
+        } else {
+            return Err(AppError::Config(
+                "DISCORD_ADMIN_CHANNEL_ID is not set".to_string(),
+            ));
+        };
+        discord_client
+            .create_message(
+                channel_id,
+                &DiscordCreateMessageRequest {
+                    content: message.to_string(),
+                    embeds: vec![],
+                },
+            )
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::predicate::eq;
+
+    use crate::discord::MockDiscordClientTrait;
+
+    use super::*;
+
+    impl PlaylistNotification {
+        pub(crate) fn new_test_data() -> Self {
+            let added_by = NotifiedUser {
+                spotify_user_id: "spotify_user_1".to_string(),
+                name: Some("User 1".to_string()),
+                discord_user_id: Some("discord_user_1".to_string()),
+            };
+            let unknown_user = NotifiedUser {
+                spotify_user_id: "unknown_user".to_string(),
+                name: None,
+                discord_user_id: None,
+            };
+            PlaylistNotification {
+                playlist_id: "test_playlist".to_string(),
+                playlist_name: "Test Playlist".to_string(),
+                playlist_url: "https://open.spotify.com/playlist/test".to_string(),
+                added_tracks: vec![NotifiedTrack {
+                    track_id: "track_1".to_string(),
+                    name: "Track 1".to_string(),
+                    url: "https://open.spotify.com/track/track_1".to_string(),
+                    artists: vec!["Artist".to_string()],
+                    album_name: "Album".to_string(),
+                    image_url: Some("https://i.scdn.co/image/300".to_string()),
+                    duration_ms: 215_000,
+                    preview_url: None,
+                    added_at: "2023-01-01T00:00:00Z".to_string(),
+                    added_by: added_by.clone(),
+                }],
+                removed_tracks: vec![NotifiedTrackReference {
+                    track_id: "track_0".to_string(),
+                    url: "https://open.spotify.com/track/track_0".to_string(),
+                    added_at: "2022-12-31T00:00:00Z".to_string(),
+                    added_by: added_by.clone(),
+                }],
+                moved_tracks: vec![NotifiedMovedTrack {
+                    track: NotifiedTrackReference {
+                        track_id: "track_2".to_string(),
+                        url: "https://open.spotify.com/track/track_2".to_string(),
+                        added_at: "2023-01-02T00:00:00Z".to_string(),
+                        added_by: unknown_user,
+                    },
+                    from_position: 3,
+                    to_position: 1,
+                }],
+                next_user: Some(NotifiedUser {
+                    spotify_user_id: "spotify_user_2".to_string(),
+                    name: Some("User 2".to_string()),
+                    discord_user_id: Some("discord_user_2".to_string()),
+                }),
+                skipped_user: None,
+                reminder: None,
+                templates: MessageTemplates::japanese(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_new_playlist_notification() {
+        let user_master = UserMaster {
+            users: vec![User {
+                name: "User 1".to_string(),
+                spotify_user_id: "spotify_user_1".to_string(),
+                discord_user_id: "discord_user_1".to_string(),
+                order: 1,
+                paused: false,
+                away: None,
+            }],
+        };
+        let previous = vec![TrackFingerprint {
+            track_id: "track_0".to_string(),
+            added_at: "2022-12-31T00:00:00Z".to_string(),
+            added_by: "unknown_user".to_string(),
+        }];
+        let current = vec![SpotifyPlaylistItem::new_test_data(
+            "track_1",
+            "spotify_user_1",
+            "2023-01-01T00:00:00Z",
+        )];
+        let diff = PlaylistDiff::compute(&previous, &current);
+        let spotify_playlist = SpotifyPlaylistResponse {
+            name: "Test Playlist".to_string(),
+            snapshot_id: "snapshot".to_string(),
+            external_urls: crate::spotify::SpotifyExternalUrls {
+                spotify: "https://open.spotify.com/playlist/test".to_string(),
+            },
+        };
+        let notification = PlaylistNotification::new(
+            "test_playlist",
+            &spotify_playlist,
+            &diff,
+            user_master.users.first(),
+            &user_master,
+            MessageTemplates::japanese(),
+        );
+        assert_eq!(notification.added_tracks.len(), 1);
+        assert_eq!(
+            notification.added_tracks[0].added_by.display_name(),
+            "User 1"
+        );
+        assert_eq!(notification.removed_tracks.len(), 1);
+        assert_eq!(
+            notification.removed_tracks[0].added_by.display_name(),
+            "unknown_user"
+        );
+        assert_eq!(
+            notification.next_user.unwrap().discord_user_id.as_deref(),
+            Some("discord_user_1")
+        );
+    }
+
+    #[test]
+    fn test_notification_target_redaction() {
+        let url = "https://hooks.slack.com/services/T000/B000/secret";
+        let target = NotificationTarget::Slack {
+            webhook_url: url.to_string(),
+        };
+        assert!(!target.key().contains(url));
+        assert!(!target.to_string().contains("secret"));
+        assert!(!format!("{:?}", target).contains("secret"));
+        // 同じURLなら同じキーになる
+        assert_eq!(
+            target.key(),
+            NotificationTarget::Slack {
+                webhook_url: url.to_string()
+            }
+            .key()
+        );
+
+        let target = NotificationTarget::Webhook {
+            url: "https://example.com/hook?token=secret".to_string(),
+        };
+        assert!(!target.key().contains("secret"));
+        assert!(!target.to_string().contains("secret"));
+
+        let target = NotificationTarget::Discord {
+            channel_id: "channel_1".to_string(),
+        };
+        assert_eq!(target.key(), "discord:channel_1");
+        assert_eq!(target.to_string(), "discord:channel_1");
+    }
+
+    #[tokio::test]
+    async fn test_notify_discord() {
+        let notification = PlaylistNotification::new_test_data();
+        let requests = DiscordCreateMessageRequest::from_notification(&notification);
+        let mut mock_discord_client = MockDiscordClientTrait::new();
+        // 追加された曲と、削除・移動された曲の2通に分かれ、ページごとに1通ずつ送る
+        assert_eq!(requests.len(), 2);
+        let mut sequence = mockall::Sequence::new();
+        for request in requests {
+            mock_discord_client
+                .expect_create_message()
+                .with(eq("test_channel"), eq(request))
+                .times(1)
+                .in_sequence(&mut sequence)
+                .returning(|_, _| Ok(()));
+        }
+        let notifier = Notifier::new(Ok(mock_discord_client), None, smtp_not_set());
+        let target = NotificationTarget::Discord {
+            channel_id: "test_channel".to_string(),
+        };
+        assert_eq!(target.page_count(&notification), 2);
+        for page in 0..target.page_count(&notification) {
+            notifier.notify(&target, &notification, page).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_alert_admin() {
+        let mut mock_discord_client = MockDiscordClientTrait::new();
+        mock_discord_client
+            .expect_create_message()
+            .withf(|channel_id, request| {
+                channel_id == "admin_channel" && request.content == "alert"
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let notifier = Notifier::new(
+            Ok(mock_discord_client),
+            Some("admin_channel".to_string()),
+            smtp_not_set(),
+        );
+        notifier.alert_admin("alert").await.unwrap();
+
+        // 管理者チャンネルが設定されていない場合は設定エラー
+        let notifier = Notifier::new(Ok(MockDiscordClientTrait::new()), None, smtp_not_set());
+        assert!(matches!(
+            notifier.alert_admin("alert").await,
+            Err(AppError::Config(_))
+        ));
+    }
+
+    fn smtp_not_set() -> Result<EmailClient, AppError> {
+        Err(AppError::Config("SMTP_HOST is not set".to_string()))
+    }
+
+    #[tokio::test]
+    async fn test_notify_misconfigured_target() {
+        // 初期化に失敗した通知先は、その理由とともに通知の失敗になる
+        let notifier = Notifier::<MockDiscordClientTrait>::new(
+            Err(AppError::Config("DISCORD_BOT_TOKEN is not set".to_string())),
+            None,
+            Err(AppError::Config("invalid SMTP_FROM: bad".to_string())),
+        );
+        let notification = PlaylistNotification::new_test_data();
+        let err = notifier
+            .notify(
+                &NotificationTarget::Email {
+                    to: vec!["member@example.com".to_string()],
+                },
+                &notification,
+                0,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "config error: email: invalid SMTP_FROM: bad"
+        );
+        let err = notifier
+            .notify(
+                &NotificationTarget::Discord {
+                    channel_id: "test_channel".to_string(),
+                },
+                &notification,
+                0,
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("DISCORD_BOT_TOKEN is not set"));
+    }
+}
@@ -0,0 +1,184 @@
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use rand::{Rng, distr::Alphanumeric};
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+use crate::{error::AppError, spotify::SpotifyError};
+
+// プレイリストの取得に必要な権限
+const SPOTIFY_SCOPES: &str = "playlist-read-private playlist-read-collaborative";
+
+// 認可コードフローのPKCE(RFC 7636)のcode_verifierとcode_challenge
+#[derive(Debug)]
+pub struct PkceChallenge {
+    pub verifier: String,
+    pub challenge: String,
+}
+
+impl PkceChallenge {
+    pub fn new() -> Self {
+        Self::from_verifier(&random_string(64))
+    }
+
+    fn from_verifier(verifier: &str) -> Self {
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+        Self {
+            verifier: verifier.to_string(),
+            challenge,
+        }
+    }
+}
+
+impl Default for PkceChallenge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// CSRF対策のため、コールバックで同じ値が返ってくることを確認する
+pub fn random_state() -> String {
+    random_string(32)
+}
+
+fn random_string(length: usize) -> String {
+    rand::rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+pub fn authorize_url(
+    client_id: &str,
+    redirect_uri: &str,
+    state: &str,
+    pkce: &PkceChallenge,
+) -> Result<String, AppError> {
+    let url = Url::parse_with_params(
+        "https://accounts.spotify.com/authorize",
+        &[
+            ("response_type", "code"),
+            ("client_id", client_id),
+            ("redirect_uri", redirect_uri),
+            ("scope", SPOTIFY_SCOPES),
+            ("state", state),
+            ("code_challenge_method", "S256"),
+            ("code_challenge", &pkce.challenge),
+        ],
+    )
+    .map_err(|e| AppError::Config(format!("invalid authorize url: {e}")))?;
+    Ok(url.to_string())
+}
+
+// コールバックのリクエストパス(/callback?code=...&state=...)から認可コードを取り出す。
+// 関係のないリクエスト(faviconなど)の場合はNone
+pub fn parse_callback(path: &str, expected_state: &str) -> Result<Option<String>, AppError> {
+    let url = Url::parse(&format!("http://localhost{path}"))
+        .map_err(|e| AppError::Config(format!("invalid callback: {e}")))?;
+    if url.path() != "/callback" {
+        return Ok(None);
+    }
+    let query = url.query_pairs().collect::<Vec<_>>();
+    let get = |key: &str| {
+        query
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.to_string())
+    };
+    if get("state").as_deref() != Some(expected_state) {
+        return Err(AppError::Config("state mismatch in callback".to_string()));
+    }
+    // 利用者が許可しなかった場合など
+    if let Some(error) = get("error") {
+        return Err(SpotifyError::Unauthorized { message: error }.into());
+    }
+    if let Some(code) = get("code") {
+        Ok(Some(code))
+    } else {
+        Err(AppError::Config("no code in callback".to_string()))
+    }
+}
+
+// 認可コードを受け取るまでコールバックのリクエストを待ち受ける
+pub async fn wait_for_callback(
+    listener: &TcpListener,
+    expected_state: &str,
+) -> Result<String, AppError> {
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let mut buffer = vec![0; 8192];
+        let length = stream.read(&mut buffer).await?;
+        let request = String::from_utf8_lossy(&buffer[..length]);
+        // リクエストラインは"GET /callback?code=... HTTP/1.1"の形式
+        let path = request.split_whitespace().nth(1).unwrap_or("/");
+        let result = parse_callback(path, expected_state);
+        let (status, body) = match &result {
+            Ok(Some(_)) => ("200 OK", "Authorization completed. You can close this tab."),
+            Ok(None) => ("404 Not Found", "Not Found"),
+            Err(_) => ("400 Bad Request", "Authorization failed."),
+        };
+        let response = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(response.as_bytes()).await?;
+        if let Some(code) = result? {
+            return Ok(code);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpStream;
+
+    use super::*;
+
+    #[test]
+    fn test_pkce_challenge() {
+        // BASE64URL(SHA256(code_verifier))をパディングなしで表したもの
+        let pkce = PkceChallenge::from_verifier("test_code_verifier");
+        assert_eq!(
+            pkce.challenge,
+            "Qq1fGD0HhxwbmeMrqaebgn1qhvKeguQPXqLdpmixaM4"
+        );
+        assert_eq!(PkceChallenge::new().verifier.len(), 64);
+    }
+
+    #[test]
+    fn test_parse_callback() {
+        assert_eq!(
+            parse_callback("/callback?code=abc&state=xyz", "xyz").unwrap(),
+            Some("abc".to_string())
+        );
+        assert_eq!(parse_callback("/favicon.ico", "xyz").unwrap(), None);
+        assert!(parse_callback("/callback?code=abc&state=other", "xyz").is_err());
+        assert!(matches!(
+            parse_callback("/callback?error=access_denied&state=xyz", "xyz"),
+            Err(AppError::Spotify(SpotifyError::Unauthorized { .. }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_callback() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            for path in ["/favicon.ico", "/callback?code=abc&state=xyz"] {
+                let mut stream = TcpStream::connect(address).await.unwrap();
+                stream
+                    .write_all(format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes())
+                    .await
+                    .unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).await.unwrap();
+            }
+        });
+        assert_eq!(wait_for_callback(&listener, "xyz").await.unwrap(), "abc");
+        client.await.unwrap();
+    }
+}
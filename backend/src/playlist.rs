@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+
+use crate::{
+    notifier::{NotificationTarget, TurnReminder},
+    spotify::SpotifyPlaylistItem,
+};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone)]
+pub struct PlaylistConfig {
+    pub playlist_id: String,
+    pub notification_targets: Vec<NotificationTarget>,
+    // 空の場合はユーザーテーブルの全員をorder順にローテーションする
+    pub member_spotify_user_ids: Vec<String>,
+    // "ja"または"en"
+    pub locale: String,
+    // MessageTemplatesのフィールド名をキーとした文言の上書き
+    pub template_overrides: HashMap<String, String>,
+    // Noneの場合は番の人にリマインドしない
+    pub reminder: Option<ReminderConfig>,
+    // 番が回ってきてからこの日数が過ぎても曲が追加されない場合は、自動的に次の人の番にする
+    pub turn_deadline_days: Option<u64>,
+}
+
+impl PlaylistConfig {
+    pub fn is_turn_overdue(&self, turn: &Turn, now: u64) -> bool {
+        self.turn_deadline_days
+            .is_some_and(|days| now.saturating_sub(turn.started_at) >= days * SECONDS_PER_DAY)
+    }
+}
+
+// 番が回ってきてからgrace_period_days日後に最初のリマインドを送り、
+// その後はinterval_days日ごとにmax_reminders回まで送る
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReminderConfig {
+    pub grace_period_days: u64,
+    pub interval_days: u64,
+    pub max_reminders: u64,
+}
+
+impl ReminderConfig {
+    // 今送るべきリマインド。送る必要がなければNone
+    pub fn due_reminder(&self, turn: &Turn, now: u64) -> Option<TurnReminder> {
+        if turn.reminder_count >= self.max_reminders {
+            return None;
+        }
+        let elapsed_days = now.saturating_sub(turn.started_at) / SECONDS_PER_DAY;
+        if elapsed_days < self.grace_period_days + turn.reminder_count * self.interval_days {
+            return None;
+        }
+        Some(TurnReminder {
+            number: turn.reminder_count + 1,
+            elapsed_days,
+            is_last: turn.reminder_count + 1 == self.max_reminders,
+        })
+    }
+}
+
+pub const UNAVAILABLE_TRACK_ID_PREFIX: &str = "unavailable:";
+
+// プレイリスト内の1曲を識別する。同じ曲が複数回追加されてもadded_atで区別できる
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TrackFingerprint {
+    pub track_id: String,
+    pub added_at: String,
+    pub added_by: String,
+}
+
+impl TrackFingerprint {
+    pub fn from_item(item: &SpotifyPlaylistItem) -> Self {
+        Self {
+            track_id: item.track_id(),
+            added_at: item.added_at.clone(),
+            added_by: item.added_by.id.clone(),
+        }
+    }
+
+    // 曲が削除されるなどして、trackがnullになった項目
+    pub fn is_unavailable(&self) -> bool {
+        self.track_id.starts_with(UNAVAILABLE_TRACK_ID_PREFIX)
+    }
+
+    pub fn track_url(&self) -> String {
+        format!("https://open.spotify.com/track/{}", self.track_id)
+    }
+}
+
+// 通知のoutbox。同じ変更(idempotency_key)について通知済みの通知先を記録し、再実行時に二重に通知しない
+#[derive(Debug, Clone, PartialEq)]
+pub struct NotificationOutbox {
+    pub idempotency_key: String,
+    // 通知内容のJSON
+    pub payload: String,
+    // 送信済みのメッセージの一覧。NotificationTarget::keyにページ番号を付けた"{key}#{page}"
+    pub delivered_targets: Vec<String>,
+    // UNIX時間(秒)
+    pub created_at: u64,
+}
+
+// 曲を追加する番。番の人が曲を追加すると次の人に進む
+#[derive(Debug, Clone, PartialEq)]
+pub struct Turn {
+    pub spotify_user_id: String,
+    // 番が回ってきた時刻(UNIX時間の秒)
+    pub started_at: u64,
+    // 番が進むたびに1増える通し番号
+    pub number: u64,
+    // この番で送ったリマインドの回数
+    pub reminder_count: u64,
+}
+
+// スキップされた番の記録。集計に使う
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkippedTurn {
+    pub spotify_user_id: String,
+    pub number: u64,
+    // 番の記録を導入する前の番の場合はNone
+    pub started_at: Option<u64>,
+    pub skipped_at: u64,
+    pub reason: SkipReason,
+}
+
+// 番をスキップした理由
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SkipReason {
+    // 期限を過ぎても曲が追加されなかった
+    Overdue,
+    // 番の人が休止中・不在だった
+    Away,
+    // /skipやSkipTurnによる手動スキップ
+    Manual,
+}
+
+impl SkipReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Overdue => "overdue",
+            Self::Away => "away",
+            Self::Manual => "manual",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "overdue" => Some(Self::Overdue),
+            "away" => Some(Self::Away),
+            "manual" => Some(Self::Manual),
+            _ => None,
+        }
+    }
+}
+
+// プレイリストごとの通知状態。前回実行時点のsnapshot_idと曲の並びを保持する
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlaylistState {
+    pub snapshot_id: String,
+    pub tracks: Vec<TrackFingerprint>,
+    // 現在の番。番の記録を導入する前に保存された状態ではNone
+    pub turn: Option<Turn>,
+    // 楽観的排他制御のためのバージョン。保存するたびに1増やす。未保存の場合は0
+    pub version: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_due_reminder() {
+        let config = ReminderConfig {
+            grace_period_days: 3,
+            interval_days: 2,
+            max_reminders: 2,
+        };
+        let turn = Turn {
+            spotify_user_id: "spotify_user_1".to_string(),
+            started_at: 0,
+            number: 1,
+            reminder_count: 0,
+        };
+        let day = |days: u64| days * SECONDS_PER_DAY;
+        // 猶予期間中はリマインドしない
+        assert_eq!(config.due_reminder(&turn, day(3) - 1), None);
+        assert_eq!(
+            config.due_reminder(&turn, day(3)),
+            Some(TurnReminder {
+                number: 1,
+                elapsed_days: 3,
+                is_last: false,
+            })
+        );
+        // 2回目はinterval_days後
+        let reminded = Turn {
+            reminder_count: 1,
+            ..turn.clone()
+        };
+        assert_eq!(config.due_reminder(&reminded, day(4)), None);
+        assert_eq!(
+            config.due_reminder(&reminded, day(5)),
+            Some(TurnReminder {
+                number: 2,
+                elapsed_days: 5,
+                is_last: true,
+            })
+        );
+        // 上限に達したら送らない
+        let capped = Turn {
+            reminder_count: 2,
+            ..turn
+        };
+        assert_eq!(config.due_reminder(&capped, day(30)), None);
+    }
+}
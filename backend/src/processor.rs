@@ -0,0 +1,2719 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{
+    command::{DryRunPayload, LambdaCommand, LambdaResponse},
+    diff::PlaylistDiff,
+    discord::DiscordCreateMessageRequest,
+    dynamodb::{DynamoDBClient, DynamoDBClientTrait},
+    error::{AppError, DomainError},
+    notifier::{NotificationTarget, Notifier, NotifierTrait, PlaylistNotification},
+    playlist::{
+        NotificationOutbox, PlaylistConfig, PlaylistState, SkipReason, SkippedTurn,
+        TrackFingerprint, Turn,
+    },
+    spotify::{SpotifyClient, SpotifyClientTrait, SpotifyError, SpotifyPlaylistResponse},
+    template::MessageTemplates,
+    user::{User, UserMaster},
+};
+
+// Lambdaのタイムアウト(5分)より長くし、実行中にロックの期限が切れないようにする
+const RUN_LOCK_LEASE: Duration = Duration::from_secs(6 * 60);
+
+pub async fn execute_process(
+    owner: &str,
+    command: &LambdaCommand,
+) -> Result<LambdaResponse, AppError> {
+    let dynamodb_client = DynamoDBClient::new().await;
+    // dry_runは通知もDynamoDBへの書き込みもしないため、他の実行と並行してよい
+    if let LambdaCommand::DryRun { .. } = command {
+        return execute_process_locked(dynamodb_client, command).await;
+    }
+    with_run_lock(
+        &dynamodb_client,
+        owner,
+        execute_process_locked(dynamodb_client.clone(), command),
+    )
+    .await
+}
+
+// 同時に実行されると二重に通知されるため、ロックを取得できなかった場合は何もしない
+async fn with_run_lock<D: DynamoDBClientTrait>(
+    dynamodb_client: &D,
+    owner: &str,
+    process: impl Future<Output = Result<LambdaResponse, AppError>>,
+) -> Result<LambdaResponse, AppError> {
+    if !dynamodb_client
+        .acquire_run_lock(owner, RUN_LOCK_LEASE)
+        .await?
+    {
+        println!("skipped: another invocation is running");
+        return Ok(LambdaResponse {
+            skipped: true,
+            ..LambdaResponse::default()
+        });
+    }
+    let result = process.await;
+    // 解放に失敗してもリースの期限が切れれば次の実行でロックを取得できる
+    if let Err(e) = dynamodb_client.release_run_lock(owner).await {
+        println!("failed to release run lock: {}", e);
+    }
+    result
+}
+
+async fn execute_process_locked(
+    dynamodb_client: DynamoDBClient,
+    command: &LambdaCommand,
+) -> Result<LambdaResponse, AppError> {
+    let spotify_refresh_token = if let Some(spotify_refresh_token) =
+        dynamodb_client.extract_spotify_refresh_token().await?
+    {
+        spotify_refresh_token
+    } else {
+        return Err(DomainError::MissingSpotifyRefreshToken.into());
+    };
+    let notifier = Notifier::init()?;
+    let spotify_client = match SpotifyClient::init(&spotify_refresh_token).await {
+        Ok(spotify_client) => spotify_client,
+        // dry_runではトークンが無効になったことも記録しない
+        Err(e) if matches!(command, LambdaCommand::DryRun { .. }) => return Err(e),
+        Err(e) => {
+            return Err(alert_invalid_refresh_token(
+                &dynamodb_client,
+                &notifier,
+                &spotify_refresh_token,
+                e,
+            )
+            .await);
+        }
+    };
+    let processer =
+        SpotifyPlaylistNotificationProcesser::init(dynamodb_client, spotify_client, notifier)
+            .await?;
+    processer.run(command).await
+}
+
+// リフレッシュトークンが無効になった場合は再認可が必要なため、運用者に一度だけ通知する
+async fn alert_invalid_refresh_token<D: DynamoDBClientTrait, N: NotifierTrait>(
+    dynamodb_client: &D,
+    notifier: &N,
+    spotify_refresh_token: &str,
+    error: AppError,
+) -> AppError {
+    if let AppError::Spotify(SpotifyError::InvalidGrant { message }) = &error {
+        match dynamodb_client
+            .mark_spotify_refresh_token_invalid(spotify_refresh_token, message)
+            .await
+        {
+            Ok(true) => {
+                let alert = format!(
+                    "Spotifyのリフレッシュトークンが無効になりました ({message})。\n`cargo run --bin cli -- auth login`で再認可してください。"
+                );
+                if let Err(e) = notifier.alert_admin(&alert).await {
+                    println!("failed to alert admin: {}", e);
+                }
+            }
+            Ok(false) => println!("skipped: invalid refresh token was already alerted"),
+            Err(e) => println!("failed to record invalid refresh token: {}", e),
+        }
+    }
+    error
+}
+
+// メールのダイジェストを送るまで反映を待つ書き込み
+enum PendingWrite {
+    SkippedTurn(SkippedTurn),
+    PlaylistState(PlaylistState),
+}
+
+// ダイジェストにまとめて送る通知。送った後にoutboxへ通知済みとして記録する
+struct DigestPage {
+    target: NotificationTarget,
+    idempotency_key: String,
+    page_key: String,
+    notification: PlaylistNotification,
+}
+
+// 一つのプレイリストの処理で、実行の最後にまとめて送る通知と反映する書き込み
+#[derive(Default)]
+struct PlaylistRun {
+    writes: Vec<PendingWrite>,
+    digest_pages: Vec<DigestPage>,
+}
+
+struct SpotifyPlaylistNotificationProcesser<
+    D: DynamoDBClientTrait,
+    S: SpotifyClientTrait,
+    N: NotifierTrait,
+> {
+    playlist_configs: Vec<PlaylistConfig>,
+    dynamodb_client: D,
+    user_master: UserMaster,
+    spotify_client: S,
+    notifier: N,
+    // 実行開始時刻(UNIX時間の秒)。番が回ってきた時刻として記録する
+    now: u64,
+}
+
+impl<D: DynamoDBClientTrait, S: SpotifyClientTrait, N: NotifierTrait>
+    SpotifyPlaylistNotificationProcesser<D, S, N>
+{
+    async fn init(dynamodb_client: D, spotify_client: S, notifier: N) -> Result<Self, AppError> {
+        let playlist_configs = dynamodb_client.extract_playlist_configs().await?;
+        let user_master = dynamodb_client.extract_user_master().await?;
+
+        Ok(Self {
+            playlist_configs,
+            dynamodb_client,
+            user_master,
+            spotify_client,
+            notifier,
+            now: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        })
+    }
+
+    async fn run(&self, command: &LambdaCommand) -> Result<LambdaResponse, AppError> {
+        let result = match command {
+            LambdaCommand::Check { playlist_id } => self
+                .execute(playlist_id.as_deref(), false)
+                .await
+                .map(|_| LambdaResponse::default()),
+            LambdaCommand::DryRun { playlist_id } => self
+                .execute(playlist_id.as_deref(), true)
+                .await
+                .map(|dry_run| LambdaResponse {
+                    dry_run,
+                    ..LambdaResponse::default()
+                }),
+            LambdaCommand::ResendLast { playlist_id } => self
+                .resend_last(playlist_id)
+                .await
+                .map(|_| LambdaResponse::default()),
+            LambdaCommand::SkipTurn { playlist_id } => self
+                .skip_turn(playlist_id)
+                .await
+                .map(|_| LambdaResponse::default()),
+            LambdaCommand::RebuildState { playlist_id } => self
+                .rebuild_state(playlist_id.as_deref())
+                .await
+                .map(|_| LambdaResponse::default()),
+        };
+        if let LambdaCommand::DryRun { .. } = command {
+            // dry_runではDynamoDBに書き込まないため、新しいトークンはこの実行の間だけ使う
+            if self
+                .spotify_client
+                .get_next_spotify_refresh_token()
+                .is_some()
+            {
+                println!("dry run: rotated spotify refresh token was not persisted");
+            }
+            return result;
+        }
+        // 操作の成否にかかわらず、新しいリフレッシュトークンは保存する
+        self.save_next_spotify_refresh_token().await?;
+        result
+    }
+
+    // dry_runの場合は、通知するはずだったDiscordのメッセージを返す
+    async fn execute(
+        &self,
+        playlist_id: Option<&str>,
+        dry_run: bool,
+    ) -> Result<Vec<DryRunPayload>, AppError> {
+        // 一つのプレイリストの失敗で他のプレイリストの通知が止まらないように、エラーは最後にまとめて返す
+        let mut failed_playlists = Vec::new();
+        let mut dry_run_payloads = Vec::new();
+        let mut runs = Vec::new();
+        for playlist_config in self.select_playlist_configs(playlist_id)? {
+            let mut run = PlaylistRun::default();
+            match self
+                .execute_playlist(playlist_config, dry_run, &mut run)
+                .await
+            {
+                Ok(Some(notification)) if dry_run => {
+                    let payload = DryRunPayload {
+                        playlist_id: playlist_config.playlist_id.clone(),
+                        discord_messages: DiscordCreateMessageRequest::from_notification(
+                            &notification,
+                        ),
+                    };
+                    println!(
+                        "{}: dry run: {}",
+                        playlist_config.playlist_id,
+                        serde_json::to_string(&payload)?
+                    );
+                    dry_run_payloads.push(payload);
+                }
+                Ok(_) => runs.push((playlist_config.playlist_id.clone(), run)),
+                Err(e) => {
+                    println!("{}: {:}", playlist_config.playlist_id, e);
+                    failed_playlists.push((playlist_config.playlist_id.clone(), e));
+                }
+            }
+        }
+        for (playlist_id, e) in self.finish_runs(runs).await {
+            match e {
+                // 同時に実行された別の処理が先に状態を更新した場合は、その処理の結果を正とする
+                AppError::Conflict(resource) => {
+                    println!(
+                        "{}: skipped: {} was updated concurrently",
+                        playlist_id, resource
+                    );
+                }
+                e => failed_playlists.push((playlist_id, e)),
+            }
+        }
+        if !failed_playlists.is_empty() {
+            return Err(AppError::FailedPlaylists(failed_playlists));
+        }
+        Ok(dry_run_payloads)
+    }
+
+    async fn save_next_spotify_refresh_token(&self) -> Result<(), AppError> {
+        if let Some(new_refresh_token) = &self.spotify_client.get_next_spotify_refresh_token() {
+            match self
+                .dynamodb_client
+                .update_spotify_refresh_token(
+                    self.spotify_client.get_spotify_refresh_token(),
+                    new_refresh_token,
+                )
+                .await
+            {
+                Ok(_) => {}
+                Err(AppError::Conflict(resource)) => {
+                    println!("skipped: {} was updated concurrently", resource);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    // メールのダイジェストを通知先ごとに1通ずつ送り、送れたプレイリストの書き込みを反映する
+    // 失敗したプレイリストは、書き込みを反映せずに次回の実行で未通知の通知先にだけ再通知する
+    async fn finish_runs(&self, runs: Vec<(String, PlaylistRun)>) -> Vec<(String, AppError)> {
+        let mut failed_playlists: Vec<(String, AppError)> = Vec::new();
+        let mut digests: Vec<(&NotificationTarget, Vec<(&str, &DigestPage)>)> = Vec::new();
+        for (playlist_id, run) in &runs {
+            for page in &run.digest_pages {
+                if let Some((_, pages)) = digests.iter_mut().find(|(t, _)| **t == page.target) {
+                    pages.push((playlist_id, page));
+                } else {
+                    digests.push((&page.target, vec![(playlist_id, page)]));
+                }
+            }
+        }
+        let mut undelivered_playlist_ids: Vec<&str> = Vec::new();
+        for (target, pages) in &digests {
+            let notifications = pages
+                .iter()
+                .map(|(_, page)| page.notification.clone())
+                .collect::<Vec<PlaylistNotification>>();
+            if let Err(e) = self.notifier.notify_digest(target, &notifications).await {
+                let mut playlist_ids: Vec<&str> = pages.iter().map(|(id, _)| *id).collect();
+                playlist_ids.dedup();
+                println!("{}: {}: {:}", playlist_ids.join(","), target, e);
+                undelivered_playlist_ids.extend(&playlist_ids);
+                failed_playlists.push((
+                    playlist_ids.join(","),
+                    AppError::FailedNotificationTargets(vec![(target.to_string(), e)]),
+                ));
+                continue;
+            }
+            for (playlist_id, page) in pages {
+                if let Err(e) = self
+                    .dynamodb_client
+                    .add_notification_outbox_delivered_target(
+                        playlist_id,
+                        &page.idempotency_key,
+                        &page.page_key,
+                    )
+                    .await
+                {
+                    println!("{}: {}: {:}", playlist_id, target, e);
+                    undelivered_playlist_ids.push(playlist_id);
+                    failed_playlists.push((playlist_id.to_string(), e));
+                }
+            }
+        }
+        for (playlist_id, run) in &runs {
+            if undelivered_playlist_ids.contains(&playlist_id.as_str()) {
+                continue;
+            }
+            if let Err(e) = self.apply_writes(playlist_id, &run.writes).await {
+                failed_playlists.push((playlist_id.clone(), e));
+            }
+        }
+        failed_playlists
+    }
+
+    // 状態はバージョンで排他するため、書き込みは順番に反映し、失敗したら残りは反映しない
+    async fn apply_writes(
+        &self,
+        playlist_id: &str,
+        writes: &[PendingWrite],
+    ) -> Result<(), AppError> {
+        for write in writes {
+            match write {
+                PendingWrite::SkippedTurn(skipped_turn) => {
+                    self.dynamodb_client
+                        .record_skipped_turn(playlist_id, skipped_turn)
+                        .await?
+                }
+                PendingWrite::PlaylistState(state) => {
+                    self.dynamodb_client
+                        .update_playlist_state(playlist_id, state)
+                        .await?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn select_playlist_configs(
+        &self,
+        playlist_id: Option<&str>,
+    ) -> Result<Vec<&PlaylistConfig>, AppError> {
+        match playlist_id {
+            Some(playlist_id) => Ok(vec![self.find_playlist_config(playlist_id)?]),
+            None => Ok(self.playlist_configs.iter().collect()),
+        }
+    }
+
+    fn find_playlist_config(&self, playlist_id: &str) -> Result<&PlaylistConfig, AppError> {
+        if let Some(playlist_config) = self
+            .playlist_configs
+            .iter()
+            .find(|c| c.playlist_id == playlist_id)
+        {
+            Ok(playlist_config)
+        } else {
+            Err(AppError::Config(format!("unknown playlist: {playlist_id}")))
+        }
+    }
+
+    // 通知した(dry_runの場合は通知するはずだった)内容を返す
+    async fn execute_playlist(
+        &self,
+        playlist_config: &PlaylistConfig,
+        dry_run: bool,
+        run: &mut PlaylistRun,
+    ) -> Result<Option<PlaylistNotification>, AppError> {
+        let playlist_id = playlist_config.playlist_id.as_str();
+        let spotify_playlist = self
+            .spotify_client
+            .get_spotify_playlist(playlist_id)
+            .await?;
+        let previous_state = self
+            .dynamodb_client
+            .extract_playlist_state(playlist_id)
+            .await?;
+        // snapshot_idが変わっていなければプレイリストに変更はないため、曲の差分は取らない
+        let is_unchanged = previous_state
+            .as_ref()
+            .is_some_and(|s| s.snapshot_id == spotify_playlist.snapshot_id);
+        let (state, sent_notification) = match previous_state {
+            Some(previous_state) if is_unchanged => (previous_state, None),
+            previous_state => {
+                let (state, notification) = self
+                    .apply_playlist_changes(
+                        playlist_config,
+                        &spotify_playlist,
+                        previous_state.as_ref(),
+                        dry_run,
+                        run,
+                    )
+                    .await?;
+                if dry_run && notification.is_some() {
+                    return Ok(notification);
+                }
+                (state, notification)
+            }
+        };
+        // 期限や不在、リマインドは曲の変更の有無にかかわらず毎回確認する
+        let turn_notification = self
+            .check_turn(playlist_config, &spotify_playlist, &state, dry_run, run)
+            .await?;
+        Ok(turn_notification.or(sent_notification))
+    }
+
+    // 前回の状態からの曲の差分を通知し、番を進めた状態を返す
+    async fn apply_playlist_changes(
+        &self,
+        playlist_config: &PlaylistConfig,
+        spotify_playlist: &SpotifyPlaylistResponse,
+        previous_state: Option<&PlaylistState>,
+        dry_run: bool,
+        run: &mut PlaylistRun,
+    ) -> Result<(PlaylistState, Option<PlaylistNotification>), AppError> {
+        let playlist_id = playlist_config.playlist_id.as_str();
+        let spotify_playlist_tracks = self
+            .spotify_client
+            .list_all_spotify_playlist_tracks(playlist_id)
+            .await?;
+        let tracks = spotify_playlist_tracks
+            .items
+            .iter()
+            .map(TrackFingerprint::from_item)
+            .collect::<Vec<TrackFingerprint>>();
+        let rotation = self.build_rotation(playlist_config);
+        let turn;
+        let mut sent_notification = None;
+        let mut away_skipped_turn = None;
+        // 状態が存在しない場合は現在の曲をすべて通知済みとみなす
+        if let Some(previous_state) = previous_state {
+            let diff =
+                PlaylistDiff::compute(&previous_state.tracks, &spotify_playlist_tracks.items);
+            let added = diff
+                .added
+                .iter()
+                .map(|item| TrackFingerprint::from_item(item))
+                .collect::<Vec<TrackFingerprint>>();
+            let advanced_turn = advance_turn(
+                playlist_id,
+                previous_state.turn.as_ref(),
+                &tracks,
+                &added,
+                &rotation,
+                self.now,
+            )?;
+            // 曲が追加された場合は次の人を通知するため、不在の人を通知しないようにここで番を回す
+            // それ以外の場合は、check_turnで番を回してスキップを通知する
+            turn = if added.is_empty() {
+                advanced_turn
+            } else {
+                let (passed_turn, skipped_turn) =
+                    self.pass_away_turn(playlist_config, advanced_turn, &rotation);
+                away_skipped_turn = skipped_turn;
+                passed_turn
+            };
+            if !diff.is_empty() {
+                println!(
+                    "{}: added {}, removed {}, moved {}",
+                    playlist_id,
+                    diff.added.len(),
+                    diff.removed.len(),
+                    diff.moved.len()
+                );
+                // 曲が追加された場合は、番を進めた後の人を次の人として通知する
+                let next_user = if diff.added.is_empty() {
+                    None
+                } else {
+                    turn.as_ref()
+                        .and_then(|t| rotation.get_user_by_spotify_id(&t.spotify_user_id))
+                };
+                let notification =
+                    self.build_notification(playlist_config, spotify_playlist, &diff, next_user)?;
+                if !dry_run {
+                    let idempotency_key = diff.idempotency_key(&previous_state.snapshot_id);
+                    self.notify(playlist_config, &notification, &idempotency_key, run)
+                        .await?;
+                }
+                sent_notification = Some(notification);
+            }
+        } else {
+            turn = advance_turn(playlist_id, None, &tracks, &[], &rotation, self.now)?;
+        }
+        let state = PlaylistState {
+            snapshot_id: spotify_playlist.snapshot_id.clone(),
+            tracks,
+            turn,
+            version: previous_state.map_or(0, |s| s.version) + 1,
+        };
+        if dry_run {
+            return Ok((state, sent_notification));
+        }
+        if let Some(skipped_turn) = away_skipped_turn {
+            run.writes.push(PendingWrite::SkippedTurn(skipped_turn));
+        }
+        // 通知がすべて成功した場合のみ状態を更新し、失敗した場合は次回の実行で未通知の通知先にだけ再通知する
+        run.writes.push(PendingWrite::PlaylistState(state.clone()));
+        Ok((state, sent_notification))
+    }
+
+    // 番の人が期限を過ぎたか不在になった場合は番を回し、そうでなければ必要に応じてリマインドする
+    async fn check_turn(
+        &self,
+        playlist_config: &PlaylistConfig,
+        spotify_playlist: &SpotifyPlaylistResponse,
+        state: &PlaylistState,
+        dry_run: bool,
+        run: &mut PlaylistRun,
+    ) -> Result<Option<PlaylistNotification>, AppError> {
+        if let Some(turn) = &state.turn {
+            let reason = if playlist_config.is_turn_overdue(turn, self.now) {
+                Some(SkipReason::Overdue)
+            } else if self.is_turn_holder_away(playlist_config, turn) {
+                Some(SkipReason::Away)
+            } else {
+                None
+            };
+            if let Some(reason) = reason {
+                println!(
+                    "{}: turn {} of {} is skipped: {}",
+                    playlist_config.playlist_id,
+                    turn.number,
+                    turn.spotify_user_id,
+                    reason.as_str()
+                );
+                return self
+                    .pass_turn(
+                        playlist_config,
+                        spotify_playlist,
+                        state,
+                        reason,
+                        dry_run,
+                        run,
+                    )
+                    .await
+                    .map(Some);
+            }
+        }
+        self.remind_turn(playlist_config, spotify_playlist, state, dry_run, run)
+            .await
+    }
+
+    // 番が回ってきてから曲が追加されないまま猶予期間が過ぎた場合に、番の人にリマインドする
+    async fn remind_turn(
+        &self,
+        playlist_config: &PlaylistConfig,
+        spotify_playlist: &SpotifyPlaylistResponse,
+        state: &PlaylistState,
+        dry_run: bool,
+        run: &mut PlaylistRun,
+    ) -> Result<Option<PlaylistNotification>, AppError> {
+        let playlist_id = playlist_config.playlist_id.as_str();
+        let (reminder_config, turn) = match (&playlist_config.reminder, &state.turn) {
+            (Some(reminder_config), Some(turn)) => (reminder_config, turn),
+            _ => return Ok(None),
+        };
+        let reminder = if let Some(reminder) = reminder_config.due_reminder(turn, self.now) {
+            reminder
+        } else {
+            return Ok(None);
+        };
+        let rotation = self.build_rotation(playlist_config);
+        // 不在の人にはリマインドしない
+        let user = if let Some(user) = rotation
+            .get_user_by_spotify_id(&turn.spotify_user_id)
+            .filter(|user| user.is_available(self.now))
+        {
+            user
+        } else {
+            return Ok(None);
+        };
+        println!(
+            "{}: reminder {} to {} after {} days",
+            playlist_id, reminder.number, user.spotify_user_id, reminder.elapsed_days
+        );
+        let reminder_count = reminder.number;
+        let notification = PlaylistNotification::turn_reminder(
+            playlist_id,
+            spotify_playlist,
+            user,
+            reminder,
+            self.build_templates(playlist_config)?,
+        );
+        if dry_run {
+            return Ok(Some(notification));
+        }
+        // 番と何回目のリマインドかをキーにし、再実行時に同じ回のリマインドを二重に送らない
+        let idempotency_key = format!("reminder:{}:{}", turn.number, reminder_count);
+        self.notify(playlist_config, &notification, &idempotency_key, run)
+            .await?;
+        // 送信に成功してから記録する。記録に失敗して再実行されてもoutboxで二重の送信を防ぐ
+        run.writes.push(PendingWrite::PlaylistState(PlaylistState {
+            turn: Some(Turn {
+                reminder_count,
+                ..turn.clone()
+            }),
+            version: state.version + 1,
+            ..state.clone()
+        }));
+        Ok(Some(notification))
+    }
+
+    async fn resend_last(&self, playlist_id: &str) -> Result<(), AppError> {
+        let playlist_config = self.find_playlist_config(playlist_id)?;
+        let outbox = if let Some(outbox) = self
+            .dynamodb_client
+            .extract_latest_notification_outbox(playlist_id)
+            .await?
+        {
+            outbox
+        } else {
+            return Err(DomainError::NoNotificationHistory {
+                playlist_id: playlist_id.to_string(),
+            }
+            .into());
+        };
+        let mut notification: PlaylistNotification = serde_json::from_str(&outbox.payload)?;
+        notification.templates = self.build_templates(playlist_config)?;
+        self.notify_all(playlist_config, &notification).await
+    }
+
+    async fn skip_turn(&self, playlist_id: &str) -> Result<(), AppError> {
+        let playlist_config = self.find_playlist_config(playlist_id)?;
+        let state = if let Some(state) = self
+            .dynamodb_client
+            .extract_playlist_state(playlist_id)
+            .await?
+        {
+            state
+        } else {
+            return Err(DomainError::NoPlaylistState {
+                playlist_id: playlist_id.to_string(),
+            }
+            .into());
+        };
+        let spotify_playlist = self
+            .spotify_client
+            .get_spotify_playlist(playlist_id)
+            .await?;
+        let mut run = PlaylistRun::default();
+        self.pass_turn(
+            playlist_config,
+            &spotify_playlist,
+            &state,
+            SkipReason::Manual,
+            false,
+            &mut run,
+        )
+        .await?;
+        if let Some((_, e)) = self
+            .finish_runs(vec![(playlist_id.to_string(), run)])
+            .await
+            .into_iter()
+            .next()
+        {
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // 現在の番の人をスキップして次の人の番にする。通知とスキップの記録に成功してから番を進める
+    async fn pass_turn(
+        &self,
+        playlist_config: &PlaylistConfig,
+        spotify_playlist: &SpotifyPlaylistResponse,
+        state: &PlaylistState,
+        reason: SkipReason,
+        dry_run: bool,
+        run: &mut PlaylistRun,
+    ) -> Result<PlaylistNotification, AppError> {
+        let playlist_id = playlist_config.playlist_id.as_str();
+        // 番の記録がなければスキップする番を決められない
+        let turn = if let Some(turn) = &state.turn {
+            turn
+        } else {
+            return Err(DomainError::NoCurrentTurn {
+                playlist_id: playlist_id.to_string(),
+            }
+            .into());
+        };
+        let rotation = self.build_rotation(playlist_config);
+        let skipped_user = current_turn_user(playlist_id, &rotation, state, self.now)?;
+        let next_user = if let Some(next_user) =
+            rotation.get_next_user_by_spotify_id(&skipped_user.spotify_user_id, self.now)
+        {
+            next_user
+        } else {
+            return Err(DomainError::NoNextUser {
+                spotify_user_id: skipped_user.spotify_user_id.clone(),
+            }
+            .into());
+        };
+        let notification = PlaylistNotification::turn_skipped(
+            playlist_id,
+            spotify_playlist,
+            skipped_user,
+            next_user,
+            self.build_templates(playlist_config)?,
+        );
+        if dry_run {
+            return Ok(notification);
+        }
+        let number = turn.number;
+        // スキップした番をキーにし、送信に成功してから状態を更新する
+        // 状態の更新に失敗して再実行されても、outboxで二重の送信を防ぐ
+        let idempotency_key = format!("skip:{}", number);
+        self.notify(playlist_config, &notification, &idempotency_key, run)
+            .await?;
+        // スキップの記録は同じ番であれば重複しないため、番を進める前に記録する
+        run.writes.push(PendingWrite::SkippedTurn(SkippedTurn {
+            spotify_user_id: skipped_user.spotify_user_id.clone(),
+            number,
+            started_at: Some(turn.started_at),
+            skipped_at: self.now,
+            reason,
+        }));
+        run.writes.push(PendingWrite::PlaylistState(PlaylistState {
+            turn: Some(Turn {
+                spotify_user_id: next_user.spotify_user_id.clone(),
+                started_at: self.now,
+                number: number + 1,
+                reminder_count: 0,
+            }),
+            version: state.version + 1,
+            ..state.clone()
+        }));
+        Ok(notification)
+    }
+
+    async fn rebuild_state(&self, playlist_id: Option<&str>) -> Result<(), AppError> {
+        for playlist_config in self.select_playlist_configs(playlist_id)? {
+            let playlist_id = playlist_config.playlist_id.as_str();
+            let spotify_playlist = self
+                .spotify_client
+                .get_spotify_playlist(playlist_id)
+                .await?;
+            let spotify_playlist_tracks = self
+                .spotify_client
+                .list_all_spotify_playlist_tracks(playlist_id)
+                .await?;
+            let previous_state = self
+                .dynamodb_client
+                .extract_playlist_state(playlist_id)
+                .await?;
+            self.dynamodb_client
+                .update_playlist_state(
+                    playlist_id,
+                    &PlaylistState {
+                        snapshot_id: spotify_playlist.snapshot_id.clone(),
+                        tracks: spotify_playlist_tracks
+                            .items
+                            .iter()
+                            .map(TrackFingerprint::from_item)
+                            .collect(),
+                        turn: previous_state.as_ref().and_then(|s| s.turn.clone()),
+                        version: previous_state.as_ref().map_or(0, |s| s.version) + 1,
+                    },
+                )
+                .await?;
+            println!(
+                "{}: rebuilt state with {} tracks",
+                playlist_id,
+                spotify_playlist_tracks.items.len()
+            );
+        }
+        Ok(())
+    }
+
+    fn build_templates(
+        &self,
+        playlist_config: &PlaylistConfig,
+    ) -> Result<MessageTemplates, AppError> {
+        MessageTemplates::for_locale(&playlist_config.locale)?
+            .with_overrides(&playlist_config.template_overrides)
+    }
+
+    fn build_rotation(&self, playlist_config: &PlaylistConfig) -> UserMaster {
+        self.user_master
+            .filter_by_spotify_user_ids(&playlist_config.member_spotify_user_ids)
+    }
+
+    // 番の人が不在になった場合は番を回す。全員が不在の場合は回しても意味がないため回さない
+    fn is_turn_holder_away(&self, playlist_config: &PlaylistConfig, turn: &Turn) -> bool {
+        let rotation = self.build_rotation(playlist_config);
+        rotation
+            .get_user_by_spotify_id(&turn.spotify_user_id)
+            .is_some_and(|user| !user.is_available(self.now))
+            && rotation
+                .users
+                .iter()
+                .any(|user| user.is_available(self.now))
+    }
+
+    // 番の人が不在であれば次の人に番を回し、スキップの記録とともに返す
+    fn pass_away_turn(
+        &self,
+        playlist_config: &PlaylistConfig,
+        turn: Option<Turn>,
+        rotation: &UserMaster,
+    ) -> (Option<Turn>, Option<SkippedTurn>) {
+        if let Some(current) = &turn
+            && self.is_turn_holder_away(playlist_config, current)
+            && let Some(next_user) =
+                rotation.get_next_user_by_spotify_id(&current.spotify_user_id, self.now)
+        {
+            return (
+                Some(Turn {
+                    spotify_user_id: next_user.spotify_user_id.clone(),
+                    started_at: self.now,
+                    number: current.number + 1,
+                    reminder_count: 0,
+                }),
+                Some(SkippedTurn {
+                    spotify_user_id: current.spotify_user_id.clone(),
+                    number: current.number,
+                    started_at: Some(current.started_at),
+                    skipped_at: self.now,
+                    reason: SkipReason::Away,
+                }),
+            );
+        }
+        (turn, None)
+    }
+
+    fn build_notification(
+        &self,
+        playlist_config: &PlaylistConfig,
+        spotify_playlist: &SpotifyPlaylistResponse,
+        diff: &PlaylistDiff<'_>,
+        next_user: Option<&User>,
+    ) -> Result<PlaylistNotification, AppError> {
+        let templates = self.build_templates(playlist_config)?;
+        Ok(PlaylistNotification::new(
+            &playlist_config.playlist_id,
+            spotify_playlist,
+            diff,
+            next_user,
+            &self.user_master,
+            templates,
+        ))
+    }
+
+    async fn notify(
+        &self,
+        playlist_config: &PlaylistConfig,
+        notification: &PlaylistNotification,
+        idempotency_key: &str,
+        run: &mut PlaylistRun,
+    ) -> Result<(), AppError> {
+        let playlist_id = playlist_config.playlist_id.as_str();
+        // 前回の実行で通知済みの通知先には再通知しない
+        let outbox = match self
+            .dynamodb_client
+            .extract_notification_outbox(playlist_id, idempotency_key)
+            .await?
+        {
+            Some(outbox) => outbox,
+            None => {
+                let outbox = NotificationOutbox {
+                    idempotency_key: idempotency_key.to_string(),
+                    payload: serde_json::to_string(notification)?,
+                    delivered_targets: vec![],
+                    created_at: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                };
+                self.dynamodb_client
+                    .create_notification_outbox(playlist_id, &outbox)
+                    .await?;
+                outbox
+            }
+        };
+        // 一つの通知先の失敗で他の通知先への通知が止まらないようにする
+        let mut failed_targets = Vec::new();
+        for target in &playlist_config.notification_targets {
+            // 複数のメッセージに分かれる通知先は、送ったメッセージごとに記録する
+            let target_key = target.key();
+            for page in 0..target.page_count(notification) {
+                let page_key = format!("{}#{}", target_key, page);
+                if outbox.delivered_targets.contains(&page_key) {
+                    println!("{}: {}#{}: already notified", playlist_id, target, page);
+                    continue;
+                }
+                if target.is_digest() {
+                    run.digest_pages.push(DigestPage {
+                        target: target.clone(),
+                        idempotency_key: idempotency_key.to_string(),
+                        page_key,
+                        notification: notification.clone(),
+                    });
+                    continue;
+                }
+                if let Err(e) = self.notifier.notify(target, notification, page).await {
+                    // 順番が入れ替わらないように、失敗したら残りのページは次回の再実行で送る
+                    println!("{}: {}#{}: {:}", playlist_id, target, page, e);
+                    failed_targets.push((target.to_string(), e));
+                    break;
+                }
+                self.dynamodb_client
+                    .add_notification_outbox_delivered_target(
+                        playlist_id,
+                        idempotency_key,
+                        &page_key,
+                    )
+                    .await?;
+            }
+        }
+        if !failed_targets.is_empty() {
+            return Err(AppError::FailedNotificationTargets(failed_targets));
+        }
+        Ok(())
+    }
+
+    // outboxを使わずにすべての通知先に通知する
+    async fn notify_all(
+        &self,
+        playlist_config: &PlaylistConfig,
+        notification: &PlaylistNotification,
+    ) -> Result<(), AppError> {
+        let mut failed_targets = Vec::new();
+        for target in &playlist_config.notification_targets {
+            for page in 0..target.page_count(notification) {
+                if let Err(e) = self.notifier.notify(target, notification, page).await {
+                    println!("{}: {}: {:}", playlist_config.playlist_id, target, e);
+                    failed_targets.push((target.to_string(), e));
+                    break;
+                }
+            }
+        }
+        if !failed_targets.is_empty() {
+            return Err(AppError::FailedNotificationTargets(failed_targets));
+        }
+        Ok(())
+    }
+}
+
+// 現在の番の人。番の記録がなければ最後に曲を追加した人の次の人
+pub fn current_turn_user<'a>(
+    playlist_id: &str,
+    rotation: &'a UserMaster,
+    state: &PlaylistState,
+    now: u64,
+) -> Result<&'a User, AppError> {
+    if let Some(turn) = &state.turn
+        && let Some(user) = rotation.get_user_by_spotify_id(&turn.spotify_user_id)
+    {
+        return Ok(user);
+    }
+    next_user_after_last_track(playlist_id, rotation, &state.tracks, now)
+}
+
+// 番の人が曲を追加するたびに、番を次の人に進める。
+// 番の記録がない、または番の人がローテーションから外れた場合は、最後に曲を追加した人の次の人を番にする
+pub fn advance_turn(
+    playlist_id: &str,
+    previous_turn: Option<&Turn>,
+    tracks: &[TrackFingerprint],
+    added: &[TrackFingerprint],
+    rotation: &UserMaster,
+    now: u64,
+) -> Result<Option<Turn>, AppError> {
+    if let Some(previous_turn) = previous_turn
+        && rotation
+            .get_user_by_spotify_id(&previous_turn.spotify_user_id)
+            .is_some()
+    {
+        let mut turn = previous_turn.clone();
+        let mut added = added.iter().collect::<Vec<&TrackFingerprint>>();
+        added.sort_by(|a, b| a.added_at.cmp(&b.added_at));
+        for track in added {
+            if track.added_by == turn.spotify_user_id
+                && let Some(next_user) =
+                    rotation.get_next_user_by_spotify_id(&turn.spotify_user_id, now)
+            {
+                turn = Turn {
+                    spotify_user_id: next_user.spotify_user_id.clone(),
+                    started_at: now,
+                    number: turn.number + 1,
+                    reminder_count: 0,
+                };
+            }
+        }
+        return Ok(Some(turn));
+    }
+    match next_user_after_last_track(playlist_id, rotation, tracks, now) {
+        Ok(user) => Ok(Some(Turn {
+            spotify_user_id: user.spotify_user_id.clone(),
+            started_at: now,
+            number: previous_turn.map_or(0, |t| t.number) + 1,
+            reminder_count: 0,
+        })),
+        // 曲が追加されていなければ次の人を通知しないため、番を決められなくても失敗にしない
+        Err(_) if added.is_empty() => Ok(previous_turn.cloned()),
+        Err(e) => Err(e),
+    }
+}
+
+fn next_user_after_last_track<'a>(
+    playlist_id: &str,
+    rotation: &'a UserMaster,
+    tracks: &[TrackFingerprint],
+    now: u64,
+) -> Result<&'a User, AppError> {
+    let last_track =
+        if let Some(last_track) = tracks.iter().max_by(|a, b| a.added_at.cmp(&b.added_at)) {
+            last_track
+        } else {
+            return Err(DomainError::NoCurrentTurn {
+                playlist_id: playlist_id.to_string(),
+            }
+            .into());
+        };
+    if let Some(user) = rotation.get_next_user_by_spotify_id(&last_track.added_by, now) {
+        Ok(user)
+    } else {
+        Err(DomainError::NoNextUser {
+            spotify_user_id: last_track.added_by.clone(),
+        }
+        .into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use mockall::predicate::{always, eq, in_iter};
+
+    use crate::{
+        discord::DiscordError,
+        dynamodb::MockDynamoDBClientTrait,
+        notifier::{MockNotifierTrait, NotificationTarget, TurnReminder},
+        playlist::ReminderConfig,
+        spotify::{MockSpotifyClientTrait, SpotifyPlaylistItem, SpotifyPlaylistTracksResponse},
+        user::User,
+    };
+
+    use super::*;
+
+    const TEST_NOW: u64 = 1_700_000_000;
+
+    #[tokio::test]
+    async fn test_execute_process() {
+        dotenvy::dotenv().ok();
+        execute_process("test", &LambdaCommand::Check { playlist_id: None })
+            .await
+            .unwrap();
+    }
+
+    impl UserMaster {
+        pub(crate) fn new_test_data() -> Self {
+            UserMaster {
+                users: vec![
+                    User {
+                        name: "User 1".to_string(),
+                        spotify_user_id: "spotify_user_1".to_string(),
+                        discord_user_id: "discord_user_1".to_string(),
+                        order: 1,
+                        paused: false,
+                        away: None,
+                    },
+                    User {
+                        name: "User 2".to_string(),
+                        spotify_user_id: "spotify_user_2".to_string(),
+                        discord_user_id: "discord_user_2".to_string(),
+                        order: 2,
+                        paused: false,
+                        away: None,
+                    },
+                ],
+            }
+        }
+    }
+
+    impl PlaylistConfig {
+        pub(crate) fn new_test_data() -> Self {
+            PlaylistConfig {
+                playlist_id: "test_playlist".to_string(),
+                notification_targets: vec![NotificationTarget::Discord {
+                    channel_id: "test_channel".to_string(),
+                }],
+                member_spotify_user_ids: vec![],
+                locale: "ja".to_string(),
+                template_overrides: HashMap::new(),
+                reminder: None,
+                turn_deadline_days: None,
+            }
+        }
+    }
+
+    impl SpotifyPlaylistResponse {
+        fn new_test_data() -> Self {
+            SpotifyPlaylistResponse {
+                name: "Test Playlist".to_string(),
+                snapshot_id: "snapshot_2".to_string(),
+                external_urls: crate::spotify::SpotifyExternalUrls {
+                    spotify: "https://open.spotify.com/playlist/test".to_string(),
+                },
+            }
+        }
+    }
+
+    impl SpotifyPlaylistTracksResponse {
+        fn new_test_data() -> Self {
+            SpotifyPlaylistTracksResponse {
+                next: None,
+                items: vec![
+                    SpotifyPlaylistItem::new_test_data(
+                        "track_1",
+                        "spotify_user_1",
+                        "2023-01-01T00:00:00Z",
+                    ),
+                    SpotifyPlaylistItem::new_test_data(
+                        "track_2",
+                        "spotify_user_2",
+                        "2023-01-02T00:00:00Z",
+                    ),
+                ],
+            }
+        }
+    }
+
+    impl PlaylistState {
+        pub(crate) fn new_test_data(snapshot_id: &str, track_ids: &[&str], version: u64) -> Self {
+            let tracks = SpotifyPlaylistTracksResponse::new_test_data();
+            PlaylistState {
+                snapshot_id: snapshot_id.to_string(),
+                tracks: track_ids
+                    .iter()
+                    .map(|id| {
+                        let item = tracks.items.iter().find(|i| i.track_id() == *id);
+                        match item {
+                            Some(item) => TrackFingerprint::from_item(item),
+                            None => TrackFingerprint {
+                                track_id: id.to_string(),
+                                added_at: "2022-12-31T00:00:00Z".to_string(),
+                                added_by: "spotify_user_2".to_string(),
+                            },
+                        }
+                    })
+                    .collect(),
+                turn: None,
+                version,
+            }
+        }
+    }
+
+    impl Turn {
+        pub(crate) fn new_test_data(spotify_user_id: &str, number: u64) -> Self {
+            Turn {
+                spotify_user_id: spotify_user_id.to_string(),
+                started_at: TEST_NOW,
+                number,
+                reminder_count: 0,
+            }
+        }
+    }
+
+    // テストごとに異なる期待だけを設定し、共通のモックの設定はbuildでまとめて行う
+    // (mockallは先に設定した期待から照合するため、buildで設定する期待は既定値になる)
+    struct TestProcesser {
+        dynamodb_client: MockDynamoDBClientTrait,
+        spotify_client: MockSpotifyClientTrait,
+        notifier: MockNotifierTrait,
+        state: Option<PlaylistState>,
+        playlist_configs: Vec<PlaylistConfig>,
+        user_master: fn() -> UserMaster,
+    }
+
+    impl TestProcesser {
+        fn new(state: Option<PlaylistState>) -> Self {
+            TestProcesser {
+                dynamodb_client: MockDynamoDBClientTrait::new(),
+                spotify_client: MockSpotifyClientTrait::new(),
+                notifier: MockNotifierTrait::new(),
+                state,
+                playlist_configs: vec![PlaylistConfig::new_test_data()],
+                user_master: UserMaster::new_test_data,
+            }
+        }
+
+        // outboxを新しく作成し、指定したページだけを通知済みとして記録することを期待する
+        // idempotency_keyがNoneの場合は、曲の差分から作るキーなど任意のキーに一致する
+        fn expect_new_outbox(&mut self, idempotency_key: Option<&str>, delivered_targets: &[&str]) {
+            let idempotency_key = idempotency_key.map(str::to_string);
+            let is_key = move |key: &str| idempotency_key.as_deref().is_none_or(|k| k == key);
+            self.dynamodb_client
+                .expect_extract_notification_outbox()
+                .withf({
+                    let is_key = is_key.clone();
+                    move |playlist_id, key| playlist_id == "test_playlist" && is_key(key)
+                })
+                .returning(|_, _| Ok(None));
+            self.dynamodb_client
+                .expect_create_notification_outbox()
+                .withf({
+                    let is_key = is_key.clone();
+                    move |playlist_id, outbox| {
+                        playlist_id == "test_playlist"
+                            && is_key(&outbox.idempotency_key)
+                            && outbox.delivered_targets.is_empty()
+                    }
+                })
+                .times(1)
+                .returning(|_, _| Ok(()));
+            let delivered_targets = delivered_targets
+                .iter()
+                .map(|target| target.to_string())
+                .collect::<Vec<String>>();
+            self.dynamodb_client
+                .expect_add_notification_outbox_delivered_target()
+                .times(delivered_targets.len())
+                .withf(move |playlist_id, key, target_key| {
+                    playlist_id == "test_playlist"
+                        && is_key(key)
+                        && delivered_targets.iter().any(|t| t == target_key)
+                })
+                .returning(|_, _, _| Ok(()));
+        }
+
+        async fn build(
+            mut self,
+        ) -> SpotifyPlaylistNotificationProcesser<
+            MockDynamoDBClientTrait,
+            MockSpotifyClientTrait,
+            MockNotifierTrait,
+        > {
+            let state = self.state;
+            self.dynamodb_client
+                .expect_extract_playlist_state()
+                .returning(move |_| Ok(state.clone()));
+            let user_master = self.user_master;
+            self.dynamodb_client
+                .expect_extract_user_master()
+                .returning(move || Ok(user_master()));
+            let playlist_configs = self.playlist_configs;
+            self.dynamodb_client
+                .expect_extract_playlist_configs()
+                .returning(move || Ok(playlist_configs.clone()));
+            self.spotify_client
+                .expect_get_spotify_playlist()
+                .returning(|_| Ok(SpotifyPlaylistResponse::new_test_data()));
+            self.spotify_client
+                .expect_list_all_spotify_playlist_tracks()
+                .returning(|_| Ok(SpotifyPlaylistTracksResponse::new_test_data()));
+            self.spotify_client
+                .expect_get_next_spotify_refresh_token()
+                .return_const(None);
+            let mut processer = SpotifyPlaylistNotificationProcesser::init(
+                self.dynamodb_client,
+                self.spotify_client,
+                self.notifier,
+            )
+            .await
+            .unwrap();
+            processer.now = TEST_NOW;
+            processer
+        }
+    }
+
+    // User 1が休止中のユーザーマスタ
+    fn paused_user_master() -> UserMaster {
+        let mut user_master = UserMaster::new_test_data();
+        user_master.users[0].paused = true;
+        user_master
+    }
+
+    #[tokio::test]
+    async fn test_playlist_state_not_found() {
+        let mut processer = TestProcesser::new(None);
+        processer
+            .dynamodb_client
+            .expect_update_playlist_state()
+            .with(
+                eq("test_playlist"),
+                eq(PlaylistState {
+                    turn: Some(Turn::new_test_data("spotify_user_1", 1)),
+                    ..PlaylistState::new_test_data("snapshot_2", &["track_1", "track_2"], 1)
+                }),
+            )
+            .returning(|_, _| Ok(()));
+        processer.notifier.expect_notify().never();
+        processer
+            .build()
+            .await
+            .run(&LambdaCommand::Check { playlist_id: None })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_added_track() {
+        let mut processer = TestProcesser::new(Some(PlaylistState::new_test_data(
+            "snapshot_1",
+            &["track_1"],
+            1,
+        )));
+        processer
+            .dynamodb_client
+            .expect_update_playlist_state()
+            .with(
+                eq("test_playlist"),
+                eq(PlaylistState {
+                    turn: Some(Turn::new_test_data("spotify_user_1", 1)),
+                    ..PlaylistState::new_test_data("snapshot_2", &["track_1", "track_2"], 2)
+                }),
+            )
+            .returning(|_, _| Ok(()));
+        processer.expect_new_outbox(None, &["discord:test_channel#0"]);
+        processer
+            .notifier
+            .expect_notify()
+            .withf(|target, notification, _| {
+                *target
+                    == NotificationTarget::Discord {
+                        channel_id: "test_channel".to_string(),
+                    }
+                    && notification.added_tracks.len() == 1
+                    && notification.added_tracks[0].track_id == "track_2"
+                    && notification.added_tracks[0].added_by.display_name() == "User 2"
+                    && notification.removed_tracks.is_empty()
+                    && notification.moved_tracks.is_empty()
+                    && notification
+                        .next_user
+                        .as_ref()
+                        .and_then(|u| u.discord_user_id.as_deref())
+                        == Some("discord_user_1")
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        processer
+            .build()
+            .await
+            .run(&LambdaCommand::Check { playlist_id: None })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dry_run() {
+        let mut processer = TestProcesser::new(Some(PlaylistState::new_test_data(
+            "snapshot_1",
+            &["track_1"],
+            1,
+        )));
+        // 状態もoutboxも更新しない
+        processer
+            .dynamodb_client
+            .expect_update_playlist_state()
+            .never();
+        processer
+            .dynamodb_client
+            .expect_create_notification_outbox()
+            .never();
+        // Spotifyが新しいリフレッシュトークンを発行しても保存しない
+        processer
+            .dynamodb_client
+            .expect_update_spotify_refresh_token()
+            .never();
+        processer
+            .spotify_client
+            .expect_get_next_spotify_refresh_token()
+            .return_const(Some("new_refresh_token".to_string()));
+        processer.notifier.expect_notify().never();
+        let response = processer
+            .build()
+            .await
+            .run(&LambdaCommand::DryRun { playlist_id: None })
+            .await
+            .unwrap();
+        assert_eq!(response.dry_run.len(), 1);
+        assert_eq!(response.dry_run[0].playlist_id, "test_playlist");
+        let messages = &response.dry_run[0].discord_messages;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].embeds.len(), 1);
+        assert!(messages[0].content.contains("<@discord_user_1>"));
+    }
+
+    #[tokio::test]
+    async fn test_last_notified_track_removed() {
+        let mut processer = TestProcesser::new(Some(PlaylistState::new_test_data(
+            "snapshot_1",
+            &["track_1", "removed_track"],
+            1,
+        )));
+        processer
+            .dynamodb_client
+            .expect_update_playlist_state()
+            .with(
+                eq("test_playlist"),
+                eq(PlaylistState {
+                    turn: Some(Turn::new_test_data("spotify_user_1", 1)),
+                    ..PlaylistState::new_test_data("snapshot_2", &["track_1", "track_2"], 2)
+                }),
+            )
+            .returning(|_, _| Ok(()));
+        processer.expect_new_outbox(None, &["discord:test_channel#0", "discord:test_channel#1"]);
+        processer
+            .notifier
+            .expect_notify()
+            .withf(|_, notification, _| {
+                notification.added_tracks.len() == 1
+                    && notification.added_tracks[0].track_id == "track_2"
+                    && notification.removed_tracks.len() == 1
+                    && notification.removed_tracks[0].track_id == "removed_track"
+                    && notification.removed_tracks[0].added_by.display_name() == "User 2"
+                    && notification.moved_tracks.is_empty()
+            })
+            .times(2)
+            .returning(|_, _, _| Ok(()));
+        processer
+            .build()
+            .await
+            .run(&LambdaCommand::Check { playlist_id: None })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_playlist_does_not_block_others() {
+        let mut processer = TestProcesser::new(Some(PlaylistState::new_test_data(
+            "snapshot_1",
+            &["track_1", "track_2"],
+            1,
+        )));
+        processer.playlist_configs = vec![
+            PlaylistConfig {
+                playlist_id: "broken_playlist".to_string(),
+                ..PlaylistConfig::new_test_data()
+            },
+            PlaylistConfig::new_test_data(),
+        ];
+        processer
+            .dynamodb_client
+            .expect_update_playlist_state()
+            .with(
+                eq("test_playlist"),
+                eq(PlaylistState {
+                    turn: Some(Turn::new_test_data("spotify_user_1", 1)),
+                    ..PlaylistState::new_test_data("snapshot_2", &["track_1", "track_2"], 2)
+                }),
+            )
+            .times(1)
+            .returning(|_, _| Ok(()));
+        processer
+            .spotify_client
+            .expect_get_spotify_playlist()
+            .with(eq("broken_playlist"))
+            .returning(|_| {
+                Err(SpotifyError::NotFound {
+                    message: "Resource not found".to_string(),
+                }
+                .into())
+            });
+        processer.notifier.expect_notify().never();
+        let err = processer
+            .build()
+            .await
+            .run(&LambdaCommand::Check { playlist_id: None })
+            .await
+            .unwrap_err();
+        let AppError::FailedPlaylists(failed_playlists) = &err else {
+            panic!("unexpected error: {err}");
+        };
+        assert_eq!(failed_playlists.len(), 1);
+        assert_eq!(failed_playlists[0].0, "broken_playlist");
+        assert!(matches!(
+            failed_playlists[0].1,
+            AppError::Spotify(SpotifyError::NotFound { .. })
+        ));
+        // 存在しないプレイリストは再実行しても成功しない
+        assert!(!err.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_snapshot_skips_playlist() {
+        let mut processer = TestProcesser::new(Some(PlaylistState::new_test_data(
+            "snapshot_2",
+            &["track_1", "track_2"],
+            2,
+        )));
+        processer
+            .dynamodb_client
+            .expect_update_playlist_state()
+            .never();
+        processer
+            .spotify_client
+            .expect_list_all_spotify_playlist_tracks()
+            .never();
+        processer.notifier.expect_notify().never();
+        processer
+            .build()
+            .await
+            .run(&LambdaCommand::Check { playlist_id: None })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_turn_reminder() {
+        // User 1の番が4日前に始まり、1回目のリマインドは送信済み
+        let turn = Turn {
+            started_at: TEST_NOW - 4 * 24 * 60 * 60,
+            reminder_count: 1,
+            ..Turn::new_test_data("spotify_user_1", 1)
+        };
+        let state = PlaylistState {
+            turn: Some(turn.clone()),
+            ..PlaylistState::new_test_data("snapshot_2", &["track_1", "track_2"], 2)
+        };
+        let mut processer = TestProcesser::new(Some(state.clone()));
+        processer.playlist_configs = vec![PlaylistConfig {
+            reminder: Some(ReminderConfig {
+                grace_period_days: 3,
+                interval_days: 1,
+                max_reminders: 2,
+            }),
+            ..PlaylistConfig::new_test_data()
+        }];
+        // 番と回数をキーにoutboxへ記録し、送信に成功してから状態を更新する
+        let mut sequence = mockall::Sequence::new();
+        processer.expect_new_outbox(Some("reminder:1:2"), &["discord:test_channel#0"]);
+        processer
+            .notifier
+            .expect_notify()
+            .withf(|_, notification, _| {
+                notification.reminder
+                    == Some(TurnReminder {
+                        number: 2,
+                        elapsed_days: 4,
+                        is_last: true,
+                    })
+                    && notification
+                        .next_user
+                        .as_ref()
+                        .is_some_and(|u| u.display_name() == "User 1")
+            })
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_, _, _| Ok(()));
+        processer
+            .dynamodb_client
+            .expect_update_playlist_state()
+            .with(
+                eq("test_playlist"),
+                eq(PlaylistState {
+                    turn: Some(Turn {
+                        reminder_count: 2,
+                        ..turn
+                    }),
+                    version: 3,
+                    ..state
+                }),
+            )
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_, _| Ok(()));
+        processer
+            .spotify_client
+            .expect_list_all_spotify_playlist_tracks()
+            .never();
+        processer
+            .build()
+            .await
+            .run(&LambdaCommand::Check { playlist_id: None })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_turn_reminder_after_changes() {
+        // User 1の番が4日前に始まった後、番ではないUser 2がtrack_2を追加した
+        let turn = Turn {
+            started_at: TEST_NOW - 4 * 24 * 60 * 60,
+            reminder_count: 1,
+            ..Turn::new_test_data("spotify_user_1", 1)
+        };
+        let mut processer = TestProcesser::new(Some(PlaylistState {
+            turn: Some(turn.clone()),
+            ..PlaylistState::new_test_data("snapshot_1", &["track_1"], 1)
+        }));
+        processer.playlist_configs = vec![PlaylistConfig {
+            reminder: Some(ReminderConfig {
+                grace_period_days: 3,
+                interval_days: 1,
+                max_reminders: 2,
+            }),
+            ..PlaylistConfig::new_test_data()
+        }];
+        // 同じ実行で曲の差分と番の人へのリマインドを通知してから、状態をまとめて保存する
+        let mut sequence = mockall::Sequence::new();
+        processer.expect_new_outbox(Some("reminder:1:2"), &["discord:test_channel#0"]);
+        processer.expect_new_outbox(None, &["discord:test_channel#0"]);
+        processer
+            .notifier
+            .expect_notify()
+            .withf(|_, notification, _| {
+                notification.added_tracks.len() == 1 && notification.reminder.is_none()
+            })
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_, _, _| Ok(()));
+        let changed_state = PlaylistState {
+            turn: Some(turn.clone()),
+            ..PlaylistState::new_test_data("snapshot_2", &["track_1", "track_2"], 2)
+        };
+        processer
+            .notifier
+            .expect_notify()
+            .withf(|_, notification, _| {
+                notification
+                    .reminder
+                    .as_ref()
+                    .is_some_and(|reminder| reminder.number == 2)
+            })
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_, _, _| Ok(()));
+        processer
+            .dynamodb_client
+            .expect_update_playlist_state()
+            .with(eq("test_playlist"), eq(changed_state.clone()))
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_, _| Ok(()));
+        processer
+            .dynamodb_client
+            .expect_update_playlist_state()
+            .with(
+                eq("test_playlist"),
+                eq(PlaylistState {
+                    turn: Some(Turn {
+                        reminder_count: 2,
+                        ..turn
+                    }),
+                    version: 3,
+                    ..changed_state
+                }),
+            )
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_, _| Ok(()));
+        processer
+            .build()
+            .await
+            .run(&LambdaCommand::Check { playlist_id: None })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_overdue_turn_skipped() {
+        // User 1の番が8日前に始まり、期限の7日を過ぎている
+        let started_at = TEST_NOW - 8 * 24 * 60 * 60;
+        let state = PlaylistState {
+            turn: Some(Turn {
+                started_at,
+                ..Turn::new_test_data("spotify_user_1", 3)
+            }),
+            ..PlaylistState::new_test_data("snapshot_2", &["track_1", "track_2"], 2)
+        };
+        let mut processer = TestProcesser::new(Some(state.clone()));
+        processer.playlist_configs = vec![PlaylistConfig {
+            reminder: Some(ReminderConfig {
+                grace_period_days: 3,
+                interval_days: 1,
+                max_reminders: 3,
+            }),
+            turn_deadline_days: Some(7),
+            ..PlaylistConfig::new_test_data()
+        }];
+        // 期限を過ぎた場合はリマインドせずにスキップを通知し、送信に成功してから状態を更新する
+        let mut sequence = mockall::Sequence::new();
+        processer.expect_new_outbox(Some("skip:3"), &["discord:test_channel#0"]);
+        processer
+            .notifier
+            .expect_notify()
+            .withf(|_, notification, _| {
+                notification.reminder.is_none()
+                    && notification
+                        .skipped_user
+                        .as_ref()
+                        .is_some_and(|u| u.display_name() == "User 1")
+                    && notification
+                        .next_user
+                        .as_ref()
+                        .is_some_and(|u| u.display_name() == "User 2")
+            })
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_, _, _| Ok(()));
+        processer
+            .dynamodb_client
+            .expect_update_playlist_state()
+            .with(
+                eq("test_playlist"),
+                eq(PlaylistState {
+                    turn: Some(Turn::new_test_data("spotify_user_2", 4)),
+                    version: 3,
+                    ..state
+                }),
+            )
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_, _| Ok(()));
+        processer
+            .dynamodb_client
+            .expect_record_skipped_turn()
+            .with(
+                eq("test_playlist"),
+                eq(SkippedTurn {
+                    spotify_user_id: "spotify_user_1".to_string(),
+                    number: 3,
+                    started_at: Some(started_at),
+                    skipped_at: TEST_NOW,
+                    reason: SkipReason::Overdue,
+                }),
+            )
+            .times(1)
+            .returning(|_, _| Ok(()));
+        processer
+            .spotify_client
+            .expect_list_all_spotify_playlist_tracks()
+            .never();
+        processer
+            .build()
+            .await
+            .run(&LambdaCommand::Check { playlist_id: None })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_overdue_turn_skipped_after_changes() {
+        // User 1の番が8日前に始まって期限の7日を過ぎた後、番ではないUser 2がtrack_2を追加した
+        let started_at = TEST_NOW - 8 * 24 * 60 * 60;
+        let turn = Turn {
+            started_at,
+            ..Turn::new_test_data("spotify_user_1", 3)
+        };
+        let mut processer = TestProcesser::new(Some(PlaylistState {
+            turn: Some(turn.clone()),
+            ..PlaylistState::new_test_data("snapshot_1", &["track_1"], 1)
+        }));
+        processer.playlist_configs = vec![PlaylistConfig {
+            turn_deadline_days: Some(7),
+            ..PlaylistConfig::new_test_data()
+        }];
+        // 同じ実行で曲の差分と期限切れの番のスキップを通知してから、状態をまとめて保存する
+        let mut sequence = mockall::Sequence::new();
+        processer.expect_new_outbox(Some("skip:3"), &["discord:test_channel#0"]);
+        processer.expect_new_outbox(None, &["discord:test_channel#0"]);
+        processer
+            .notifier
+            .expect_notify()
+            .withf(|_, notification, _| {
+                notification.added_tracks.len() == 1 && notification.skipped_user.is_none()
+            })
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_, _, _| Ok(()));
+        let changed_state = PlaylistState {
+            turn: Some(turn),
+            ..PlaylistState::new_test_data("snapshot_2", &["track_1", "track_2"], 2)
+        };
+        processer
+            .notifier
+            .expect_notify()
+            .withf(|_, notification, _| {
+                notification
+                    .skipped_user
+                    .as_ref()
+                    .is_some_and(|u| u.display_name() == "User 1")
+                    && notification
+                        .next_user
+                        .as_ref()
+                        .is_some_and(|u| u.display_name() == "User 2")
+            })
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_, _, _| Ok(()));
+        processer
+            .dynamodb_client
+            .expect_update_playlist_state()
+            .with(eq("test_playlist"), eq(changed_state.clone()))
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_, _| Ok(()));
+        processer
+            .dynamodb_client
+            .expect_record_skipped_turn()
+            .with(
+                eq("test_playlist"),
+                eq(SkippedTurn {
+                    spotify_user_id: "spotify_user_1".to_string(),
+                    number: 3,
+                    started_at: Some(started_at),
+                    skipped_at: TEST_NOW,
+                    reason: SkipReason::Overdue,
+                }),
+            )
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_, _| Ok(()));
+        processer
+            .dynamodb_client
+            .expect_update_playlist_state()
+            .with(
+                eq("test_playlist"),
+                eq(PlaylistState {
+                    turn: Some(Turn::new_test_data("spotify_user_2", 4)),
+                    version: 3,
+                    ..changed_state
+                }),
+            )
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_, _| Ok(()));
+        processer
+            .build()
+            .await
+            .run(&LambdaCommand::Check { playlist_id: None })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_paused_turn_holder_is_passed() {
+        let state = PlaylistState {
+            turn: Some(Turn::new_test_data("spotify_user_1", 1)),
+            ..PlaylistState::new_test_data("snapshot_2", &["track_1", "track_2"], 2)
+        };
+        let mut processer = TestProcesser::new(Some(state.clone()));
+        processer.user_master = paused_user_master;
+        processer
+            .dynamodb_client
+            .expect_update_playlist_state()
+            .with(
+                eq("test_playlist"),
+                eq(PlaylistState {
+                    turn: Some(Turn::new_test_data("spotify_user_2", 2)),
+                    version: 3,
+                    ..state
+                }),
+            )
+            .times(1)
+            .returning(|_, _| Ok(()));
+        processer.expect_new_outbox(Some("skip:1"), &["discord:test_channel#0"]);
+        processer
+            .dynamodb_client
+            .expect_record_skipped_turn()
+            .withf(|_, skipped_turn| {
+                skipped_turn.spotify_user_id == "spotify_user_1"
+                    && skipped_turn.reason == SkipReason::Away
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        processer
+            .notifier
+            .expect_notify()
+            .withf(|_, notification, _| {
+                notification
+                    .next_user
+                    .as_ref()
+                    .is_some_and(|u| u.display_name() == "User 2")
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        processer
+            .build()
+            .await
+            .run(&LambdaCommand::Check { playlist_id: None })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_away_turn_holder_is_passed_when_others_add() {
+        // 休止中のUser 1の番のまま、User 2がtrack_2を追加した
+        let started_at = TEST_NOW - 2 * 24 * 60 * 60;
+        let mut processer = TestProcesser::new(Some(PlaylistState {
+            turn: Some(Turn {
+                started_at,
+                ..Turn::new_test_data("spotify_user_1", 3)
+            }),
+            ..PlaylistState::new_test_data("snapshot_1", &["track_1"], 1)
+        }));
+        processer.user_master = paused_user_master;
+        processer.expect_new_outbox(None, &["discord:test_channel#0"]);
+        processer
+            .dynamodb_client
+            .expect_record_skipped_turn()
+            .with(
+                eq("test_playlist"),
+                eq(SkippedTurn {
+                    spotify_user_id: "spotify_user_1".to_string(),
+                    number: 3,
+                    started_at: Some(started_at),
+                    skipped_at: TEST_NOW,
+                    reason: SkipReason::Away,
+                }),
+            )
+            .times(1)
+            .returning(|_, _| Ok(()));
+        processer
+            .dynamodb_client
+            .expect_update_playlist_state()
+            .with(
+                eq("test_playlist"),
+                eq(PlaylistState {
+                    turn: Some(Turn::new_test_data("spotify_user_2", 4)),
+                    ..PlaylistState::new_test_data("snapshot_2", &["track_1", "track_2"], 2)
+                }),
+            )
+            .times(1)
+            .returning(|_, _| Ok(()));
+        // 次の人として休止中のUser 1ではなくUser 2に通知する
+        processer
+            .notifier
+            .expect_notify()
+            .withf(|_, notification, _| {
+                notification
+                    .next_user
+                    .as_ref()
+                    .is_some_and(|u| u.display_name() == "User 2")
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        processer
+            .build()
+            .await
+            .run(&LambdaCommand::Check { playlist_id: None })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_away_turn_holder_is_passed_when_tracks_removed() {
+        // 休止中のUser 1の番のまま、曲が削除されただけで追加はされていない
+        let started_at = TEST_NOW - 2 * 24 * 60 * 60;
+        let turn = Turn {
+            started_at,
+            ..Turn::new_test_data("spotify_user_1", 3)
+        };
+        let mut processer = TestProcesser::new(Some(PlaylistState {
+            turn: Some(turn.clone()),
+            ..PlaylistState::new_test_data(
+                "snapshot_1",
+                &["track_1", "track_2", "removed_track"],
+                1,
+            )
+        }));
+        processer.user_master = paused_user_master;
+        // 同じ実行で曲の削除と不在の人の番のスキップを通知してから、状態をまとめて保存する
+        let mut sequence = mockall::Sequence::new();
+        processer.expect_new_outbox(Some("skip:3"), &["discord:test_channel#0"]);
+        processer.expect_new_outbox(None, &["discord:test_channel#0"]);
+        processer
+            .notifier
+            .expect_notify()
+            .withf(|_, notification, _| {
+                notification.removed_tracks.len() == 1 && notification.next_user.is_none()
+            })
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_, _, _| Ok(()));
+        let changed_state = PlaylistState {
+            turn: Some(turn),
+            ..PlaylistState::new_test_data("snapshot_2", &["track_1", "track_2"], 2)
+        };
+        processer
+            .notifier
+            .expect_notify()
+            .withf(|_, notification, _| {
+                notification
+                    .skipped_user
+                    .as_ref()
+                    .is_some_and(|u| u.display_name() == "User 1")
+                    && notification
+                        .next_user
+                        .as_ref()
+                        .is_some_and(|u| u.display_name() == "User 2")
+            })
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_, _, _| Ok(()));
+        processer
+            .dynamodb_client
+            .expect_update_playlist_state()
+            .with(eq("test_playlist"), eq(changed_state.clone()))
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_, _| Ok(()));
+        processer
+            .dynamodb_client
+            .expect_record_skipped_turn()
+            .with(
+                eq("test_playlist"),
+                eq(SkippedTurn {
+                    spotify_user_id: "spotify_user_1".to_string(),
+                    number: 3,
+                    started_at: Some(started_at),
+                    skipped_at: TEST_NOW,
+                    reason: SkipReason::Away,
+                }),
+            )
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_, _| Ok(()));
+        processer
+            .dynamodb_client
+            .expect_update_playlist_state()
+            .with(
+                eq("test_playlist"),
+                eq(PlaylistState {
+                    turn: Some(Turn::new_test_data("spotify_user_2", 4)),
+                    version: 3,
+                    ..changed_state
+                }),
+            )
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_, _| Ok(()));
+        processer
+            .build()
+            .await
+            .run(&LambdaCommand::Check { playlist_id: None })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_notification_target_does_not_block_others() {
+        let mut processer = TestProcesser::new(Some(PlaylistState::new_test_data(
+            "snapshot_1",
+            &["track_1"],
+            1,
+        )));
+        let slack = NotificationTarget::Slack {
+            webhook_url: "https://hooks.slack.com/services/test".to_string(),
+        };
+        processer.playlist_configs = vec![PlaylistConfig {
+            notification_targets: vec![
+                NotificationTarget::Discord {
+                    channel_id: "test_channel".to_string(),
+                },
+                slack.clone(),
+            ],
+            ..PlaylistConfig::new_test_data()
+        }];
+        processer
+            .dynamodb_client
+            .expect_update_playlist_state()
+            .never();
+        // 成功した通知先だけを通知済みとして記録する
+        processer.expect_new_outbox(None, &[&(slack.key() + "#0")]);
+        processer
+            .notifier
+            .expect_notify()
+            .withf(|target, _, _| matches!(target, NotificationTarget::Discord { .. }))
+            .times(1)
+            .returning(|_, _, _| {
+                Err(DiscordError::RateLimited {
+                    retry_after: 60.0,
+                    global: false,
+                }
+                .into())
+            });
+        processer
+            .notifier
+            .expect_notify()
+            .withf(|target, _, _| matches!(target, NotificationTarget::Slack { .. }))
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        let err = processer
+            .build()
+            .await
+            .run(&LambdaCommand::Check { playlist_id: None })
+            .await
+            .unwrap_err();
+        assert!(err.is_retryable());
+    }
+
+    // 2つのプレイリストが同じ宛先にメールで通知する設定
+    fn email_playlist_configs() -> Vec<PlaylistConfig> {
+        ["test_playlist", "other_playlist"]
+            .into_iter()
+            .map(|playlist_id| PlaylistConfig {
+                playlist_id: playlist_id.to_string(),
+                notification_targets: vec![NotificationTarget::Email {
+                    to: vec!["member@example.com".to_string()],
+                }],
+                ..PlaylistConfig::new_test_data()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_email_digest() {
+        let mut processer = TestProcesser::new(Some(PlaylistState::new_test_data(
+            "snapshot_1",
+            &["track_1"],
+            1,
+        )));
+        processer.playlist_configs = email_playlist_configs();
+        processer
+            .dynamodb_client
+            .expect_extract_notification_outbox()
+            .times(2)
+            .returning(|_, _| Ok(None));
+        processer
+            .dynamodb_client
+            .expect_create_notification_outbox()
+            .times(2)
+            .returning(|_, _| Ok(()));
+        processer.notifier.expect_notify().never();
+        // 実行の最後に2つのプレイリストの通知を1通のメールにまとめて送り、送れてから記録する
+        let mut sequence = mockall::Sequence::new();
+        processer
+            .notifier
+            .expect_notify_digest()
+            .withf(|target, notifications| {
+                *target
+                    == NotificationTarget::Email {
+                        to: vec!["member@example.com".to_string()],
+                    }
+                    && notifications
+                        .iter()
+                        .map(|n| n.playlist_id.as_str())
+                        .eq(["test_playlist", "other_playlist"])
+            })
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_, _| Ok(()));
+        processer
+            .dynamodb_client
+            .expect_add_notification_outbox_delivered_target()
+            .with(
+                in_iter(["test_playlist", "other_playlist"]),
+                always(),
+                eq("email:member@example.com#0"),
+            )
+            .times(2)
+            .in_sequence(&mut sequence)
+            .returning(|_, _, _| Ok(()));
+        processer
+            .dynamodb_client
+            .expect_update_playlist_state()
+            .times(2)
+            .in_sequence(&mut sequence)
+            .returning(|_, _| Ok(()));
+        processer
+            .build()
+            .await
+            .run(&LambdaCommand::Check { playlist_id: None })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_email_digest() {
+        let mut processer = TestProcesser::new(Some(PlaylistState::new_test_data(
+            "snapshot_1",
+            &["track_1"],
+            1,
+        )));
+        processer.playlist_configs = email_playlist_configs();
+        processer
+            .dynamodb_client
+            .expect_extract_notification_outbox()
+            .returning(|_, _| Ok(None));
+        processer
+            .dynamodb_client
+            .expect_create_notification_outbox()
+            .returning(|_, _| Ok(()));
+        processer
+            .notifier
+            .expect_notify_digest()
+            .times(1)
+            .returning(|_, _| Err(AppError::Io(std::io::Error::other("connection reset"))));
+        // 送れなかった場合は状態を更新せず、次回の実行で同じ差分から送り直す
+        processer
+            .dynamodb_client
+            .expect_add_notification_outbox_delivered_target()
+            .never();
+        processer
+            .dynamodb_client
+            .expect_update_playlist_state()
+            .never();
+        let err = processer
+            .build()
+            .await
+            .run(&LambdaCommand::Check { playlist_id: None })
+            .await
+            .unwrap_err();
+        let AppError::FailedPlaylists(failed_playlists) = &err else {
+            panic!("unexpected error: {err}");
+        };
+        assert_eq!(failed_playlists.len(), 1);
+        assert_eq!(failed_playlists[0].0, "test_playlist,other_playlist");
+        assert!(err.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn test_outbox_skips_delivered_targets() {
+        let mut processer = TestProcesser::new(Some(PlaylistState::new_test_data(
+            "snapshot_1",
+            &["track_1"],
+            1,
+        )));
+        // 前回の実行で通知した後、状態の更新に失敗していた
+        processer
+            .dynamodb_client
+            .expect_extract_notification_outbox()
+            .returning(|_, idempotency_key| {
+                Ok(Some(NotificationOutbox {
+                    idempotency_key: idempotency_key.to_string(),
+                    payload: "{}".to_string(),
+                    delivered_targets: vec!["discord:test_channel#0".to_string()],
+                    created_at: 1_700_000_000,
+                }))
+            });
+        processer
+            .dynamodb_client
+            .expect_create_notification_outbox()
+            .never();
+        processer
+            .dynamodb_client
+            .expect_update_playlist_state()
+            .with(
+                eq("test_playlist"),
+                eq(PlaylistState {
+                    turn: Some(Turn::new_test_data("spotify_user_1", 1)),
+                    ..PlaylistState::new_test_data("snapshot_2", &["track_1", "track_2"], 2)
+                }),
+            )
+            .times(1)
+            .returning(|_, _| Ok(()));
+        processer.notifier.expect_notify().never();
+        processer
+            .build()
+            .await
+            .run(&LambdaCommand::Check { playlist_id: None })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_outbox_skips_delivered_pages() {
+        let mut processer = TestProcesser::new(Some(PlaylistState::new_test_data(
+            "snapshot_1",
+            &["track_1", "removed_track"],
+            1,
+        )));
+        // 前回の実行では2通のうち1通目だけ送れていた
+        processer
+            .dynamodb_client
+            .expect_extract_notification_outbox()
+            .returning(|_, idempotency_key| {
+                Ok(Some(NotificationOutbox {
+                    idempotency_key: idempotency_key.to_string(),
+                    payload: "{}".to_string(),
+                    delivered_targets: vec!["discord:test_channel#0".to_string()],
+                    created_at: 1_700_000_000,
+                }))
+            });
+        processer
+            .dynamodb_client
+            .expect_create_notification_outbox()
+            .never();
+        processer
+            .dynamodb_client
+            .expect_add_notification_outbox_delivered_target()
+            .with(eq("test_playlist"), always(), eq("discord:test_channel#1"))
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        processer
+            .dynamodb_client
+            .expect_update_playlist_state()
+            .times(1)
+            .returning(|_, _| Ok(()));
+        processer
+            .notifier
+            .expect_notify()
+            .with(always(), always(), eq(1))
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        processer
+            .build()
+            .await
+            .run(&LambdaCommand::Check { playlist_id: None })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_update_is_skipped() {
+        let mut processer = TestProcesser::new(Some(PlaylistState::new_test_data(
+            "snapshot_1",
+            &["track_1", "track_2"],
+            1,
+        )));
+        // 別の処理が先に状態とリフレッシュトークンを更新していた
+        processer
+            .dynamodb_client
+            .expect_update_playlist_state()
+            .with(
+                eq("test_playlist"),
+                eq(PlaylistState {
+                    turn: Some(Turn::new_test_data("spotify_user_1", 1)),
+                    ..PlaylistState::new_test_data("snapshot_2", &["track_1", "track_2"], 2)
+                }),
+            )
+            .times(1)
+            .returning(|playlist_id, _| {
+                Err(AppError::Conflict(format!("playlist_state {playlist_id}")))
+            });
+        processer
+            .dynamodb_client
+            .expect_update_spotify_refresh_token()
+            .with(eq("refresh_token_1"), eq("refresh_token_2"))
+            .times(1)
+            .returning(|_, _| Err(AppError::Conflict("spotify_refresh_token".to_string())));
+        processer
+            .spotify_client
+            .expect_get_spotify_refresh_token()
+            .return_const("refresh_token_1".to_string());
+        processer
+            .spotify_client
+            .expect_get_next_spotify_refresh_token()
+            .return_const(Some("refresh_token_2".to_string()));
+        processer.notifier.expect_notify().never();
+        processer
+            .build()
+            .await
+            .run(&LambdaCommand::Check { playlist_id: None })
+            .await
+            .unwrap();
+    }
+    #[tokio::test]
+    async fn test_run_lock_not_acquired() {
+        let mut mock_dynamodb_client = MockDynamoDBClientTrait::new();
+        mock_dynamodb_client
+            .expect_acquire_run_lock()
+            .with(eq("owner_2"), eq(RUN_LOCK_LEASE))
+            .times(1)
+            .returning(|_, _| Ok(false));
+        mock_dynamodb_client.expect_release_run_lock().never();
+        let result = with_run_lock(&mock_dynamodb_client, "owner_2", async {
+            panic!("process must not run without the lock")
+        })
+        .await;
+        assert!(result.unwrap().skipped);
+    }
+
+    #[tokio::test]
+    async fn test_run_lock_released_after_failure() {
+        let mut mock_dynamodb_client = MockDynamoDBClientTrait::new();
+        mock_dynamodb_client
+            .expect_acquire_run_lock()
+            .times(1)
+            .returning(|_, _| Ok(true));
+        mock_dynamodb_client
+            .expect_release_run_lock()
+            .with(eq("owner_1"))
+            .times(1)
+            .returning(|_| Ok(()));
+        let result = with_run_lock(&mock_dynamodb_client, "owner_1", async {
+            Err(AppError::Config("broken".to_string()))
+        })
+        .await;
+        assert!(matches!(result, Err(AppError::Config(_))));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_refresh_token_alerted_once() {
+        let invalid_grant = || {
+            AppError::Spotify(SpotifyError::InvalidGrant {
+                message: "invalid_grant: Refresh token revoked".to_string(),
+            })
+        };
+        let mut mock_dynamodb_client = MockDynamoDBClientTrait::new();
+        let mut recorded = false;
+        mock_dynamodb_client
+            .expect_mark_spotify_refresh_token_invalid()
+            .with(
+                eq("refresh_token"),
+                eq("invalid_grant: Refresh token revoked"),
+            )
+            .times(2)
+            .returning(move |_, _| {
+                let first = !recorded;
+                recorded = true;
+                Ok(first)
+            });
+        let mut mock_notifier = MockNotifierTrait::new();
+        mock_notifier
+            .expect_alert_admin()
+            .withf(|message| message.contains("auth login"))
+            .times(1)
+            .returning(|_| Ok(()));
+        for _ in 0..2 {
+            let error = alert_invalid_refresh_token(
+                &mock_dynamodb_client,
+                &mock_notifier,
+                "refresh_token",
+                invalid_grant(),
+            )
+            .await;
+            assert!(matches!(
+                error,
+                AppError::Spotify(SpotifyError::InvalidGrant { .. })
+            ));
+            assert!(!error.is_retryable());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_other_spotify_error_not_alerted() {
+        let mut mock_dynamodb_client = MockDynamoDBClientTrait::new();
+        mock_dynamodb_client
+            .expect_mark_spotify_refresh_token_invalid()
+            .never();
+        let mut mock_notifier = MockNotifierTrait::new();
+        mock_notifier.expect_alert_admin().never();
+        let error = alert_invalid_refresh_token(
+            &mock_dynamodb_client,
+            &mock_notifier,
+            "refresh_token",
+            AppError::Spotify(SpotifyError::Server {
+                status: 503,
+                message: String::new(),
+            }),
+        )
+        .await;
+        assert!(error.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn test_skip_turn() {
+        let state = PlaylistState {
+            turn: Some(Turn {
+                started_at: TEST_NOW - 24 * 60 * 60,
+                ..Turn::new_test_data("spotify_user_1", 3)
+            }),
+            ..PlaylistState::new_test_data("snapshot_2", &["track_1", "track_2"], 2)
+        };
+        let mut processer = TestProcesser::new(Some(state.clone()));
+        processer
+            .dynamodb_client
+            .expect_update_playlist_state()
+            .with(
+                eq("test_playlist"),
+                eq(PlaylistState {
+                    turn: Some(Turn::new_test_data("spotify_user_2", 4)),
+                    version: 3,
+                    ..state
+                }),
+            )
+            .times(1)
+            .returning(|_, _| Ok(()));
+        processer.expect_new_outbox(Some("skip:3"), &["discord:test_channel#0"]);
+        processer
+            .dynamodb_client
+            .expect_record_skipped_turn()
+            .with(
+                eq("test_playlist"),
+                eq(SkippedTurn {
+                    spotify_user_id: "spotify_user_1".to_string(),
+                    number: 3,
+                    started_at: Some(TEST_NOW - 24 * 60 * 60),
+                    skipped_at: TEST_NOW,
+                    reason: SkipReason::Manual,
+                }),
+            )
+            .times(1)
+            .returning(|_, _| Ok(()));
+        processer
+            .notifier
+            .expect_notify()
+            .withf(|_, notification, _| {
+                notification.added_tracks.is_empty()
+                    && notification
+                        .skipped_user
+                        .as_ref()
+                        .is_some_and(|u| u.display_name() == "User 1")
+                    && notification
+                        .next_user
+                        .as_ref()
+                        .is_some_and(|u| u.display_name() == "User 2")
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        processer
+            .build()
+            .await
+            .run(&LambdaCommand::SkipTurn {
+                playlist_id: "test_playlist".to_string(),
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_skip_turn_without_turn() {
+        // 番の記録がなければ、スキップする番を決められない
+        let mut processer = TestProcesser::new(Some(PlaylistState::new_test_data(
+            "snapshot_2",
+            &["track_1", "track_2"],
+            2,
+        )));
+        processer
+            .dynamodb_client
+            .expect_create_notification_outbox()
+            .never();
+        processer
+            .dynamodb_client
+            .expect_record_skipped_turn()
+            .never();
+        processer
+            .dynamodb_client
+            .expect_update_playlist_state()
+            .never();
+        processer.notifier.expect_notify().never();
+        let err = processer
+            .build()
+            .await
+            .run(&LambdaCommand::SkipTurn {
+                playlist_id: "test_playlist".to_string(),
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            AppError::Domain(DomainError::NoCurrentTurn { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_resend_last() {
+        let mut processer = TestProcesser::new(None);
+        processer.playlist_configs = vec![PlaylistConfig {
+            locale: "en".to_string(),
+            ..PlaylistConfig::new_test_data()
+        }];
+        processer
+            .dynamodb_client
+            .expect_extract_latest_notification_outbox()
+            .with(eq("test_playlist"))
+            .returning(|_| {
+                Ok(Some(NotificationOutbox {
+                    idempotency_key: "key".to_string(),
+                    payload: serde_json::to_string(&PlaylistNotification::new_test_data()).unwrap(),
+                    delivered_targets: vec!["discord:test_channel#0".to_string()],
+                    created_at: 1_700_000_000,
+                }))
+            });
+        processer
+            .dynamodb_client
+            .expect_update_playlist_state()
+            .never();
+        // 通知済みでも再送し、文言は現在の設定から作り直す
+        processer
+            .notifier
+            .expect_notify()
+            .withf(|_, notification, _| {
+                *notification
+                    == PlaylistNotification {
+                        templates: MessageTemplates::english(),
+                        ..PlaylistNotification::new_test_data()
+                    }
+            })
+            .times(2)
+            .returning(|_, _, _| Ok(()));
+        processer
+            .build()
+            .await
+            .run(&LambdaCommand::ResendLast {
+                playlist_id: "test_playlist".to_string(),
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unknown_playlist() {
+        let err = TestProcesser::new(None)
+            .build()
+            .await
+            .run(&LambdaCommand::Check {
+                playlist_id: Some("unknown_playlist".to_string()),
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::Config(_)));
+    }
+
+    #[tokio::test]
+    async fn test_no_next_user() {
+        let mut processer = TestProcesser::new(Some(PlaylistState::new_test_data(
+            "snapshot_1",
+            &["track_1"],
+            1,
+        )));
+        // track_2を追加したspotify_user_2がローテーションに含まれていない
+        processer.playlist_configs = vec![PlaylistConfig {
+            member_spotify_user_ids: vec!["spotify_user_1".to_string()],
+            ..PlaylistConfig::new_test_data()
+        }];
+        processer
+            .dynamodb_client
+            .expect_update_playlist_state()
+            .never();
+        processer.notifier.expect_notify().never();
+        let err = processer
+            .build()
+            .await
+            .run(&LambdaCommand::Check { playlist_id: None })
+            .await
+            .unwrap_err();
+        let AppError::FailedPlaylists(failed_playlists) = &err else {
+            panic!("unexpected error: {err}");
+        };
+        assert!(matches!(
+            &failed_playlists[0].1,
+            AppError::Domain(DomainError::NoNextUser { spotify_user_id })
+                if spotify_user_id == "spotify_user_2"
+        ));
+    }
+    #[test]
+    fn test_advance_turn() {
+        let rotation = UserMaster::new_test_data();
+        let state = PlaylistState::new_test_data("snapshot_2", &["track_1", "track_2"], 2);
+        let track_3 = |added_by: &str| TrackFingerprint {
+            track_id: "track_3".to_string(),
+            added_at: "2023-01-03T00:00:00Z".to_string(),
+            added_by: added_by.to_string(),
+        };
+        let turn = Turn {
+            started_at: 0,
+            ..Turn::new_test_data("spotify_user_1", 5)
+        };
+        // 番の人以外が追加しても番は進まない
+        assert_eq!(
+            advance_turn(
+                "test_playlist",
+                Some(&turn),
+                &state.tracks,
+                &[track_3("spotify_user_2")],
+                &rotation,
+                TEST_NOW,
+            )
+            .unwrap(),
+            Some(turn.clone())
+        );
+        // 番の人が追加すると次の人の番になる
+        assert_eq!(
+            advance_turn(
+                "test_playlist",
+                Some(&turn),
+                &state.tracks,
+                &[track_3("spotify_user_1")],
+                &rotation,
+                TEST_NOW,
+            )
+            .unwrap(),
+            Some(Turn::new_test_data("spotify_user_2", 6))
+        );
+        // 番の記録がない場合は最後に曲を追加した人の次の人
+        assert_eq!(
+            advance_turn(
+                "test_playlist",
+                None,
+                &state.tracks,
+                &[],
+                &rotation,
+                TEST_NOW
+            )
+            .unwrap(),
+            Some(Turn::new_test_data("spotify_user_1", 1))
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use serde::Serialize;
+
+use crate::{error::AppError, notifier::PlaylistNotification, template::render};
+
+#[derive(Serialize, Debug, PartialEq)]
+struct SlackWebhookRequest {
+    text: String,
+}
+
+impl SlackWebhookRequest {
+    fn from_notification(notification: &PlaylistNotification) -> Self {
+        let templates = &notification.templates;
+        let playlist = format!(
+            "<{}|{}>",
+            notification.playlist_url, notification.playlist_name
+        );
+        let mut message_lines = vec![
+            format!("*{}*", templates.title),
+            match (
+                &notification.reminder,
+                &notification.next_user,
+                &notification.skipped_user,
+            ) {
+                (Some(reminder), Some(user), _) => render(
+                    reminder.template(templates),
+                    &[
+                        ("playlist", &playlist),
+                        ("user", user.display_name()),
+                        ("days", &reminder.elapsed_days.to_string()),
+                    ],
+                ),
+                (_, _, Some(skipped_user)) => render(
+                    &templates.turn_skipped,
+                    &[
+                        ("playlist", &playlist),
+                        ("user", skipped_user.display_name()),
+                    ],
+                ),
+                _ => render(&templates.playlist_updated, &[("playlist", &playlist)]),
+            },
+        ];
+        if !notification.added_tracks.is_empty() {
+            message_lines.push(format!("*{}*", templates.added_tracks_heading));
+            for track in &notification.added_tracks {
+                message_lines.push(format!(
+                    "• {}",
+                    render(
+                        &templates.added_track,
+                        &[
+                            ("track", &format_link(&track.url, &track.name)),
+                            ("artists", &track.artists.join(", ")),
+                            ("added_by", track.added_by.display_name()),
+                        ],
+                    )
+                ));
+            }
+        }
+        if !notification.removed_tracks.is_empty() {
+            message_lines.push(format!("*{}*", templates.removed_tracks_heading));
+            for track in &notification.removed_tracks {
+                message_lines.push(format!(
+                    "• {}",
+                    render(
+                        &templates.removed_track,
+                        &[
+                            ("track", &track.url),
+                            ("added_by", track.added_by.display_name()),
+                        ],
+                    )
+                ));
+            }
+        }
+        if !notification.moved_tracks.is_empty() {
+            message_lines.push(format!("*{}*", templates.moved_tracks_heading));
+            for moved_track in &notification.moved_tracks {
+                message_lines.push(format!(
+                    "• {}",
+                    render(
+                        &templates.moved_track,
+                        &[
+                            ("track", &moved_track.track.url),
+                            ("from", &moved_track.from_position.to_string()),
+                            ("to", &moved_track.to_position.to_string()),
+                            ("added_by", moved_track.track.added_by.display_name()),
+                        ],
+                    )
+                ));
+            }
+        }
+        if (!notification.added_tracks.is_empty() || notification.skipped_user.is_some())
+            && let Some(next_user) = &notification.next_user
+        {
+            message_lines.push(format!("*{}*", templates.next_user_heading));
+            message_lines.push(next_user.display_name().to_string());
+        }
+        Self {
+            text: message_lines.join("\n"),
+        }
+    }
+}
+
+// ローカルファイルなどURLがない場合はリンクにしない
+fn format_link(url: &str, text: &str) -> String {
+    if url.is_empty() {
+        text.to_string()
+    } else {
+        format!("<{url}|{text}>")
+    }
+}
+
+#[derive(Default)]
+pub struct SlackClient {
+    client: reqwest::Client,
+}
+
+impl SlackClient {
+    pub fn new() -> Self {
+        Self {
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub async fn post(
+        &self,
+        webhook_url: &str,
+        notification: &PlaylistNotification,
+    ) -> Result<(), AppError> {
+        self.client
+            .post(webhook_url)
+            .json(&SlackWebhookRequest::from_notification(notification))
+            .send()
+            .await
+            // エラーメッセージにURLが含まれないようにする
+            .and_then(|response| response.error_for_status())
+            .map_err(reqwest::Error::without_url)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_notification() {
+        let request =
+            SlackWebhookRequest::from_notification(&PlaylistNotification::new_test_data());
+        assert_eq!(
+            request.text,
+            [
+                "*プレイリスト更新のお知らせ*",
+                "<https://open.spotify.com/playlist/test|Test Playlist>が更新されました！",
+                "*追加された曲*",
+                "• <https://open.spotify.com/track/track_1|Track 1> - Artist (追加した人: User 1)",
+                "*削除された曲*",
+                "• https://open.spotify.com/track/track_0 (追加した人: User 1)",
+                "*移動された曲*",
+                "• https://open.spotify.com/track/track_2 (3番目 → 1番目、追加した人: unknown_user)",
+                "*次の人*",
+                "User 2",
+            ]
+            .join("\n")
+        );
+    }
+}
@@ -1,17 +1,17 @@
-use std::{collections::HashMap, env};
+use std::{collections::HashMap, env, error::Error, fmt, time::Duration};
 
 use mockall::automock;
-use reqwest::header::{CONTENT_TYPE, HeaderMap};
-use serde::Deserialize;
+use reqwest::{RequestBuilder, Response, StatusCode, header::RETRY_AFTER};
+use serde::{Deserialize, de::DeserializeOwned};
 
-use crate::OpaqueError;
+use crate::{
+    error::{AppError, require_env},
+    playlist::UNAVAILABLE_TRACK_ID_PREFIX,
+};
 
 #[derive(Deserialize, Debug)]
 struct SpotifyTokenResponse {
     access_token: String,
-    token_type: String,
-    expires_in: i64,
-    scope: String,
     refresh_token: Option<String>,
 }
 
@@ -22,27 +22,88 @@ pub struct SpotifyUser {
 
 #[derive(Deserialize, Debug)]
 pub struct SpotifyExternalUrls {
+    // ローカルファイルの場合は空のオブジェクトになる
+    #[serde(default)]
     pub spotify: String,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct SpotifyPlaylistResponse {
     pub name: String,
+    pub snapshot_id: String,
     pub external_urls: SpotifyExternalUrls,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct SpotifyArtist {
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SpotifyImage {
+    pub url: String,
+    pub width: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SpotifyAlbum {
+    pub name: String,
+    pub images: Vec<SpotifyImage>,
+}
+
+// ローカルファイルはidがnull、ポッドキャストのエピソードはalbumとartistsがない
 #[derive(Deserialize, Debug)]
 pub struct SpotifyTrack {
-    pub id: String,
+    pub id: Option<String>,
+    #[serde(default)]
+    pub uri: String,
+    #[serde(default)]
     pub name: String,
-    pub external_urls: SpotifyExternalUrls,
+    #[serde(default)]
+    pub artists: Vec<SpotifyArtist>,
+    pub album: Option<SpotifyAlbum>,
+    #[serde(default)]
+    pub duration_ms: u64,
+    pub preview_url: Option<String>,
+    pub external_urls: Option<SpotifyExternalUrls>,
+}
+
+impl SpotifyTrack {
+    // 埋め込みのサムネイル用に、300px以上のうち最も小さい画像を選ぶ
+    pub fn thumbnail_url(&self) -> Option<&str> {
+        let images = &self.album.as_ref()?.images;
+        images
+            .iter()
+            .filter(|i| i.width.unwrap_or(0) >= 300)
+            .min_by_key(|i| i.width)
+            .or(images.first())
+            .map(|i| i.url.as_str())
+    }
+
+    pub fn url(&self) -> &str {
+        self.external_urls
+            .as_ref()
+            .map_or("", |urls| urls.spotify.as_str())
+    }
 }
 
 #[derive(Deserialize, Debug)]
 pub struct SpotifyPlaylistItem {
     pub added_at: String,
     pub added_by: SpotifyUser,
-    pub track: SpotifyTrack,
+    // 削除された曲や再生できない曲はnullになる
+    pub track: Option<SpotifyTrack>,
+}
+
+impl SpotifyPlaylistItem {
+    // 曲を識別するID。idがない場合はURI、曲自体がない場合は追加日時で代用する
+    pub fn track_id(&self) -> String {
+        match &self.track {
+            Some(SpotifyTrack { id: Some(id), .. }) => id.clone(),
+            Some(track) if !track.uri.is_empty() => track.uri.clone(),
+            _ => format!("{}{}", UNAVAILABLE_TRACK_ID_PREFIX, self.added_at),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -51,97 +112,274 @@ pub struct SpotifyPlaylistTracksResponse {
     pub items: Vec<SpotifyPlaylistItem>,
 }
 
-impl SpotifyPlaylistTracksResponse {
-    pub fn get_latest_track(&self) -> Option<&SpotifyPlaylistItem> {
-        self.items.last()
+// 一時的なエラーで再送する回数と、待つ時間の上限
+const MAX_RETRIES: u32 = 3;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+// https://developer.spotify.com/documentation/web-api/concepts/api-calls#response-status-codes
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum SpotifyErrorResponse {
+    // Web APIの形式
+    Regular {
+        error: SpotifyRegularError,
+    },
+    // 認可サーバー(accounts.spotify.com)の形式
+    Authentication {
+        error: String,
+        error_description: Option<String>,
+    },
+}
+
+#[derive(Deserialize, Debug)]
+struct SpotifyRegularError {
+    message: String,
+}
+
+#[derive(Debug)]
+pub enum SpotifyError {
+    // 401やトークンの更新失敗
+    Unauthorized { message: String },
+    // リフレッシュトークンが失効・取り消しされており、再認可が必要
+    InvalidGrant { message: String },
+    NotFound { message: String },
+    // 429: Retry-Afterヘッダーの秒数
+    RateLimited { retry_after: Option<u64> },
+    // 5xx
+    Server { status: u16, message: String },
+    Api { status: u16, message: String },
+    Request(reqwest::Error),
+}
+
+impl SpotifyError {
+    fn from_response(status: StatusCode, retry_after: Option<u64>, body: &str) -> Self {
+        let (message, authentication_error) =
+            match serde_json::from_str::<SpotifyErrorResponse>(body) {
+                Ok(SpotifyErrorResponse::Regular { error }) => (error.message, None),
+                Ok(SpotifyErrorResponse::Authentication {
+                    error,
+                    error_description: Some(description),
+                }) => (format!("{error}: {description}"), Some(error)),
+                Ok(SpotifyErrorResponse::Authentication { error, .. }) => {
+                    (error.clone(), Some(error))
+                }
+                Err(_) => (body.to_string(), None),
+            };
+        let is_authentication_error = authentication_error.is_some();
+        match status {
+            StatusCode::TOO_MANY_REQUESTS => Self::RateLimited { retry_after },
+            _ if authentication_error.as_deref() == Some("invalid_grant") => {
+                Self::InvalidGrant { message }
+            }
+            _ if status.is_server_error() => Self::Server {
+                status: status.as_u16(),
+                message,
+            },
+            // 認可サーバーはリフレッシュトークンが無効な場合に400を返す
+            StatusCode::UNAUTHORIZED => Self::Unauthorized { message },
+            _ if is_authentication_error => Self::Unauthorized { message },
+            StatusCode::NOT_FOUND => Self::NotFound { message },
+            _ => Self::Api {
+                status: status.as_u16(),
+                message,
+            },
+        }
     }
 
-    pub fn get_not_notified_tracks(
-        &self,
-        last_notified_track_id: &str,
-    ) -> Option<Vec<&SpotifyPlaylistItem>> {
-        let mut found = false;
-        let mut not_notified_tracks = Vec::new();
-        for item in self.items.iter().rev() {
-            if item.track.id == last_notified_track_id {
-                found = true;
-                break;
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::RateLimited { .. } | Self::Server { .. } => true,
+            Self::Request(e) => e.is_timeout() || e.is_connect(),
+            _ => false,
+        }
+    }
+
+    // 再送すべきエラーであれば待つ時間を返す
+    fn retry_delay(&self, attempt: u32) -> Option<Duration> {
+        let backoff = INITIAL_BACKOFF * 2u32.pow(attempt);
+        match self {
+            Self::RateLimited { retry_after } => {
+                let delay = retry_after.map(Duration::from_secs).unwrap_or(backoff);
+                (delay <= MAX_RETRY_AFTER).then_some(delay)
+            }
+            Self::Server { .. } => Some(backoff),
+            Self::Request(e) if e.is_timeout() || e.is_connect() => Some(backoff),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for SpotifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unauthorized { message } => write!(f, "spotify unauthorized: {message}"),
+            Self::InvalidGrant { message } => {
+                write!(f, "spotify refresh token is invalid: {message}")
+            }
+            Self::NotFound { message } => write!(f, "spotify not found: {message}"),
+            Self::RateLimited { retry_after } => {
+                write!(f, "spotify rate limited: retry after {retry_after:?}s")
             }
-            not_notified_tracks.push(item);
+            Self::Server { status, message } => {
+                write!(f, "spotify server error: {status} {message}")
+            }
+            Self::Api { status, message } => write!(f, "spotify api error: {status} {message}"),
+            Self::Request(e) => write!(f, "spotify request error: {e}"),
         }
-        // last_notified_track_idに該当するトラックが見つからなかった場合に全件通知されるのを防ぐ
-        if !found {
-            return None;
+    }
+}
+
+impl Error for SpotifyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Request(e) => Some(e),
+            _ => None,
         }
-        not_notified_tracks.reverse();
-        Some(not_notified_tracks)
     }
 }
 
+impl From<reqwest::Error> for SpotifyError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Request(e)
+    }
+}
+
+// 一時的なエラー(レート制限、5xx、タイムアウト)の場合は待ってから再送する
+async fn send_with_retry(request: impl Fn() -> RequestBuilder) -> Result<Response, SpotifyError> {
+    let mut attempt = 0;
+    loop {
+        let result = request().send().await.map_err(SpotifyError::from);
+        let error = match result {
+            Ok(response) if response.status().is_success() => return Ok(response),
+            Ok(response) => {
+                let status = response.status();
+                let retry_after = response
+                    .headers()
+                    .get(RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse().ok());
+                SpotifyError::from_response(status, retry_after, &response.text().await?)
+            }
+            Err(e) => e,
+        };
+        match error.retry_delay(attempt) {
+            Some(delay) if attempt < MAX_RETRIES => {
+                println!("{error}: retrying in {delay:?}");
+                attempt += 1;
+                tokio::time::sleep(delay).await;
+            }
+            _ => return Err(error),
+        }
+    }
+}
+
+async fn get_json<T: DeserializeOwned>(
+    request: impl Fn() -> RequestBuilder,
+) -> Result<T, AppError> {
+    Ok(send_with_retry(request)
+        .await?
+        .json()
+        .await
+        .map_err(SpotifyError::from)?)
+}
+
+// クライアントシークレットがない場合(PKCE)はclient_idをボディで送る
+async fn request_token(
+    client: &reqwest::Client,
+    mut params: HashMap<&'static str, String>,
+) -> Result<SpotifyTokenResponse, AppError> {
+    let client_id = require_env("SPOTIFY_CLIENT_ID")?;
+    let client_secret = env::var("SPOTIFY_CLIENT_SECRET").ok();
+    if client_secret.is_none() {
+        params.insert("client_id", client_id.clone());
+    }
+    get_json(|| {
+        let request = client.post("https://accounts.spotify.com/api/token");
+        let request = match &client_secret {
+            Some(client_secret) => request.basic_auth(&client_id, Some(client_secret)),
+            None => request,
+        };
+        request.form(&params)
+    })
+    .await
+}
+
 #[automock]
 pub trait SpotifyClientTrait {
     async fn get_spotify_playlist(
         &self,
         playlist_id: &str,
-    ) -> Result<SpotifyPlaylistResponse, OpaqueError>;
+    ) -> Result<SpotifyPlaylistResponse, AppError>;
     async fn list_all_spotify_playlist_tracks(
         &self,
         playlist_id: &str,
-    ) -> Result<SpotifyPlaylistTracksResponse, OpaqueError>;
+    ) -> Result<SpotifyPlaylistTracksResponse, AppError>;
+    // 初期化に使ったリフレッシュトークン
+    fn get_spotify_refresh_token(&self) -> &str;
     fn get_next_spotify_refresh_token(&self) -> &Option<String>;
 }
 
 pub struct SpotifyClient {
+    refresh_token: String,
     token_response: SpotifyTokenResponse,
 }
 
 impl SpotifyClient {
     async fn refresh_spotify_access_token(
         refresh_token: &str,
-    ) -> Result<SpotifyTokenResponse, OpaqueError> {
+    ) -> Result<SpotifyTokenResponse, AppError> {
         let client = reqwest::Client::new();
-        let mut headers = HeaderMap::new();
-        headers.append(CONTENT_TYPE, "application/x-www-form-urlencoded".parse()?);
         let mut params = HashMap::new();
         params.insert("grant_type", "refresh_token".to_string());
         // params.insert("refresh_token", env::var("SPOTIFY_REFRESH_TOKEN")?);
         params.insert("refresh_token", refresh_token.to_string());
-        let res = client
-            .post("https://accounts.spotify.com/api/token")
-            .basic_auth(
-                env::var("SPOTIFY_CLIENT_ID")?,
-                env::var("SPOTIFY_CLIENT_SECRET").ok(),
-            )
-            .form(&params)
-            .send()
-            .await?;
-        let res_body: SpotifyTokenResponse = res.json().await?;
-        Ok(res_body)
-    }
-
-    pub async fn init(refresh_token: &str) -> Result<Self, OpaqueError> {
+        request_token(&client, params).await
+    }
+
+    // 認可コードフロー(PKCE)で受け取ったコードをリフレッシュトークンに交換する
+    pub async fn exchange_authorization_code(
+        code: &str,
+        redirect_uri: &str,
+        code_verifier: &str,
+    ) -> Result<String, AppError> {
+        let client = reqwest::Client::new();
+        let mut params = HashMap::new();
+        params.insert("grant_type", "authorization_code".to_string());
+        params.insert("code", code.to_string());
+        params.insert("redirect_uri", redirect_uri.to_string());
+        params.insert("code_verifier", code_verifier.to_string());
+        let token_response = request_token(&client, params).await?;
+        if let Some(refresh_token) = token_response.refresh_token {
+            Ok(refresh_token)
+        } else {
+            Err(SpotifyError::Unauthorized {
+                message: "no refresh_token in token response".to_string(),
+            }
+            .into())
+        }
+    }
+
+    pub async fn init(refresh_token: &str) -> Result<Self, AppError> {
         let token_response = Self::refresh_spotify_access_token(refresh_token).await?;
-        Ok(Self { token_response })
+        Ok(Self {
+            refresh_token: refresh_token.to_string(),
+            token_response,
+        })
     }
 
     async fn get_spotify_playlist_tracks(
         &self,
         playlist_id: &str,
         url: Option<String>,
-    ) -> Result<SpotifyPlaylistTracksResponse, OpaqueError> {
+    ) -> Result<SpotifyPlaylistTracksResponse, AppError> {
         let client = reqwest::Client::new();
         let url = if let Some(url) = url {
             url.to_string()
         } else {
             format!("https://api.spotify.com/v1/playlists/{playlist_id}/tracks")
         };
-        let res = client
-            .get(url)
-            .bearer_auth(&self.get_access_token())
-            .send()
-            .await?;
-        let res_body: SpotifyPlaylistTracksResponse = res.json().await?;
-        Ok(res_body)
+        get_json(|| client.get(&url).bearer_auth(self.get_access_token())).await
     }
 
     fn get_access_token(&self) -> &str {
@@ -153,22 +391,16 @@ impl SpotifyClientTrait for SpotifyClient {
     async fn get_spotify_playlist(
         &self,
         playlist_id: &str,
-    ) -> Result<SpotifyPlaylistResponse, OpaqueError> {
+    ) -> Result<SpotifyPlaylistResponse, AppError> {
         let client = reqwest::Client::new();
         let url = format!("https://api.spotify.com/v1/playlists/{playlist_id}");
-        let res = client
-            .get(url)
-            .bearer_auth(&self.get_access_token())
-            .send()
-            .await?;
-        let res_body: SpotifyPlaylistResponse = res.json().await?;
-        Ok(res_body)
+        get_json(|| client.get(&url).bearer_auth(self.get_access_token())).await
     }
 
     async fn list_all_spotify_playlist_tracks(
         &self,
         playlist_id: &str,
-    ) -> Result<SpotifyPlaylistTracksResponse, OpaqueError> {
+    ) -> Result<SpotifyPlaylistTracksResponse, AppError> {
         let mut all_items = Vec::new();
         let mut next_url: Option<String> = None;
         loop {
@@ -187,6 +419,10 @@ impl SpotifyClientTrait for SpotifyClient {
         })
     }
 
+    fn get_spotify_refresh_token(&self) -> &str {
+        &self.refresh_token
+    }
+
     fn get_next_spotify_refresh_token(&self) -> &Option<String> {
         &self.token_response.refresh_token
     }
@@ -198,6 +434,177 @@ mod tests {
 
     use super::*;
 
+    impl SpotifyPlaylistItem {
+        pub(crate) fn new_test_data(track_id: &str, added_by: &str, added_at: &str) -> Self {
+            SpotifyPlaylistItem {
+                added_at: added_at.to_string(),
+                added_by: SpotifyUser {
+                    id: added_by.to_string(),
+                },
+                track: Some(SpotifyTrack {
+                    id: Some(track_id.to_string()),
+                    uri: format!("spotify:track:{track_id}"),
+                    name: format!("Track {track_id}"),
+                    artists: vec![SpotifyArtist {
+                        name: "Artist".to_string(),
+                    }],
+                    album: Some(SpotifyAlbum {
+                        name: "Album".to_string(),
+                        images: vec![
+                            SpotifyImage {
+                                url: "https://i.scdn.co/image/640".to_string(),
+                                width: Some(640),
+                            },
+                            SpotifyImage {
+                                url: "https://i.scdn.co/image/300".to_string(),
+                                width: Some(300),
+                            },
+                            SpotifyImage {
+                                url: "https://i.scdn.co/image/64".to_string(),
+                                width: Some(64),
+                            },
+                        ],
+                    }),
+                    duration_ms: 215_000,
+                    preview_url: None,
+                    external_urls: Some(SpotifyExternalUrls {
+                        spotify: format!("https://open.spotify.com/track/{track_id}"),
+                    }),
+                }),
+            }
+        }
+    }
+
+    #[test]
+    fn test_deserialize_unavailable_items() {
+        let response: SpotifyPlaylistTracksResponse = serde_json::from_str(
+            r#"{
+                "next": null,
+                "items": [
+                    {"added_at": "2023-01-01T00:00:00Z", "added_by": {"id": "user"}, "track": null},
+                    {
+                        "added_at": "2023-01-02T00:00:00Z",
+                        "added_by": {"id": "user"},
+                        "track": {
+                            "id": null,
+                            "uri": "spotify:local:Artist:Album:Local+Song:200",
+                            "name": "Local Song",
+                            "artists": [{"name": "Artist"}],
+                            "album": {"name": "Album", "images": []},
+                            "duration_ms": 200000,
+                            "preview_url": null,
+                            "external_urls": {}
+                        }
+                    },
+                    {
+                        "added_at": "2023-01-03T00:00:00Z",
+                        "added_by": {"id": "user"},
+                        "track": {
+                            "id": "episode_1",
+                            "uri": "spotify:episode:episode_1",
+                            "name": "Episode",
+                            "duration_ms": 1800000,
+                            "external_urls": {"spotify": "https://open.spotify.com/episode/episode_1"},
+                            "show": {"name": "Show"}
+                        }
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let track_ids = response
+            .items
+            .iter()
+            .map(|item| item.track_id())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            track_ids,
+            vec![
+                "unavailable:2023-01-01T00:00:00Z",
+                "spotify:local:Artist:Album:Local+Song:200",
+                "episode_1",
+            ]
+        );
+        let local = response.items[1].track.as_ref().unwrap();
+        assert_eq!(local.url(), "");
+        assert_eq!(local.thumbnail_url(), None);
+    }
+
+    #[test]
+    fn test_thumbnail_url() {
+        let item = SpotifyPlaylistItem::new_test_data("track_1", "spotify_user_1", "2023-01-01");
+        assert_eq!(
+            item.track.as_ref().unwrap().thumbnail_url(),
+            Some("https://i.scdn.co/image/300")
+        );
+    }
+
+    #[test]
+    fn test_spotify_error_from_response() {
+        let error = SpotifyError::from_response(
+            StatusCode::UNAUTHORIZED,
+            None,
+            r#"{"error": {"status": 401, "message": "The access token expired"}}"#,
+        );
+        assert!(
+            matches!(error, SpotifyError::Unauthorized { message } if message == "The access token expired")
+        );
+        let error = SpotifyError::from_response(
+            StatusCode::BAD_REQUEST,
+            None,
+            r#"{"error": "invalid_grant", "error_description": "Refresh token revoked"}"#,
+        );
+        assert!(
+            matches!(error, SpotifyError::InvalidGrant { message } if message == "invalid_grant: Refresh token revoked")
+        );
+        let error = SpotifyError::from_response(
+            StatusCode::BAD_REQUEST,
+            None,
+            r#"{"error": "invalid_client", "error_description": "Invalid client secret"}"#,
+        );
+        assert!(
+            matches!(error, SpotifyError::Unauthorized { message } if message == "invalid_client: Invalid client secret")
+        );
+        let error = SpotifyError::from_response(
+            StatusCode::NOT_FOUND,
+            None,
+            r#"{"error": {"status": 404, "message": "Resource not found"}}"#,
+        );
+        assert!(matches!(error, SpotifyError::NotFound { .. }));
+        let error = SpotifyError::from_response(StatusCode::TOO_MANY_REQUESTS, Some(3), "");
+        assert!(matches!(
+            error,
+            SpotifyError::RateLimited {
+                retry_after: Some(3)
+            }
+        ));
+        let error = SpotifyError::from_response(StatusCode::BAD_GATEWAY, None, "Bad Gateway");
+        assert!(matches!(error, SpotifyError::Server { status: 502, .. }));
+    }
+
+    #[test]
+    fn test_spotify_error_retry_delay() {
+        let rate_limited = SpotifyError::RateLimited {
+            retry_after: Some(3),
+        };
+        assert_eq!(rate_limited.retry_delay(0), Some(Duration::from_secs(3)));
+        // 待つ時間が長すぎる場合は再送しない
+        let rate_limited = SpotifyError::RateLimited {
+            retry_after: Some(3600),
+        };
+        assert_eq!(rate_limited.retry_delay(0), None);
+        let server_error = SpotifyError::Server {
+            status: 503,
+            message: String::new(),
+        };
+        assert_eq!(server_error.retry_delay(0), Some(INITIAL_BACKOFF));
+        assert_eq!(server_error.retry_delay(2), Some(INITIAL_BACKOFF * 4));
+        let not_found = SpotifyError::NotFound {
+            message: String::new(),
+        };
+        assert_eq!(not_found.retry_delay(0), None);
+    }
+
     #[tokio::test]
     async fn test_get_spotify_playlist() {
         dotenvy::dotenv().ok();
@@ -230,23 +637,4 @@ mod tests {
             .unwrap();
         println!("{:?}", res);
     }
-
-    #[tokio::test]
-    async fn test_get_not_notified_tracks_not_found() {
-        dotenvy::dotenv().ok();
-        let playlist_id = env::var("SPOTIFY_PLAYLIST_ID").unwrap();
-        let dynamodb_client = DynamoDBClient::new().await;
-        let spotify_refresh_token = dynamodb_client
-            .extract_spotify_refresh_token()
-            .await
-            .unwrap()
-            .unwrap();
-        let client = SpotifyClient::init(&spotify_refresh_token).await.unwrap();
-        let res = client
-            .list_all_spotify_playlist_tracks(&playlist_id)
-            .await
-            .unwrap();
-        let not_notified_tracks = res.get_not_notified_tracks("non_existent_track_id");
-        assert!(not_notified_tracks.is_none());
-    }
 }
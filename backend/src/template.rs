@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+
+use crate::error::AppError;
+
+// 通知メッセージの文言。{playlist}や{track}などのプレースホルダは送信先ごとの形式(リンクなど)で置き換えられる
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageTemplates {
+    pub title: String,
+    // {playlist}
+    pub playlist_updated: String,
+    // {playlist}
+    pub playlist_tracks_changed: String,
+    pub added_tracks_heading: String,
+    pub removed_tracks_heading: String,
+    pub moved_tracks_heading: String,
+    pub next_user_heading: String,
+    pub added_by_label: String,
+    pub duration_label: String,
+    pub preview_label: String,
+    // {track}, {artists}, {added_by}
+    pub added_track: String,
+    // {track}, {added_by}
+    pub removed_track: String,
+    // {track}, {from}, {to}, {added_by}
+    pub moved_track: String,
+    // {playlist}, {user}
+    pub turn_skipped: String,
+    // 番の人へのリマインド。回数に応じて文言を強める
+    // {playlist}, {user}, {days}
+    pub reminder: String,
+    pub reminder_repeated: String,
+    pub reminder_last: String,
+}
+
+impl MessageTemplates {
+    pub fn japanese() -> Self {
+        Self {
+            title: "プレイリスト更新のお知らせ".to_string(),
+            playlist_updated: "{playlist}が更新されました！".to_string(),
+            playlist_tracks_changed: "{playlist}の曲が削除・移動されました".to_string(),
+            added_tracks_heading: "追加された曲".to_string(),
+            removed_tracks_heading: "削除された曲".to_string(),
+            moved_tracks_heading: "移動された曲".to_string(),
+            next_user_heading: "次の人".to_string(),
+            added_by_label: "追加した人".to_string(),
+            duration_label: "再生時間".to_string(),
+            preview_label: "試聴".to_string(),
+            added_track: "{track} - {artists} (追加した人: {added_by})".to_string(),
+            removed_track: "{track} (追加した人: {added_by})".to_string(),
+            moved_track: "{track} ({from}番目 → {to}番目、追加した人: {added_by})".to_string(),
+            turn_skipped: "{playlist}の{user}さんの番をスキップしました".to_string(),
+            reminder: "{user}さん、{playlist}に曲を追加する番です".to_string(),
+            reminder_repeated: "{user}さん、{playlist}の番が回ってきてから{days}日経ちました。曲を追加してください".to_string(),
+            reminder_last: "{user}さん、{playlist}の番が回ってきてから{days}日経ちました。これが最後のリマインドです".to_string(),
+        }
+    }
+
+    pub fn english() -> Self {
+        Self {
+            title: "Playlist update".to_string(),
+            playlist_updated: "{playlist} has been updated!".to_string(),
+            playlist_tracks_changed: "Tracks in {playlist} were removed or moved".to_string(),
+            added_tracks_heading: "Added tracks".to_string(),
+            removed_tracks_heading: "Removed tracks".to_string(),
+            moved_tracks_heading: "Moved tracks".to_string(),
+            next_user_heading: "Up next".to_string(),
+            added_by_label: "Added by".to_string(),
+            duration_label: "Duration".to_string(),
+            preview_label: "Preview".to_string(),
+            added_track: "{track} - {artists} (added by {added_by})".to_string(),
+            removed_track: "{track} (added by {added_by})".to_string(),
+            moved_track: "{track} (#{from} → #{to}, added by {added_by})".to_string(),
+            turn_skipped: "{user}'s turn in {playlist} was skipped".to_string(),
+            reminder: "{user}, it's your turn to add a track to {playlist}".to_string(),
+            reminder_repeated: "{user}, it has been {days} days since your turn in {playlist} started. Please add a track".to_string(),
+            reminder_last: "{user}, it has been {days} days since your turn in {playlist} started. This is the last reminder".to_string(),
+        }
+    }
+
+    pub fn for_locale(locale: &str) -> Result<Self, AppError> {
+        match locale {
+            "ja" => Ok(Self::japanese()),
+            "en" => Ok(Self::english()),
+            _ => Err(AppError::Config(format!("unknown locale: {locale}"))),
+        }
+    }
+
+    // プレイリストごとの設定で一部の文言を上書きする
+    pub fn with_overrides(mut self, overrides: &HashMap<String, String>) -> Result<Self, AppError> {
+        for (key, value) in overrides {
+            let field = match key.as_str() {
+                "title" => &mut self.title,
+                "playlist_updated" => &mut self.playlist_updated,
+                "playlist_tracks_changed" => &mut self.playlist_tracks_changed,
+                "added_tracks_heading" => &mut self.added_tracks_heading,
+                "removed_tracks_heading" => &mut self.removed_tracks_heading,
+                "moved_tracks_heading" => &mut self.moved_tracks_heading,
+                "next_user_heading" => &mut self.next_user_heading,
+                "added_by_label" => &mut self.added_by_label,
+                "duration_label" => &mut self.duration_label,
+                "preview_label" => &mut self.preview_label,
+                "added_track" => &mut self.added_track,
+                "removed_track" => &mut self.removed_track,
+                "moved_track" => &mut self.moved_track,
+                "turn_skipped" => &mut self.turn_skipped,
+                "reminder" => &mut self.reminder,
+                "reminder_repeated" => &mut self.reminder_repeated,
+                "reminder_last" => &mut self.reminder_last,
+                _ => return Err(AppError::Config(format!("unknown template key: {key}"))),
+            };
+            *field = value.clone();
+        }
+        Ok(self)
+    }
+}
+
+impl Default for MessageTemplates {
+    fn default() -> Self {
+        Self::japanese()
+    }
+}
+
+// {name}形式のプレースホルダを置き換える。未知のプレースホルダはそのまま残す
+pub fn render(template: &str, values: &[(&str, &str)]) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let after_brace = &rest[start + 1..];
+        let replaced = after_brace.find('}').and_then(|end| {
+            let key = &after_brace[..end];
+            values
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, value)| (*value, end))
+        });
+        match replaced {
+            Some((value, end)) => {
+                rendered.push_str(value);
+                rest = &after_brace[end + 1..];
+            }
+            None => {
+                rendered.push('{');
+                rest = after_brace;
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            render(
+                "{track} ({from} → {to}) {unknown} {",
+                &[("track", "Song"), ("from", "3"), ("to", "1")]
+            ),
+            "Song (3 → 1) {unknown} {"
+        );
+        // 置き換えた値に含まれるプレースホルダは再度置き換えない
+        assert_eq!(render("{a}{b}", &[("a", "{b}"), ("b", "x")]), "{b}x");
+    }
+
+    #[test]
+    fn test_with_overrides() {
+        let templates = MessageTemplates::for_locale("en")
+            .unwrap()
+            .with_overrides(&HashMap::from([(
+                "next_user_heading".to_string(),
+                "Your turn".to_string(),
+            )]))
+            .unwrap();
+        assert_eq!(templates.next_user_heading, "Your turn");
+        assert_eq!(templates.title, "Playlist update");
+
+        assert!(
+            MessageTemplates::japanese()
+                .with_overrides(&HashMap::from([("nope".to_string(), "x".to_string())]))
+                .is_err()
+        );
+        assert!(MessageTemplates::for_locale("fr").is_err());
+    }
+}
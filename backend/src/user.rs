@@ -1,9 +1,61 @@
-#[derive(Debug)]
+use chrono::NaiveDate;
+
+use crate::error::AppError;
+
+// 不在期間の日付は日本時間で判定する
+const UTC_OFFSET_SECONDS: u64 = 9 * 60 * 60;
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct User {
     pub name: String,
     pub spotify_user_id: String,
     pub discord_user_id: String,
     pub order: usize,
+    // 再開するまでローテーションで飛ばす
+    pub paused: bool,
+    pub away: Option<AwayPeriod>,
+}
+
+// 旅行などで不在にする期間。YYYY-MM-DD形式で、両端の日を含む
+#[derive(Debug, Clone, PartialEq)]
+pub struct AwayPeriod {
+    pub from: String,
+    pub until: String,
+}
+
+impl AwayPeriod {
+    pub fn new(from: &str, until: &str) -> Result<Self, AppError> {
+        for date in [from, until] {
+            if !is_valid_date(date) {
+                return Err(AppError::Config(format!("invalid date: {date}")));
+            }
+        }
+        if from > until {
+            return Err(AppError::Config(format!(
+                "away period ends before it starts: {from} - {until}"
+            )));
+        }
+        Ok(Self {
+            from: from.to_string(),
+            until: until.to_string(),
+        })
+    }
+}
+
+impl User {
+    // nowはUNIX時間(秒)
+    pub fn is_available(&self, now: u64) -> bool {
+        if self.paused {
+            return false;
+        }
+        match &self.away {
+            Some(away) => {
+                let today = date_from_unix_seconds(now + UTC_OFFSET_SECONDS);
+                today < away.from || away.until < today
+            }
+            None => true,
+        }
+    }
 }
 
 pub struct UserMaster {
@@ -11,20 +63,116 @@ pub struct UserMaster {
 }
 
 impl UserMaster {
-    pub fn get_next_user_by_spotify_id(&self, spotify_user_id: &str) -> Option<&User> {
+    pub fn get_user_by_spotify_id(&self, spotify_user_id: &str) -> Option<&User> {
+        self.users
+            .iter()
+            .find(|user| user.spotify_user_id == spotify_user_id)
+    }
+
+    // 不在のユーザーは飛ばす。全員が不在の場合は飛ばさずに次のユーザーを返す
+    pub fn get_next_user_by_spotify_id(&self, spotify_user_id: &str, now: u64) -> Option<&User> {
         for (i, user) in self.users.iter().enumerate() {
             if user.spotify_user_id == spotify_user_id {
-                return self.users.get((i + 1) % self.users.len());
+                let len = self.users.len();
+                return (1..=len)
+                    .map(|offset| &self.users[(i + offset) % len])
+                    .find(|user| user.is_available(now))
+                    .or_else(|| self.users.get((i + 1) % len));
             }
         }
         None
     }
+
+    // 新しく追加するユーザーは最後の番にする
+    pub fn next_order(&self) -> usize {
+        self.users.iter().map(|user| user.order).max().unwrap_or(0) + 1
+    }
+
+    // 指定した順にorderを1から振り直す。全員をちょうど一度ずつ指定する必要がある
+    pub fn reorder(&self, spotify_user_ids: &[String]) -> Result<Vec<User>, AppError> {
+        if spotify_user_ids.len() != self.users.len() {
+            return Err(AppError::Config(format!(
+                "expected {} users, got {}",
+                self.users.len(),
+                spotify_user_ids.len()
+            )));
+        }
+        let mut reordered: Vec<User> = Vec::new();
+        for (i, spotify_user_id) in spotify_user_ids.iter().enumerate() {
+            let user = if let Some(user) = self.get_user_by_spotify_id(spotify_user_id) {
+                user
+            } else {
+                return Err(AppError::Config(format!("unknown user: {spotify_user_id}")));
+            };
+            if reordered
+                .iter()
+                .any(|u| &u.spotify_user_id == spotify_user_id)
+            {
+                return Err(AppError::Config(format!(
+                    "duplicated user: {spotify_user_id}"
+                )));
+            }
+            reordered.push(User {
+                order: i + 1,
+                ..user.clone()
+            });
+        }
+        Ok(reordered)
+    }
+
+    pub fn filter_by_spotify_user_ids(&self, spotify_user_ids: &[String]) -> UserMaster {
+        if spotify_user_ids.is_empty() {
+            return UserMaster {
+                users: self.users.clone(),
+            };
+        }
+        let users = spotify_user_ids
+            .iter()
+            .filter_map(|id| self.users.iter().find(|u| &u.spotify_user_id == id))
+            .cloned()
+            .collect();
+        UserMaster { users }
+    }
+}
+
+// YYYY-MM-DD
+fn is_valid_date(date: &str) -> bool {
+    // 文字列のまま比較するため、ゼロ埋めした10文字の形式に限る
+    date.len() == 10 && NaiveDate::parse_from_str(date, "%Y-%m-%d").is_ok()
+}
+
+// https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn date_from_unix_seconds(seconds: u64) -> String {
+    let z = (seconds / (24 * 60 * 60)) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{year:04}-{month:02}-{day:02}")
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn new_test_users() -> Vec<User> {
+        (1..=3)
+            .map(|i| User {
+                name: format!("User{i}"),
+                spotify_user_id: format!("spotify{i}"),
+                discord_user_id: format!("discord{i}"),
+                order: i,
+                paused: false,
+                away: None,
+            })
+            .collect()
+    }
+
     #[test]
     fn test_get_next_user_by_spotify_id() {
         let user1 = User {
@@ -32,43 +180,174 @@ mod tests {
             spotify_user_id: "spotify1".to_string(),
             discord_user_id: "discord1".to_string(),
             order: 1,
+            paused: false,
+            away: None,
         };
         let user2 = User {
             name: "User2".to_string(),
             spotify_user_id: "spotify2".to_string(),
             discord_user_id: "discord2".to_string(),
             order: 2,
+            paused: false,
+            away: None,
         };
         let user3 = User {
             name: "User3".to_string(),
             spotify_user_id: "spotify3".to_string(),
             discord_user_id: "discord3".to_string(),
             order: 3,
+            paused: false,
+            away: None,
         };
         let user_master = UserMaster {
             users: vec![user1, user2, user3],
         };
         assert_eq!(
             user_master
-                .get_next_user_by_spotify_id("spotify1")
+                .get_next_user_by_spotify_id("spotify1", 0)
                 .unwrap()
                 .spotify_user_id,
             "spotify2"
         );
         assert_eq!(
             user_master
-                .get_next_user_by_spotify_id("spotify2")
+                .get_next_user_by_spotify_id("spotify2", 0)
                 .unwrap()
                 .spotify_user_id,
             "spotify3"
         );
         assert_eq!(
             user_master
-                .get_next_user_by_spotify_id("spotify3")
+                .get_next_user_by_spotify_id("spotify3", 0)
                 .unwrap()
                 .spotify_user_id,
             "spotify1"
         );
-        assert!(user_master.get_next_user_by_spotify_id("unknown").is_none());
+        assert!(
+            user_master
+                .get_next_user_by_spotify_id("unknown", 0)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_availability() {
+        // 2024-03-10T00:00:00Z(日本時間では2024-03-10 09:00)
+        let now = 1_710_028_800;
+        assert_eq!(date_from_unix_seconds(now), "2024-03-10");
+        assert_eq!(date_from_unix_seconds(0), "1970-01-01");
+
+        let mut users = new_test_users();
+        users[1].away = Some(AwayPeriod::new("2024-03-01", "2024-03-10").unwrap());
+        let user_master = UserMaster { users };
+        assert!(!user_master.users[1].is_available(now));
+        // 不在期間が終わった翌日(日本時間)からは番が回る
+        let next_day = now + 24 * 60 * 60 - UTC_OFFSET_SECONDS;
+        assert!(!user_master.users[1].is_available(next_day - 1));
+        assert!(user_master.users[1].is_available(next_day));
+        assert_eq!(
+            user_master
+                .get_next_user_by_spotify_id("spotify1", now)
+                .unwrap()
+                .spotify_user_id,
+            "spotify3"
+        );
+
+        let mut users = new_test_users();
+        for user in &mut users {
+            user.paused = true;
+        }
+        let user_master = UserMaster { users };
+        // 全員が不在の場合は飛ばさない
+        assert_eq!(
+            user_master
+                .get_next_user_by_spotify_id("spotify1", now)
+                .unwrap()
+                .spotify_user_id,
+            "spotify2"
+        );
+
+        assert!(AwayPeriod::new("2024-03-10", "2024-03-01").is_err());
+        assert!(AwayPeriod::new("2024/03/01", "2024-03-10").is_err());
+        // 存在しない日付は受け付けず、うるう日は受け付ける
+        assert!(AwayPeriod::new("2024-02-31", "2024-03-10").is_err());
+        assert!(AwayPeriod::new("2023-02-29", "2023-03-10").is_err());
+        assert!(AwayPeriod::new("2024-2-1", "2024-03-10").is_err());
+        assert!(AwayPeriod::new("2024-02-29", "2024-03-10").is_ok());
+    }
+
+    #[test]
+    fn test_reorder() {
+        let user_master = UserMaster {
+            users: new_test_users(),
+        };
+        assert_eq!(user_master.next_order(), 4);
+        let reordered = user_master
+            .reorder(&[
+                "spotify3".to_string(),
+                "spotify1".to_string(),
+                "spotify2".to_string(),
+            ])
+            .unwrap();
+        let orders = reordered
+            .iter()
+            .map(|u| (u.spotify_user_id.as_str(), u.order))
+            .collect::<Vec<(&str, usize)>>();
+        assert_eq!(
+            orders,
+            vec![("spotify3", 1), ("spotify1", 2), ("spotify2", 3)]
+        );
+
+        assert!(
+            user_master
+                .reorder(&["spotify3".to_string(), "spotify1".to_string()])
+                .is_err()
+        );
+        assert!(
+            user_master
+                .reorder(&[
+                    "spotify1".to_string(),
+                    "spotify1".to_string(),
+                    "spotify2".to_string(),
+                ])
+                .is_err()
+        );
+        assert!(
+            user_master
+                .reorder(&[
+                    "spotify1".to_string(),
+                    "spotify2".to_string(),
+                    "unknown".to_string(),
+                ])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_filter_by_spotify_user_ids() {
+        let user_master = UserMaster {
+            users: new_test_users(),
+        };
+        let filtered = user_master
+            .filter_by_spotify_user_ids(&["spotify3".to_string(), "spotify1".to_string()]);
+        let ids = filtered
+            .users
+            .iter()
+            .map(|u| u.spotify_user_id.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(ids, vec!["spotify3", "spotify1"]);
+        assert_eq!(
+            filtered
+                .get_next_user_by_spotify_id("spotify1", 0)
+                .unwrap()
+                .spotify_user_id,
+            "spotify3"
+        );
+
+        let unknown_filtered = user_master.filter_by_spotify_user_ids(&["unknown".to_string()]);
+        assert!(unknown_filtered.users.is_empty());
+
+        let all = user_master.filter_by_spotify_user_ids(&[]);
+        assert_eq!(all.users.len(), 3);
     }
 }
@@ -0,0 +1,118 @@
+use crate::{error::AppError, notifier::PlaylistNotification};
+
+// 任意のURLに通知内容をそのままJSONでPOSTする
+#[derive(Default)]
+pub struct WebhookClient {
+    client: reqwest::Client,
+}
+
+impl WebhookClient {
+    pub fn new() -> Self {
+        Self {
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub async fn post(
+        &self,
+        url: &str,
+        notification: &PlaylistNotification,
+    ) -> Result<(), AppError> {
+        self.client
+            .post(url)
+            .json(notification)
+            .send()
+            .await
+            // エラーメッセージにURLが含まれないようにする
+            .and_then(|response| response.error_for_status())
+            .map_err(reqwest::Error::without_url)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    // リクエストを1件受けてstatusで応答し、受け取ったボディを返す
+    async fn serve_once(listener: TcpListener, status: &str) -> serde_json::Value {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buffer = [0; 4096];
+        loop {
+            let n = stream.read(&mut buffer).await.unwrap();
+            assert!(n > 0, "connection closed before the body was read");
+            request.extend_from_slice(&buffer[..n]);
+            let Some(header_end) = request.windows(4).position(|w| w == b"\r\n\r\n") else {
+                continue;
+            };
+            let headers = String::from_utf8_lossy(&request[..header_end]).to_ascii_lowercase();
+            let content_length = headers
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length:"))
+                .map_or(0, |value| value.trim().parse::<usize>().unwrap());
+            let body = &request[header_end + 4..];
+            if body.len() < content_length {
+                continue;
+            }
+            assert!(headers.starts_with("post / "));
+            assert!(headers.contains("content-type: application/json"));
+            stream
+                .write_all(
+                    format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                        .as_bytes(),
+                )
+                .await
+                .unwrap();
+            return serde_json::from_slice(body).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_post() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let server = tokio::spawn(serve_once(listener, "204 No Content"));
+        let notification = PlaylistNotification::new_test_data();
+        WebhookClient::new()
+            .post(&url, &notification)
+            .await
+            .unwrap();
+        // 通知内容をそのままJSONで送る
+        let payload = server.await.unwrap();
+        assert_eq!(payload, serde_json::to_value(&notification).unwrap());
+        assert_eq!(payload["playlist_name"], "Test Playlist");
+        assert_eq!(payload["added_tracks"][0]["added_by"]["name"], "User 1");
+    }
+
+    #[tokio::test]
+    async fn test_post_error_status() {
+        let notification = PlaylistNotification::new_test_data();
+        for (status, retryable) in [
+            ("500 Internal Server Error", true),
+            ("429 Too Many Requests", true),
+            ("404 Not Found", false),
+        ] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/", listener.local_addr().unwrap());
+            let server = tokio::spawn(serve_once(listener, status));
+            let err = WebhookClient::new()
+                .post(&url, &notification)
+                .await
+                .unwrap_err();
+            server.await.unwrap();
+            let AppError::Http(e) = &err else {
+                panic!("unexpected error: {err}");
+            };
+            assert_eq!(e.status().map(|s| s.to_string()).as_deref(), Some(status));
+            assert_eq!(err.is_retryable(), retryable);
+            // URLは秘密情報なのでエラーメッセージに含めない
+            assert!(!err.to_string().contains(&url));
+        }
+    }
+}
@@ -4,6 +4,7 @@ import {
     aws_iam,
     aws_scheduler,
     aws_scheduler_targets,
+    CfnOutput,
     Duration,
     Stack,
     TimeZone,
@@ -12,7 +13,7 @@ import {
 import { ServicePrincipal } from "aws-cdk-lib/aws-iam";
 import type { Construct } from "constructs";
 import { RustFunction } from "cargo-lambda-cdk";
-import { Architecture } from "aws-cdk-lib/aws-lambda";
+import { Architecture, FunctionUrlAuthType } from "aws-cdk-lib/aws-lambda";
 
 export class CdkStack extends Stack {
     constructor(scope: Construct, id: string, props?: StackProps) {
@@ -32,10 +33,31 @@ export class CdkStack extends Stack {
         const lambda = new RustFunction(this, "Lambda", {
             role,
             manifestPath: join(__dirname, "..", "..", "backend"),
+            // CLIと同じパッケージのため、Lambdaのバイナリを指定する
+            binaryName: "spotify-playlist-notification-backend",
             architecture: Architecture.ARM_64,
             timeout: Duration.minutes(5),
         });
 
+        // Discordのスラッシュコマンドの受け口。リクエストはLambda側で署名を検証する
+        const functionUrl = lambda.addFunctionUrl({
+            authType: FunctionUrlAuthType.NONE,
+        });
+        new CfnOutput(this, "InteractionsEndpointUrl", {
+            value: functionUrl.url,
+        });
+        // 3秒以内に応答できないコマンドは、Lambda自身を非同期に呼び出して実行する
+        // (ロールのデフォルトポリシーに追加すると関数との循環参照になるため、別のポリシーにする)
+        new aws_iam.Policy(this, "SelfInvokePolicy", {
+            roles: [role],
+            statements: [
+                new aws_iam.PolicyStatement({
+                    actions: ["lambda:InvokeFunction"],
+                    resources: [lambda.functionArn],
+                }),
+            ],
+        });
+
         const userTable = new aws_dynamodb.TableV2(this, "UserTable", {
             tableName: "spotify-playlist-notification_user",
             partitionKey: {
@@ -48,8 +70,19 @@ export class CdkStack extends Stack {
             },
         });
         userTable.grantReadData(localTestUser);
+        userTable.grantWriteData(localTestUser);
         userTable.grantReadData(lambda);
 
+        const playlistTable = new aws_dynamodb.TableV2(this, "PlaylistTable", {
+            tableName: "spotify-playlist-notification_playlist",
+            partitionKey: {
+                name: "playlist_id",
+                type: aws_dynamodb.AttributeType.STRING,
+            },
+        });
+        playlistTable.grantReadData(localTestUser);
+        playlistTable.grantReadData(lambda);
+
         const spotifyRefreshTokenTable = new aws_dynamodb.TableV2(
             this,
             "SpotifyRefreshTokenTable",
@@ -67,21 +100,83 @@ export class CdkStack extends Stack {
         spotifyRefreshTokenTable.grantReadData(lambda);
         spotifyRefreshTokenTable.grantWriteData(lambda);
 
-        const lastNotifiedTrackTable = new aws_dynamodb.TableV2(
+        const playlistStateTable = new aws_dynamodb.TableV2(
             this,
-            "LastNotifiedTrackTable",
+            "PlaylistStateTable",
             {
-                tableName: "spotify-playlist-notification_last_notified_track",
+                tableName: "spotify-playlist-notification_playlist_state",
                 partitionKey: {
-                    name: "singleton_key",
+                    name: "playlist_id",
+                    type: aws_dynamodb.AttributeType.STRING,
+                },
+                sortKey: {
+                    name: "environment",
                     type: aws_dynamodb.AttributeType.STRING,
                 },
             },
         );
-        lastNotifiedTrackTable.grantReadData(localTestUser);
-        lastNotifiedTrackTable.grantWriteData(localTestUser);
-        lastNotifiedTrackTable.grantReadData(lambda);
-        lastNotifiedTrackTable.grantWriteData(lambda);
+        playlistStateTable.grantReadData(localTestUser);
+        playlistStateTable.grantWriteData(localTestUser);
+        playlistStateTable.grantReadData(lambda);
+        playlistStateTable.grantWriteData(lambda);
+
+        const notificationOutboxTable = new aws_dynamodb.TableV2(
+            this,
+            "NotificationOutboxTable",
+            {
+                tableName: "spotify-playlist-notification_notification_outbox",
+                partitionKey: {
+                    name: "playlist_id",
+                    type: aws_dynamodb.AttributeType.STRING,
+                },
+                sortKey: {
+                    name: "outbox_id",
+                    type: aws_dynamodb.AttributeType.STRING,
+                },
+                timeToLiveAttribute: "expires_at",
+            },
+        );
+        notificationOutboxTable.grantReadData(localTestUser);
+        notificationOutboxTable.grantWriteData(localTestUser);
+        notificationOutboxTable.grantReadData(lambda);
+        notificationOutboxTable.grantWriteData(lambda);
+
+        const runLockTable = new aws_dynamodb.TableV2(this, "RunLockTable", {
+            tableName: "spotify-playlist-notification_run_lock",
+            partitionKey: {
+                name: "lock_name",
+                type: aws_dynamodb.AttributeType.STRING,
+            },
+            sortKey: {
+                name: "environment",
+                type: aws_dynamodb.AttributeType.STRING,
+            },
+            timeToLiveAttribute: "expires_at",
+        });
+        runLockTable.grantReadData(localTestUser);
+        runLockTable.grantWriteData(localTestUser);
+        runLockTable.grantReadData(lambda);
+        runLockTable.grantWriteData(lambda);
+
+        const skippedTurnTable = new aws_dynamodb.TableV2(
+            this,
+            "SkippedTurnTable",
+            {
+                tableName: "spotify-playlist-notification_skipped_turn",
+                partitionKey: {
+                    name: "playlist_id",
+                    type: aws_dynamodb.AttributeType.STRING,
+                },
+                sortKey: {
+                    name: "skipped_turn_id",
+                    type: aws_dynamodb.AttributeType.STRING,
+                },
+            },
+        );
+        skippedTurnTable.grantReadData(localTestUser);
+        skippedTurnTable.grantWriteData(localTestUser);
+        skippedTurnTable.grantReadData(lambda);
+        skippedTurnTable.grantWriteData(lambda);
 
         new aws_scheduler.Schedule(this, "Schedule", {
             schedule: aws_scheduler.ScheduleExpression.cron({
@@ -92,7 +187,10 @@ export class CdkStack extends Stack {
                 year: "*",
                 timeZone: TimeZone.ASIA_TOKYO,
             }),
-            target: new aws_scheduler_targets.LambdaInvoke(lambda),
+            // 空のペイロードをスケジュール実行として扱う
+            target: new aws_scheduler_targets.LambdaInvoke(lambda, {
+                input: aws_scheduler.ScheduleTargetInput.fromObject({}),
+            }),
         });
     }
 }